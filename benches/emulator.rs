@@ -0,0 +1,94 @@
+//! Benchmarks the emulator on every program in `test_cases` and on a long-running loop, running
+//! the decoded ROM `Emulator::run` uses against stepping through it one instruction at a time.
+//! Throughput is reported in instructions per second; the decoded ROM should manage 100M or more
+//! on the long loop on a desktop machine.
+//!
+//! `cargo bench --bench emulator` runs them.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use hack_asm::emulator::Emulator;
+use std::fs;
+
+const MAX_CYCLES: u64 = 1_000_000_000;
+
+/// Counts down from 1000 a thousand times over, which is about 5M instructions.
+const LOOP: &str = "\
+@1000
+D=A
+@outer
+M=D
+(OUTER)
+@1000
+D=A
+@inner
+M=D
+(INNER)
+@inner
+MD=M-1
+@INNER
+D;JGT
+@outer
+MD=M-1
+@OUTER
+D;JGT
+(END)
+@END
+0;JMP";
+
+/// Every program in `test_cases` which assembles, along with its name.
+fn test_cases() -> Vec<(String, Vec<u16>)> {
+    let mut programs = vec![];
+    for directory in &["test_cases", "test_cases/formats"] {
+        for path in fs::read_dir(directory).unwrap().map(|x| x.unwrap().path()) {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if !name.ends_with(".asm") || name.ends_with(".expected.asm") {
+                continue;
+            }
+            let text = fs::read_to_string(&path).unwrap();
+            if let Ok(rom) = hack_asm::assemble(&path.to_string_lossy(), &text) {
+                programs.push((name, rom));
+            }
+        }
+    }
+    programs.sort();
+    programs
+}
+
+fn step(mut emulator: Emulator) -> Emulator {
+    while !emulator.halted() && emulator.cycles < MAX_CYCLES {
+        emulator.step();
+    }
+    emulator
+}
+
+fn decoded(mut emulator: Emulator) -> Emulator {
+    emulator.run(MAX_CYCLES);
+    emulator
+}
+
+/// Benchmarks running a program from the start to where it halts both ways.
+fn bench_program(c: &mut Criterion, group: &str, name: &str, rom: &[u16]) {
+    let cycles = decoded(Emulator::new(rom.to_vec())).cycles;
+    let mut group = c.benchmark_group(group);
+    group.throughput(Throughput::Elements(cycles));
+    for (how, run) in [
+        ("decoded", decoded as fn(Emulator) -> Emulator),
+        ("stepped", step),
+    ] {
+        group.bench_with_input(BenchmarkId::new(how, name), rom, |b, rom| {
+            b.iter_batched(|| Emulator::new(rom.to_vec()), run, BatchSize::LargeInput)
+        });
+    }
+    group.finish();
+}
+
+fn benches(c: &mut Criterion) {
+    for (name, rom) in test_cases() {
+        bench_program(c, "test_cases", &name, &rom);
+    }
+    let rom = hack_asm::assemble("loop.asm", LOOP).unwrap();
+    bench_program(c, "loop", "countdown", &rom);
+}
+
+criterion_group!(emulator, benches);
+criterion_main!(emulator);
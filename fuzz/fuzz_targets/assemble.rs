@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        if !text.to_ascii_lowercase().contains("#include") {
+            let _ = hack_asm::assemble("fuzz.asm", text);
+        }
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+use hack_asm::parser::try_parse_lines;
+use hack_asm::types::Line;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = try_parse_lines(Line::read("fuzz.asm", text));
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+use hack_asm::preprocessor::Preprocessable;
+use hack_asm::types::Line;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        // #include would read whatever files the fuzzer names
+        if !text.to_ascii_lowercase().contains("#include") {
+            let _ = Line::read("fuzz.asm", text).try_preprocess();
+        }
+    }
+});
@@ -1,70 +1,440 @@
-use regex::Regex;
+use crate::parser::parse_line;
+use crate::types::*;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-pub trait Assemblable {
-    fn assemble(self) -> Vec<String>;
+pub trait Assemblable: Sized {
+    /// Assembles into machine code, giving the first error instead of panicking.
+    fn try_assemble(self) -> Result<Vec<u16>, String>;
+
+    fn assemble(self) -> Vec<u16> {
+        self.try_assemble().unwrap_or_else(|e| panic!("{}", e))
+    }
 }
 
-impl Assemblable for Vec<String> {
-    fn assemble(self) -> Vec<String> {
-        let clean_regex = Regex::new("(//.*$)|(\\s)").unwrap();
-        let label_regex = Regex::new("\\(.*\\)").unwrap();
-        let mut labels: HashMap<String, i32> = HashMap::new();
-        let mut count = 15;
-        self.iter()
-            .map(|x| clean_regex.replace_all(x, "").to_string())
-            .filter(|x| x != "")
-            .enumerate()
-            .filter_map(|(_index, x)| {
-                if label_regex.is_match(&x) {
-                    let _key = &x[1..x.len()];
-
-                    None
-                } else {
-                    Some(x)
+/// The first RAM address handed out to variables.
+pub const FIRST_VARIABLE: u16 = 16;
+
+/// Where `#call` keeps its stack pointer.  The stack grows down from just below it, so variables
+/// are only handed out below it.
+pub const STACK_POINTER: u16 = 16383;
+
+/// The symbols every Hack program starts out with.
+pub fn predefined_symbols() -> HashMap<String, u16> {
+    let mut symbols: HashMap<String, u16> = vec![
+        ("SP", 0),
+        ("LCL", 1),
+        ("ARG", 2),
+        ("THIS", 3),
+        ("THAT", 4),
+        ("SCREEN", 16384),
+        ("KBD", 24576),
+    ]
+    .into_iter()
+    .map(|(name, address)| (name.to_string(), address))
+    .collect();
+    for register in 0..16 {
+        symbols.insert(format!("R{}", register), register);
+    }
+    symbols
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymbolKind {
+    Predefined,
+    Label,
+    /// A label added by a directive such as `#call` rather than written in the source.
+    #[serde(rename = "macro-generated")]
+    Generated,
+    Variable,
+}
+
+impl SymbolKind {
+    /// Whether the symbol is a ROM address rather than a RAM one.
+    pub fn in_rom(self) -> bool {
+        matches!(self, SymbolKind::Label | SymbolKind::Generated)
+    }
+}
+
+/// Hands out RAM to variables, either where they are pinned or in turn from `FIRST_VARIABLE`,
+/// going around the pinned ones.  Everything has to be pinned before anything is allocated.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Allocator {
+    next: u32,
+    /// The name, address and size of every pinned variable.
+    pinned: Vec<(String, u16, u16)>,
+}
+
+impl Default for Allocator {
+    fn default() -> Allocator {
+        Allocator::new()
+    }
+}
+
+impl Allocator {
+    pub fn new() -> Allocator {
+        Allocator {
+            next: FIRST_VARIABLE as u32,
+            pinned: vec![],
+        }
+    }
+
+    fn overlapping(&self, address: u32, size: u16) -> Option<&(String, u16, u16)> {
+        self.pinned.iter().find(|(_, start, length)| {
+            address < *start as u32 + *length as u32 && (*start as u32) < address + size as u32
+        })
+    }
+
+    /// Reserves `size` words at `address`, giving an error if any of them are already pinned or
+    /// past the end of the address space.
+    pub fn pin(&mut self, name: &str, address: u16, size: u16) -> Result<u16, String> {
+        if address as u32 + size as u32 > MAX_ADDRESS as u32 + 1 {
+            return Err(format!(
+                "{} runs past the end of RAM at {}",
+                describe(name, address, size),
+                MAX_ADDRESS
+            ));
+        }
+        if let Some((other, start, length)) = self.overlapping(address as u32, size) {
+            return Err(format!(
+                "{} overlaps {}",
+                describe(name, address, size),
+                describe(other, *start, *length)
+            ));
+        }
+        self.pinned.push((name.to_string(), address, size));
+        Ok(address)
+    }
+
+    /// Gives `size` words at the next free address below the stack pointer.
+    pub fn allocate(&mut self, name: &str, size: u16) -> Result<u16, String> {
+        let mut address = self.next;
+        while let Some((_, start, length)) = self.overlapping(address, size) {
+            address = *start as u32 + *length as u32;
+        }
+        if address + size as u32 > STACK_POINTER as u32 {
+            return Err(format!("Out of addresses for variable {}", name));
+        }
+        self.next = address + size as u32;
+        Ok(address as u16)
+    }
+}
+
+/// Describes where a variable is, e.g. `buf at 16 to 79`.
+pub fn describe(name: &str, address: u16, size: u16) -> String {
+    match size {
+        1 => format!("{} at {}", name, address),
+        _ => format!(
+            "{} at {} to {}",
+            name,
+            address,
+            address as u32 + size as u32 - 1
+        ),
+    }
+}
+
+/// The address the assembler gives every symbol in a program.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SymbolTable {
+    symbols: HashMap<String, (SymbolKind, u16)>,
+    /// The size of every variable declared with `#var`, the rest being one word.
+    sizes: HashMap<String, u16>,
+}
+
+impl SymbolTable {
+    pub fn new(program: &[Instruction]) -> SymbolTable {
+        SymbolTable::try_new(program).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Gives every label its ROM address and every variable its RAM address, or an error if a
+    /// label is defined twice or the program doesn't fit.
+    pub fn try_new(program: &[Instruction]) -> Result<SymbolTable, String> {
+        let mut symbols: HashMap<String, (SymbolKind, u16)> = predefined_symbols()
+            .into_iter()
+            .map(|(name, address)| (name, (SymbolKind::Predefined, address)))
+            .collect();
+        let mut count: u16 = 0;
+        for instruction in program {
+            match instruction {
+                Instruction::Label(label) => {
+                    if symbols
+                        .insert(label.clone(), (SymbolKind::Label, count))
+                        .is_some()
+                    {
+                        return Err(format!("Label {} is defined more than once", label));
+                    }
                 }
-            })
-            .map(|x| {
-                if &x[0..1] == "@" && !(&x[1..]).parse::<u64>().is_ok() {
-                    // TODO make a first run through to collapse () labels into numbers
-                    let index = match labels.get(&x[1..]) {
-                        Some(T) => *T,
-                        None => {
-                            count += 1;
-                            labels.insert(x[1..].to_string(), count);
-                            count
-                        }
-                    };
-                    format!("@{}", index)
-                } else {
-                    x
+                x if !x.takes_rom() => {}
+                _ if count > MAX_ADDRESS => {
+                    return Err(format!("Program is longer than {} words", MAX_ADDRESS + 1))
                 }
+                _ => count += 1,
+            }
+        }
+
+        // variables declared with #var come first, pinned ones where they ask to be
+        let mut allocator = Allocator::new();
+        let mut sizes = HashMap::new();
+        let mut declared = program
+            .iter()
+            .filter_map(|x| match x {
+                Instruction::Macro(Macro::Var(variable)) => Some(variable),
+                _ => None,
             })
-            .map(|x| {
-                if &x[0..1] == "@" {
-                    format!(
-                        "@{}",
-                        match &x[1..] {
-                            "SP" => "0",
-                            "LCL" => "@",
-                            "ARG" => "2",
-                            "THIS" => "3",
-                            "THAT" => "4",
-                            "SCREEN" => "16384",
-                            "KBD" => "24576",
-                            symbol if symbol.parse::<i32>().is_ok() => symbol,
-                            symbol => panic!("Could not parse symbol: {}", symbol),
-                        }
-                    )
-                } else {
-                    x
+            .collect::<Vec<&Variable>>();
+        declared.sort_by_key(|x| x.address.is_none());
+        for variable in declared {
+            if let Some((kind, _)) = symbols.get(&variable.name) {
+                return Err(match kind {
+                    SymbolKind::Variable => format!("{} is declared more than once", variable.name),
+                    SymbolKind::Predefined => format!("{} is predefined", variable.name),
+                    _ => format!(
+                        "{} is declared with #var but is also a label",
+                        variable.name
+                    ),
+                });
+            }
+            let address = match variable.address {
+                Some(address) => allocator.pin(&variable.name, address, variable.size)?,
+                None => allocator.allocate(&variable.name, variable.size)?,
+            };
+            symbols.insert(variable.name.clone(), (SymbolKind::Variable, address));
+            sizes.insert(variable.name.clone(), variable.size);
+        }
+
+        // anything else which isn't a label is a variable, allocated in order of first use
+        for instruction in program {
+            if let Instruction::A(Location::Label(label)) = instruction {
+                if symbols.contains_key(label) {
+                    continue;
                 }
-            })
-            // .map(|x| match x {
-            //     instr if &instr[0..1] == "@" => (&x[1..]).parse::<u16>().unwrap(),
-            //
-            //     _ => panic!("Could not parse instruction")
-            // })
-            .collect::<Vec<String>>()
+                let address = allocator.allocate(label, 1)?;
+                symbols.insert(label.clone(), (SymbolKind::Variable, address));
+            }
+        }
+        Ok(SymbolTable { symbols, sizes })
+    }
+
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.symbols.get(name).map(|(_, address)| *address)
+    }
+
+    pub fn kind(&self, name: &str) -> Option<SymbolKind> {
+        self.symbols.get(name).map(|(kind, _)| *kind)
+    }
+
+    /// How many words of RAM a variable takes up.
+    pub fn size(&self, name: &str) -> u16 {
+        self.sizes.get(name).copied().unwrap_or(1)
+    }
+
+    /// Every symbol of the given kind, in order of address.
+    pub fn of_kind(&self, kind: SymbolKind) -> Vec<(&str, u16)> {
+        let mut symbols = self
+            .symbols
+            .iter()
+            .filter(|(_, (x, _))| *x == kind)
+            .map(|(name, (_, address))| (name.as_str(), *address))
+            .collect::<Vec<(&str, u16)>>();
+        symbols.sort_by_key(|(name, address)| (*address, *name));
+        symbols
+    }
+}
+
+/// Encodes a single instruction, or gives `None` for labels which don't take up any ROM.
+pub fn encode(instruction: &Instruction, symbols: &SymbolTable) -> Option<u16> {
+    try_encode(instruction, symbols).unwrap_or_else(|e| panic!("{}", e))
+}
+
+pub fn try_encode(instruction: &Instruction, symbols: &SymbolTable) -> Result<Option<u16>, String> {
+    Ok(match instruction {
+        Instruction::Label(_) => None,
+        Instruction::A(Location::Address(address)) => Some(*address),
+        Instruction::A(Location::Label(label)) => symbols.get(label),
+        Instruction::A(Location::Expression(expression)) => {
+            let value = expression.evaluate(&|x| symbols.get(x).map(i64::from))?;
+            Some(check_address(expression, value)?)
+        }
+        Instruction::C(dest, computation, jump) => {
+            let comp = computation
+                .bits()
+                .ok_or_else(|| format!("Could not assemble {}", computation))?;
+            let dest = dest.iter().fold(0, |acc, x| acc | x.dest_bit());
+            Some(0b111 << 13 | comp << 6 | dest << 3 | jump.bits())
+        }
+        Instruction::Macro(Macro::Var(_)) => None,
+        Instruction::Macro(m) => {
+            return Err(format!(
+                "{} must be preprocessed before it can be assembled",
+                m
+            ))
+        }
+    })
+}
+
+/// The canonical spelling of every computation the ALU can do.
+const COMPUTATIONS: [&str; 28] = [
+    "0", "1", "-1", "D", "A", "!D", "!A", "-D", "-A", "D+1", "A+1", "D-1", "A-1", "D+A", "D-A",
+    "A-D", "D&A", "D|A", "M", "!M", "-M", "M+1", "M-1", "D+M", "D-M", "M-D", "D&M", "D|M",
+];
+
+/// Turns a word of machine code back into an instruction, or gives `None` if it is a C-instruction
+/// with a computation the assembler would never produce.
+pub fn decode(word: u16) -> Option<Instruction> {
+    if word & 0x8000 == 0 {
+        return Some(Instruction::A(Location::Address(word)));
+    }
+    let computation = COMPUTATIONS
+        .iter()
+        .filter_map(|x| match parse_line(x) {
+            Ok(Some(Instruction::C(_, computation, _))) => Some(computation),
+            _ => None,
+        })
+        .find(|x| x.bits() == Some((word >> 6) & 0b1_111111))?;
+    let dest = [Register::A, Register::M, Register::D]
+        .iter()
+        .filter(|x| word >> 3 & x.dest_bit() != 0)
+        .copied()
+        .collect();
+    let jump = [
+        Jump::None,
+        Jump::JGT,
+        Jump::JEQ,
+        Jump::JGE,
+        Jump::JLT,
+        Jump::JNE,
+        Jump::JLE,
+        Jump::JMP,
+    ][(word & 0b111) as usize];
+    Some(Instruction::C(dest, computation, jump))
+}
+
+impl Assemblable for Vec<Instruction> {
+    fn try_assemble(self) -> Result<Vec<u16>, String> {
+        let symbols = SymbolTable::try_new(&self)?;
+        self.iter()
+            .filter_map(|x| try_encode(x, &symbols).transpose())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{decode, Assemblable};
+    use crate::parser::parse;
+
+    #[test]
+    fn assembles() {
+        let program = "@2\nD=A\n@3\nD=D+A\n@0\nM=D";
+        assert_eq!(
+            parse(program.to_string()).assemble(),
+            vec![
+                0b0000000000000010,
+                0b1110110000010000,
+                0b0000000000000011,
+                0b1110000010010000,
+                0b0000000000000000,
+                0b1110001100001000,
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_symbols() {
+        let program = "(LOOP)\n@i\nM=M+1\n@SCREEN\n@j\n@i\n@END\n0;JMP\n(END)\n@R13\n@LOOP";
+        assert_eq!(
+            parse(program.to_string()).assemble(),
+            vec![
+                16,
+                0b1111110111001000,
+                16384,
+                17,
+                16,
+                7,
+                0b1110101010000111,
+                13,
+                0
+            ]
+        );
+    }
+
+    #[test]
+    fn evaluates_expressions() {
+        let program = "@i\n(END)\n@SCREEN+32*2\n@END-1\n@i+(1<<3)\n@-(1-2)";
+        assert_eq!(
+            parse(program.to_string()).assemble(),
+            vec![16, 16448, 0, 24, 1]
+        );
+        assert_eq!(
+            parse("@KBD+(SCREEN-1)".to_string()).try_assemble(),
+            Err(
+                "KBD+(SCREEN-1) is 40959, which does not fit in an A-instruction (0 to 32767)"
+                    .into()
+            )
+        );
+        assert_eq!(
+            parse("(END)\n@END/(END-END)".to_string()).try_assemble(),
+            Err("Division by zero in END/(END-END)".into())
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_duplicate_labels() {
+        parse("(A)\n(A)".to_string()).assemble();
+    }
+
+    #[test]
+    fn allocates_declared_variables() {
+        let program = "@a\n#var buf[4]\n#var port @ 17\n@b\n#var c\n@buf\n@port\n@c";
+        // pinned first, then declared in order going around them, then the rest in order of use
+        assert_eq!(
+            parse(program.to_string()).assemble(),
+            vec![23, 24, 18, 17, 22]
+        );
+        assert_eq!(
+            parse("#var a[4] @ 100\n#var b @ 103".to_string()).try_assemble(),
+            Err("b at 103 overlaps a at 100 to 103".into())
+        );
+        assert_eq!(
+            parse("#var big[16] @ 32760".to_string()).try_assemble(),
+            Err("big at 32760 to 32775 runs past the end of RAM at 32767".into())
+        );
+        assert_eq!(
+            parse("#var ram[16367]\n@x".to_string()).try_assemble(),
+            Err("Out of addresses for variable x".into())
+        );
+        assert_eq!(
+            parse("(a)\n#var a".to_string()).try_assemble(),
+            Err("a is declared with #var but is also a label".into())
+        );
+    }
+
+    #[test]
+    fn runs_out_of_addresses() {
+        let variables = (0..0x8000)
+            .map(|i| format!("@v{}", i))
+            .collect::<Vec<String>>();
+        assert_eq!(
+            parse(variables.join("\n")).try_assemble(),
+            Err("Out of addresses for variable v16367".to_string())
+        );
+        assert_eq!(
+            parse("@0\n".repeat(0x8001)).try_assemble(),
+            Err("Program is longer than 32768 words".to_string())
+        );
+    }
+
+    #[test]
+    fn decodes() {
+        let program = "@5\nD=A\nAM=M+1\nMD=D|M;JNE\nAMD=-1\n0;JMP\nA=!M;JGE";
+        let words = parse(program.to_string()).assemble();
+        let decoded = words
+            .iter()
+            .map(|x| decode(*x).unwrap().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(decoded.join("\n"), program);
+        assert_eq!(decode(0b1111_1111_1100_0000), None);
     }
 }
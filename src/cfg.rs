@@ -0,0 +1,327 @@
+use crate::optimizer::always_jumps;
+use crate::types::{Instruction, Jump, Line, Location, Register};
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum EdgeKind {
+    /// Carrying on to the next block, including coming back from a `#call`.
+    FallThrough,
+    Jump,
+    /// The jump into a function made by `#call`.
+    Call,
+    /// The jump back out of a function made by `#ret`.
+    Return,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Edge {
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+/// A run of instructions which is only entered at the top and only left at the bottom.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Block {
+    /// The index of the first instruction of the block in the program.
+    pub start: usize,
+    /// The index just past the last instruction of the block.
+    pub end: usize,
+    pub successors: Vec<Edge>,
+    /// Whether the block ends with the jump of a `#ret`.
+    pub returns: bool,
+}
+
+/// The control-flow graph of a preprocessed program.  Jumps are resolved when the A-instruction
+/// before them in the same block gives the target, and `#call` and `#ret` are recognised from the
+/// directive their expansion came from.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    /// The block each label starts.
+    pub labels: HashMap<String, usize>,
+}
+
+/// The first word of the directive a line was expanded from, e.g. `#call`.
+fn directive(line: &Line) -> Option<String> {
+    line.directive
+        .as_deref()
+        .and_then(|x| x.split_whitespace().next())
+        .map(|x| x.to_lowercase())
+}
+
+fn is_jump(instruction: &Instruction) -> bool {
+    matches!(instruction, Instruction::C(_, _, jump) if *jump != Jump::None)
+}
+
+impl Cfg {
+    pub fn new(program: &[(Instruction, Line)]) -> Cfg {
+        // a block starts at the first of a run of labels and just after every jump
+        let mut starts = if program.is_empty() { vec![] } else { vec![0] };
+        for (i, (instruction, _)) in program.iter().enumerate() {
+            let previous = i.checked_sub(1).map(|x| &program[x].0);
+            let starts_block = match instruction {
+                Instruction::Label(_) => !matches!(previous, Some(Instruction::Label(_))),
+                _ => previous.is_some_and(is_jump),
+            };
+            if starts_block && i > 0 {
+                starts.push(i);
+            }
+        }
+        let ends = starts
+            .iter()
+            .skip(1)
+            .copied()
+            .chain(std::iter::once(program.len()))
+            .collect::<Vec<usize>>();
+
+        let mut labels = HashMap::new();
+        let mut addresses = HashMap::new();
+        let mut address = 0;
+        for (block, (start, end)) in starts.iter().zip(&ends).enumerate() {
+            addresses.entry(address).or_insert(block);
+            for (instruction, _) in &program[*start..*end] {
+                match instruction {
+                    Instruction::Label(label) => {
+                        labels.insert(label.clone(), block);
+                    }
+                    x if x.takes_rom() => address += 1,
+                    _ => {}
+                }
+            }
+        }
+
+        let mut blocks = starts
+            .iter()
+            .zip(&ends)
+            .enumerate()
+            .map(|(i, (start, end))| {
+                let code = &program[*start..*end];
+                let next = Some(i + 1).filter(|x| *x < starts.len());
+                let fall_through = next.map(|to| Edge {
+                    to,
+                    kind: EdgeKind::FallThrough,
+                });
+                let (last, line) = match code.last() {
+                    Some((last, line)) if is_jump(last) => (last, line),
+                    _ => {
+                        return Block {
+                            start: *start,
+                            end: *end,
+                            successors: fall_through.into_iter().collect(),
+                            returns: false,
+                        }
+                    }
+                };
+                // the target is whatever was last put in A, unless that happened in another block
+                let target = code.iter().rev().skip(1).find_map(|(x, _)| match x {
+                    Instruction::A(Location::Label(label)) => Some(labels.get(label).copied()),
+                    Instruction::A(Location::Address(x)) => Some(addresses.get(x).copied()),
+                    Instruction::A(Location::Expression(_)) => Some(None),
+                    Instruction::C(dest, _, _) if dest.contains(&Register::A) => Some(None),
+                    _ => None,
+                });
+                let kind = match directive(line).as_deref() {
+                    Some("#call") => EdgeKind::Call,
+                    _ => EdgeKind::Jump,
+                };
+                let mut successors = target
+                    .flatten()
+                    .map(|to| Edge { to, kind })
+                    .into_iter()
+                    .collect::<Vec<Edge>>();
+                if kind == EdgeKind::Call || !always_jumps(last) {
+                    successors.extend(fall_through);
+                }
+                Block {
+                    start: *start,
+                    end: *end,
+                    successors,
+                    returns: directive(line).as_deref() == Some("#ret"),
+                }
+            })
+            .collect::<Vec<Block>>();
+
+        // a #ret goes back to just after every call of the function it is part of
+        let mut cfg = Cfg {
+            blocks: blocks.clone(),
+            labels,
+        };
+        for (caller, block) in cfg.blocks.iter().enumerate() {
+            for edge in block.successors.iter().filter(|x| x.kind == EdgeKind::Call) {
+                for returning in cfg.function(edge.to) {
+                    if cfg.blocks[returning].returns && caller + 1 < cfg.blocks.len() {
+                        blocks[returning].successors.push(Edge {
+                            to: caller + 1,
+                            kind: EdgeKind::Return,
+                        });
+                    }
+                }
+            }
+        }
+        cfg.blocks = blocks;
+        cfg
+    }
+
+    /// Every block reachable from `entry` without following calls or returns, i.e. the body of
+    /// the function starting there.  Calls made along the way are assumed to come back.
+    pub fn function(&self, entry: usize) -> Vec<usize> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut stack = vec![entry];
+        while let Some(block) = stack.pop() {
+            if visited[block] {
+                continue;
+            }
+            visited[block] = true;
+            stack.extend(
+                self.blocks[block]
+                    .successors
+                    .iter()
+                    .filter(|x| x.kind == EdgeKind::FallThrough || x.kind == EdgeKind::Jump)
+                    .map(|x| x.to),
+            );
+        }
+        (0..self.blocks.len()).filter(|x| visited[*x]).collect()
+    }
+
+    /// The number of edges coming into each block.
+    pub fn predecessors(&self) -> Vec<usize> {
+        let mut predecessors = vec![0; self.blocks.len()];
+        for block in &self.blocks {
+            for edge in &block.successors {
+                predecessors[edge.to] += 1;
+            }
+        }
+        predecessors
+    }
+
+    /// Renders the graph in Graphviz's DOT language, with the source lines each block came from.
+    pub fn to_dot(&self, program: &[(Instruction, Line)]) -> String {
+        let mut output = vec![
+            "digraph cfg {".to_string(),
+            "    node [shape=box, fontname=\"monospace\"];".to_string(),
+        ];
+        for (i, block) in self.blocks.iter().enumerate() {
+            let code = &program[block.start..block.end];
+            let mut text = vec![source_range(code.iter().map(|(_, line)| line))];
+            text.extend(code.iter().map(|(x, _)| x.to_string()));
+            let label = text
+                .iter()
+                .map(|x| x.replace('\\', "\\\\").replace('"', "\\\"") + "\\l")
+                .collect::<String>();
+            output.push(format!("    b{} [label=\"{}\"];", i, label));
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            for edge in &block.successors {
+                let style = match edge.kind {
+                    EdgeKind::FallThrough => "",
+                    EdgeKind::Jump => " [label=\"jump\"]",
+                    EdgeKind::Call => " [label=\"call\", style=dashed]",
+                    EdgeKind::Return => " [label=\"return\", style=dotted]",
+                };
+                output.push(format!("    b{} -> b{}{};", i, edge.to, style));
+            }
+        }
+        output.push("}".to_string());
+        output.join("\n") + "\n"
+    }
+}
+
+/// Describes where a run of lines came from, e.g. `main.asm:3-7`.
+fn source_range<'a>(lines: impl Iterator<Item = &'a Line>) -> String {
+    let mut ranges: Vec<(String, usize, usize)> = vec![];
+    for line in lines {
+        let file = if line.file.is_empty() && line.number == 0 {
+            "<prologue>".to_string()
+        } else {
+            line.file.clone()
+        };
+        match ranges.iter_mut().find(|(x, _, _)| *x == file) {
+            Some((_, first, last)) => {
+                *first = (*first).min(line.number);
+                *last = (*last).max(line.number);
+            }
+            None => ranges.push((file, line.number, line.number)),
+        }
+    }
+    ranges
+        .into_iter()
+        .map(|(file, first, last)| match (file.as_ref(), first == last) {
+            ("<prologue>", _) => file,
+            (_, true) => format!("{}:{}", file, first),
+            (_, false) => format!("{}:{}-{}", file, first, last),
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg::{Cfg, Edge, EdgeKind};
+    use crate::parser::parse_lines;
+    use crate::preprocessor::Preprocessable;
+    use crate::types::Line;
+
+    fn edges(cfg: &Cfg) -> Vec<(usize, usize, EdgeKind)> {
+        cfg.blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(i, x)| {
+                x.successors
+                    .iter()
+                    .map(move |Edge { to, kind }| (i, *to, *kind))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_blocks() {
+        let program = "@i\nM=0\n(LOOP)\n@i\nMD=M+1\n@10\nD=D-A\n@LOOP\nD;JLT\n(END)\n@END\n0;JMP";
+        let program = parse_lines(Line::read("main.asm", program));
+        let cfg = Cfg::new(&program);
+        assert_eq!(
+            cfg.blocks
+                .iter()
+                .map(|x| (x.start, x.end))
+                .collect::<Vec<_>>(),
+            vec![(0, 2), (2, 9), (9, 12)]
+        );
+        assert_eq!(
+            edges(&cfg),
+            vec![
+                (0, 1, EdgeKind::FallThrough),
+                (1, 1, EdgeKind::Jump),
+                (1, 2, EdgeKind::FallThrough),
+                (2, 2, EdgeKind::Jump),
+            ]
+        );
+        assert_eq!(cfg.predecessors(), vec![0, 2, 2]);
+    }
+
+    #[test]
+    fn links_calls_and_returns() {
+        let program = "#call f\n(END)\n@END\n0;JMP\n(f)\n@x\nD=M\n@SKIP\nD;JEQ\n#ret\n(SKIP)\n#ret";
+        let program = parse_lines(Line::read("main.asm", program).preprocess());
+        let cfg = Cfg::new(&program);
+        let f = cfg.labels["f"];
+        assert_eq!(
+            edges(&cfg),
+            vec![
+                (0, f, EdgeKind::Call),
+                (0, 1, EdgeKind::FallThrough),
+                (1, 1, EdgeKind::Jump),
+                (f, f + 2, EdgeKind::Jump),
+                (f, f + 1, EdgeKind::FallThrough),
+                (f + 1, 1, EdgeKind::Return),
+                (f + 2, 1, EdgeKind::Return),
+            ]
+        );
+        assert_eq!(cfg.function(f), vec![f, f + 1, f + 2]);
+
+        let dot = cfg.to_dot(&program);
+        assert!(dot.contains("    b0 -> b2 [label=\"call\", style=dashed];\n"));
+        assert!(
+            dot.contains("    b3 [label=\"main.asm:10\\l@16383\\lAM=M+1\\lA=M\\l0;JMP\\l\"];\n")
+        );
+        assert!(dot.contains("b0 [label=\"<prologue>, main.asm:1\\l"));
+    }
+}
@@ -0,0 +1,445 @@
+use crate::assembler::{decode, SymbolKind};
+use crate::emulator::{Emulator, Write as RamWrite};
+use crate::snapshot::Snapshot;
+use crate::symbols::Names;
+use crate::types::{parse_literal, Instruction, Location};
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+/// How long `continue` runs for before giving up on reaching a breakpoint.
+const MAX_CYCLES: u64 = 100_000_000;
+
+/// How many instructions can be stepped back over.
+const HISTORY: usize = 1_000_000;
+
+const HELP: &str = "\
+step [n]           execute n instructions (default 1)
+continue           run until a breakpoint or watchpoint or the program halts
+reverse-step [n]   undo the last n instructions (default 1)
+reverse-continue   go back to the last breakpoint or watchpoint
+break <location>   stop before executing the instruction at a label or ROM address
+delete <location>  remove a breakpoint
+watch <address>    stop when a word of RAM is written, by variable name or address
+unwatch <address>  remove a watchpoint
+print <address>    show a word of RAM, by variable name or address
+info               show the registers, breakpoints and watchpoints
+save <file>        write a snapshot of the registers and RAM to a file
+restore <file>     carry on from a snapshot written by save or run --save
+quit               exit the debugger";
+
+pub struct Debugger {
+    pub emulator: Emulator,
+    names: Names,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: BTreeSet<u16>,
+}
+
+impl Debugger {
+    pub fn new(mut emulator: Emulator, names: Names) -> Debugger {
+        emulator.keep_history(HISTORY);
+        Debugger {
+            emulator,
+            names,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+        }
+    }
+
+    /// Names a ROM address after the closest label at or before it, e.g. `LOOP+3`.
+    fn rom_location(&self, address: u16) -> String {
+        match (0..=address)
+            .rev()
+            .find_map(|x| self.names.rom(x).map(|name| (x, name)))
+        {
+            Some((x, name)) if x == address => format!("{} ({})", address, name),
+            Some((x, name)) => format!("{} ({}+{})", address, name, address - x),
+            None => address.to_string(),
+        }
+    }
+
+    /// Names a RAM address after its variable, e.g. `x (RAM[16])`.
+    fn ram_location(&self, address: u16) -> String {
+        match self.names.ram(address) {
+            Some(name) => format!("{} (RAM[{}])", name, address),
+            None => format!("RAM[{}]", address),
+        }
+    }
+
+    /// Describes a write if it hit a watchpoint.
+    fn watched(&self, write: Option<RamWrite>) -> Option<String> {
+        let write = write.filter(|x| self.watchpoints.contains(&x.address))?;
+        Some(format!(
+            "Watchpoint {}: {} -> {}",
+            self.ram_location(write.address),
+            write.old as i16,
+            write.new as i16
+        ))
+    }
+
+    /// Disassembles the instruction at a ROM address, noting which symbols an A-instruction
+    /// could be referring to.
+    fn instruction(&self, address: u16) -> String {
+        let word = match self.emulator.rom().get(address as usize) {
+            Some(word) => *word,
+            None => return "<end of ROM>".to_string(),
+        };
+        match decode(word) {
+            Some(Instruction::A(Location::Address(x))) => {
+                let names = self
+                    .names
+                    .ram(x)
+                    .filter(|name| {
+                        self.names.get(name).map(|x| x.kind) != Some(SymbolKind::Predefined)
+                    })
+                    .into_iter()
+                    .chain(self.names.rom(x))
+                    .collect::<Vec<&str>>();
+                if names.is_empty() {
+                    format!("@{}", x)
+                } else {
+                    format!("@{}  // {}", x, names.join(", "))
+                }
+            }
+            Some(instruction) => instruction.to_string(),
+            None => format!("{:016b}  // not a valid instruction", word),
+        }
+    }
+
+    /// The next instruction along with the registers.
+    fn state(&self) -> String {
+        let emulator = &self.emulator;
+        format!(
+            "{}: {}\nA={} D={} M={} cycles={}",
+            self.rom_location(emulator.pc),
+            self.instruction(emulator.pc),
+            emulator.a,
+            emulator.d as i16,
+            emulator.ram[(emulator.a & 0x7FFF) as usize] as i16,
+            emulator.cycles
+        )
+    }
+
+    /// Resolves a label, variable or literal to an address.
+    fn address(&self, location: &str, rom: bool) -> Result<u16, String> {
+        match parse_literal(location) {
+            Some(Ok(x)) if (0..=0x7FFF).contains(&x) => Ok(x as u16),
+            Some(Ok(_)) => Err(format!("{} is out of range", location)),
+            Some(Err(e)) => Err(e),
+            None => match self.names.get(location) {
+                Some(symbol) if symbol.kind.in_rom() == rom => Ok(symbol.address),
+                Some(_) if rom => Err(format!("{} is not a ROM address", location)),
+                Some(_) => Err(format!("{} is not a RAM address", location)),
+                None => Err(format!("Unknown symbol {}", location)),
+            },
+        }
+    }
+
+    fn continue_running(&mut self) -> String {
+        for _ in 0..MAX_CYCLES {
+            if let Some(trap) = &self.emulator.trap {
+                return format!("Stopped by {}\n{}", trap, self.state());
+            }
+            if self.emulator.halted() {
+                return format!("Halted\n{}", self.state());
+            }
+            let write = self.emulator.step();
+            if let Some(watchpoint) = self.watched(write) {
+                return format!("{}\n{}", watchpoint, self.state());
+            }
+            if self.breakpoints.contains(&self.emulator.pc) {
+                return format!("Breakpoint\n{}", self.state());
+            }
+        }
+        format!(
+            "Still running after {} cycles\n{}",
+            MAX_CYCLES,
+            self.state()
+        )
+    }
+
+    /// Steps backwards until the next instruction is at a breakpoint or is the one which wrote
+    /// to a watchpoint.
+    fn reverse_continue(&mut self) -> String {
+        while let Some(change) = self.emulator.step_back() {
+            if let Some(watchpoint) = self.watched(change.write) {
+                return format!("{}\n{}", watchpoint, self.state());
+            }
+            if self.breakpoints.contains(&self.emulator.pc) {
+                return format!("Breakpoint\n{}", self.state());
+            }
+        }
+        format!("Reached the start of the history\n{}", self.state())
+    }
+
+    /// Runs a single command, giving what it printed or `None` if the debugger should exit.
+    pub fn execute(&mut self, command: &str) -> Option<String> {
+        let mut words = command.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let argument = words.next();
+        Some(match (command, argument) {
+            ("", _) => String::new(),
+            ("step", n) | ("s", n) => match n.map(|x| x.parse::<u64>()).unwrap_or(Ok(1)) {
+                Ok(n) => {
+                    for _ in 0..n {
+                        if self.emulator.halted() {
+                            break;
+                        }
+                        self.emulator.step();
+                    }
+                    self.state()
+                }
+                Err(_) => format!("Could not parse step count {:?}", n.unwrap()),
+            },
+            ("continue", None) | ("c", None) => self.continue_running(),
+            ("reverse-step", n) | ("rs", n) => match n.map(|x| x.parse::<u64>()).unwrap_or(Ok(1)) {
+                Ok(n) => {
+                    for _ in 0..n {
+                        if self.emulator.step_back().is_none() {
+                            return Some(format!(
+                                "Reached the start of the history\n{}",
+                                self.state()
+                            ));
+                        }
+                    }
+                    self.state()
+                }
+                Err(_) => format!("Could not parse step count {:?}", n.unwrap()),
+            },
+            ("reverse-continue", None) | ("rc", None) => self.reverse_continue(),
+            ("break", Some(location)) | ("b", Some(location)) => {
+                match self.address(location, true) {
+                    Ok(address) => {
+                        self.breakpoints.insert(address);
+                        format!("Breakpoint at {}", self.rom_location(address))
+                    }
+                    Err(e) => e,
+                }
+            }
+            ("delete", Some(location)) | ("d", Some(location)) => {
+                match self.address(location, true) {
+                    Ok(address) if self.breakpoints.remove(&address) => {
+                        format!("Deleted breakpoint at {}", self.rom_location(address))
+                    }
+                    Ok(address) => format!("No breakpoint at {}", self.rom_location(address)),
+                    Err(e) => e,
+                }
+            }
+            ("watch", Some(location)) | ("w", Some(location)) => {
+                match self.address(location, false) {
+                    Ok(address) => {
+                        self.watchpoints.insert(address);
+                        format!("Watchpoint at {}", self.ram_location(address))
+                    }
+                    Err(e) => e,
+                }
+            }
+            ("unwatch", Some(location)) | ("u", Some(location)) => {
+                match self.address(location, false) {
+                    Ok(address) if self.watchpoints.remove(&address) => {
+                        format!("Deleted watchpoint at {}", self.ram_location(address))
+                    }
+                    Ok(address) => format!("No watchpoint at {}", self.ram_location(address)),
+                    Err(e) => e,
+                }
+            }
+            ("print", Some(location)) | ("p", Some(location)) => {
+                match self.address(location, false) {
+                    Ok(address) => format!(
+                        "{} = {}",
+                        self.ram_location(address),
+                        self.emulator.ram[address as usize] as i16
+                    ),
+                    Err(e) => e,
+                }
+            }
+            ("info", None) | ("i", None) => {
+                let breakpoints = self
+                    .breakpoints
+                    .iter()
+                    .map(|x| self.rom_location(*x))
+                    .collect::<Vec<String>>();
+                let watchpoints = self
+                    .watchpoints
+                    .iter()
+                    .map(|x| self.ram_location(*x))
+                    .collect::<Vec<String>>();
+                format!(
+                    "{}\nBreakpoints: {}\nWatchpoints: {}",
+                    self.state(),
+                    breakpoints.join(", "),
+                    watchpoints.join(", ")
+                )
+            }
+            ("save", Some(path)) => {
+                match Snapshot::new(&self.emulator).and_then(|x| x.save(path)) {
+                    Ok(()) => format!(
+                        "Saved a snapshot at cycle {} to {}",
+                        self.emulator.cycles, path
+                    ),
+                    Err(e) => e,
+                }
+            }
+            ("restore", Some(path)) => {
+                match Snapshot::load(path).and_then(|x| self.emulator.restore(&x)) {
+                    Ok(()) => format!("Restored {}\n{}", path, self.state()),
+                    Err(e) => e,
+                }
+            }
+            ("help", None) | ("h", None) => HELP.to_string(),
+            ("quit", None) | ("q", None) => return None,
+            _ => format!("Unknown command {:?}, try help", command),
+        })
+    }
+
+    /// Reads commands until `quit` or the end of the input.
+    pub fn repl(&mut self, input: impl BufRead, mut output: impl Write) {
+        writeln!(output, "{}", self.state()).expect("Could not write to output");
+        for command in input.lines() {
+            let command = command.expect("Could not read command");
+            match self.execute(&command) {
+                Some(text) => writeln!(output, "{}", text).expect("Could not write to output"),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assemblable;
+    use crate::debugger::Debugger;
+    use crate::emulator::Emulator;
+    use crate::parser::parse_lines;
+    use crate::preprocessor::Preprocessable;
+    use crate::symbols::{symbols, Names};
+    use crate::types::{Instruction, Line};
+
+    fn debugger() -> Debugger {
+        let lines = Line::read(
+            "main.asm",
+            "#call f\n(END)\n@END\n0;JMP\n(f)\n@x\nM=1\nM=M+1\n#ret",
+        );
+        let program = parse_lines(lines.preprocess());
+        let names = Names::new(&symbols(&program));
+        let rom = program
+            .into_iter()
+            .map(|(x, _)| x)
+            .collect::<Vec<Instruction>>()
+            .assemble();
+        Debugger::new(Emulator::new(rom), names)
+    }
+
+    #[test]
+    fn steps() {
+        let mut debugger = debugger();
+        assert_eq!(
+            debugger.execute("step 3").unwrap(),
+            "3: @12  // END\nA=16383 D=16382 M=16382 cycles=3"
+        );
+        assert_eq!(
+            debugger.execute("s").unwrap(),
+            "4: D=A\nA=12 D=16382 M=0 cycles=4"
+        );
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.execute("break f").unwrap(), "Breakpoint at 14 (f)");
+        assert_eq!(
+            debugger.execute("continue").unwrap(),
+            "Breakpoint\n14 (f): @16  // x\nA=14 D=12 M=0 cycles=12"
+        );
+        debugger.execute("step 2");
+        assert_eq!(debugger.execute("print x").unwrap(), "x (RAM[16]) = 1");
+        assert_eq!(
+            debugger.execute("info").unwrap(),
+            "16 (f+2): M=M+1\nA=16 D=12 M=1 cycles=14\nBreakpoints: 14 (f)\nWatchpoints: "
+        );
+        assert_eq!(
+            debugger.execute("delete f").unwrap(),
+            "Deleted breakpoint at 14 (f)"
+        );
+        assert_eq!(
+            debugger.execute("c").unwrap(),
+            "Halted\n12 (END): @12  // END\nA=12 D=12 M=0 cycles=19"
+        );
+        assert_eq!(debugger.execute("print 16").unwrap(), "x (RAM[16]) = 2");
+        assert_eq!(
+            debugger.execute("break x").unwrap(),
+            "x is not a ROM address"
+        );
+        assert_eq!(debugger.execute("quit"), None);
+    }
+
+    #[test]
+    fn saves_and_restores_snapshots() {
+        let path = std::env::temp_dir().join(format!("hack-asm-{}.snapshot", std::process::id()));
+        let path = path.to_string_lossy();
+        let mut debugger = debugger();
+        debugger.execute("break f");
+        debugger.execute("continue");
+        assert_eq!(
+            debugger.execute(&format!("save {}", path)).unwrap(),
+            format!("Saved a snapshot at cycle 12 to {}", path)
+        );
+        debugger.execute("c");
+        assert_eq!(debugger.execute("print x").unwrap(), "x (RAM[16]) = 2");
+        assert_eq!(
+            debugger.execute(&format!("restore {}", path)).unwrap(),
+            format!(
+                "Restored {}\n14 (f): @16  // x\nA=14 D=12 M=0 cycles=12",
+                path
+            )
+        );
+        assert_eq!(debugger.execute("print x").unwrap(), "x (RAM[16]) = 0");
+        assert_eq!(
+            debugger.execute("rs").unwrap(),
+            "Reached the start of the history\n14 (f): @16  // x\nA=14 D=12 M=0 cycles=12"
+        );
+        std::fs::remove_file(path.as_ref()).unwrap();
+    }
+
+    #[test]
+    fn steps_backwards() {
+        let mut debugger = debugger();
+        assert_eq!(
+            debugger.execute("rs").unwrap(),
+            "Reached the start of the history\n0: @16383\nA=0 D=0 M=0 cycles=0"
+        );
+        assert_eq!(
+            debugger.execute("watch 16383").unwrap(),
+            "Watchpoint at RAM[16383]"
+        );
+        debugger.execute("watch x");
+        assert_eq!(
+            debugger.execute("continue").unwrap(),
+            "Watchpoint RAM[16383]: 0 -> 16382\n3: @12  // END\nA=16383 D=16382 M=16382 cycles=3"
+        );
+        debugger.execute("unwatch 16383");
+        assert_eq!(
+            debugger.execute("c").unwrap(),
+            "Watchpoint x (RAM[16]): 0 -> 1\n16 (f+2): M=M+1\nA=16 D=12 M=1 cycles=14"
+        );
+        debugger.execute("c");
+        assert_eq!(debugger.execute("print x").unwrap(), "x (RAM[16]) = 2");
+
+        // going back stops before the instruction which wrote to x
+        assert_eq!(
+            debugger.execute("reverse-continue").unwrap(),
+            "Watchpoint x (RAM[16]): 1 -> 2\n16 (f+2): M=M+1\nA=16 D=12 M=1 cycles=14"
+        );
+        assert_eq!(debugger.execute("print x").unwrap(), "x (RAM[16]) = 1");
+        debugger.execute("unwatch x");
+        debugger.execute("break END");
+        assert_eq!(
+            debugger.execute("reverse-step 2").unwrap(),
+            "14 (f): @16  // x\nA=14 D=12 M=0 cycles=12"
+        );
+        assert_eq!(
+            debugger.execute("rc").unwrap(),
+            "Reached the start of the history\n0: @16383\nA=0 D=0 M=0 cycles=0"
+        );
+        assert_eq!(debugger.execute("print 16383").unwrap(), "RAM[16383] = 0");
+    }
+}
@@ -0,0 +1,299 @@
+use crate::emulator::{alu, Emulator, RAM_SIZE};
+use std::convert::TryInto;
+
+/// An instruction decoded ahead of time.  The C-instructions programs use most get their own ops
+/// so running them doesn't go through the ALU.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum Op {
+    /// An A-instruction.
+    Load(u16),
+    /// The `@END` of an `(END) @END 0;JMP` loop, where the program has finished.
+    Halt,
+    /// An unconditional jump which doesn't store anything.  It is the end of the program if it
+    /// jumps to itself, or back to an `@` which loads its own address.
+    Jump {
+        loops_back: bool,
+    },
+    /// `D;JGT` and the like, with the jump bits.
+    JumpOnD(u16),
+    DEqualsM,
+    DEqualsA,
+    MEqualsD,
+    AEqualsM,
+    /// `M=0`, `M=1` or `M=-1`.
+    MEquals(u16),
+    MPlusOne,
+    MMinusOne,
+    /// `AM=M+1`, which pops off a stack growing down.
+    AmPlusOne,
+    /// `AM=M-1`, which pops off a stack growing up.
+    AmMinusOne,
+    /// `MD=M+1`, which steps a counter or pointer.
+    MdPlusOne,
+    MdMinusOne,
+    DPlusM,
+    DMinusM,
+    DPlusA,
+    DMinusA,
+    MPlusD,
+    /// Any other C-instruction, with its a-bit and ALU control bits, destination and jump bits.
+    Compute {
+        comp: u16,
+        dest: u16,
+        jump: u16,
+    },
+}
+
+/// Whether the jump bits jump for the ALU's output.
+fn jumps(out: u16, jump: u16) -> bool {
+    match out as i16 {
+        x if x < 0 => jump & 0b100 != 0,
+        0 => jump & 0b010 != 0,
+        _ => jump & 0b001 != 0,
+    }
+}
+
+impl Op {
+    fn writes_ram(self) -> bool {
+        match self {
+            Op::MEqualsD
+            | Op::MEquals(_)
+            | Op::MPlusOne
+            | Op::MMinusOne
+            | Op::AmPlusOne
+            | Op::AmMinusOne
+            | Op::MdPlusOne
+            | Op::MdMinusOne
+            | Op::MPlusD => true,
+            Op::Compute { dest, .. } => dest & 0b001 != 0,
+            _ => false,
+        }
+    }
+
+    /// Whether the op always goes on to the next one without changing RAM.
+    fn is_quiet(self) -> bool {
+        match self {
+            Op::Halt | Op::Jump { .. } | Op::JumpOnD(_) => false,
+            Op::Compute { jump, .. } => jump == 0 && !self.writes_ram(),
+            _ => !self.writes_ram(),
+        }
+    }
+}
+
+fn decode(rom: &[u16], pc: usize) -> Op {
+    let instruction = rom[pc];
+    if instruction & 0x8000 == 0 {
+        let next = rom.get(pc + 1).copied().unwrap_or(0);
+        return if instruction as usize == pc && next & 0x8000 != 0 && next & 0b111_111 == 0b111 {
+            Op::Halt
+        } else {
+            Op::Load(instruction)
+        };
+    }
+    let comp = (instruction >> 6) & 0b1_111_111;
+    let dest = (instruction >> 3) & 0b111;
+    let jump = instruction & 0b111;
+    match (comp, dest, jump) {
+        (_, 0, 0b111) => Op::Jump {
+            loops_back: pc > 0 && rom[pc - 1] as usize == pc - 1,
+        },
+        (0b0_001100, 0, _) => Op::JumpOnD(jump),
+        (0b1_110000, 0b010, 0) => Op::DEqualsM,
+        (0b0_110000, 0b010, 0) => Op::DEqualsA,
+        (0b0_001100, 0b001, 0) => Op::MEqualsD,
+        (0b1_110000, 0b100, 0) => Op::AEqualsM,
+        (0b0_101010, 0b001, 0) => Op::MEquals(0),
+        (0b0_111111, 0b001, 0) => Op::MEquals(1),
+        (0b0_111010, 0b001, 0) => Op::MEquals(0xFFFF),
+        (0b1_110111, 0b001, 0) => Op::MPlusOne,
+        (0b1_110010, 0b001, 0) => Op::MMinusOne,
+        (0b1_110111, 0b101, 0) => Op::AmPlusOne,
+        (0b1_110010, 0b101, 0) => Op::AmMinusOne,
+        (0b1_110111, 0b011, 0) => Op::MdPlusOne,
+        (0b1_110010, 0b011, 0) => Op::MdMinusOne,
+        (0b1_000010, 0b010, 0) => Op::DPlusM,
+        (0b1_010011, 0b010, 0) => Op::DMinusM,
+        (0b0_000010, 0b010, 0) => Op::DPlusA,
+        (0b0_010011, 0b010, 0) => Op::DMinusA,
+        (0b1_000010, 0b001, 0) => Op::MPlusD,
+        _ => Op::Compute { comp, dest, jump },
+    }
+}
+
+/// A ROM decoded into ops, which runs the same as stepping through it one word at a time but
+/// many times faster.
+///
+/// It also spots loops which spin without getting anywhere, such as `(L) @KBD D=M @L D;JEQ`
+/// waiting on a key.  A jump back to the start of a run of ops which neither jump nor write RAM
+/// leaves the registers the same each time round once the loop can't get out, so the rest of the
+/// times round are skipped straight over.
+#[derive(Debug, Default, Clone)]
+pub struct Decoded {
+    ops: Vec<Op>,
+    /// For each op, the earliest address a jump back from it can go to and still spin: the ops
+    /// from there up to it neither jump nor write RAM.  It is past the op if the op writes RAM.
+    spins_from: Vec<u16>,
+}
+
+impl Decoded {
+    pub fn new(rom: &[u16]) -> Decoded {
+        let ops = (0..rom.len())
+            .map(|pc| decode(rom, pc))
+            .collect::<Vec<Op>>();
+        let mut spins_from = Vec::with_capacity(ops.len());
+        let mut quiet_since = 0;
+        for (pc, op) in ops.iter().enumerate() {
+            let from = if op.writes_ram() { pc + 1 } else { quiet_since };
+            spins_from.push(from as u16);
+            if !op.is_quiet() {
+                quiet_since = pc + 1;
+            }
+        }
+        Decoded { ops, spins_from }
+    }
+
+    /// Runs the emulator's program until it halts or has executed `max_cycles` instructions, the
+    /// same as `Emulator::run` without an OS, history or stack limit.  Returns whether it halted.
+    pub fn run(&self, emulator: &mut Emulator, max_cycles: u64) -> bool {
+        let (mut a, mut d, mut pc) = (emulator.a, emulator.d, emulator.pc);
+        // the last jump back into a spinning loop: where from, A and D before it and the cycles
+        // left after it
+        let mut spin: Option<(u16, u16, u16, u64)> = None;
+        let ram: &mut [u16; RAM_SIZE] = (&mut emulator.ram[..])
+            .try_into()
+            .expect("RAM is the wrong size");
+        let mut remaining = max_cycles;
+        let halted = loop {
+            let op = match self.ops.get(pc as usize) {
+                Some(op) => *op,
+                None => break true,
+            };
+            match op {
+                Op::Halt => break true,
+                Op::Jump { loops_back } if pc > 0 && (a == pc || loops_back && a == pc - 1) => {
+                    break true
+                }
+                _ if remaining == 0 => break false,
+                _ => {}
+            }
+            remaining -= 1;
+            let m = (a & 0x7FFF) as usize;
+            let (from, a0, d0) = (pc, a, d);
+            pc = match op {
+                Op::Load(value) => {
+                    a = value;
+                    pc + 1
+                }
+                Op::Halt => unreachable!(),
+                Op::Jump { .. } => a,
+                Op::JumpOnD(jump) => {
+                    if jumps(d, jump) {
+                        a
+                    } else {
+                        pc + 1
+                    }
+                }
+                Op::DEqualsM => {
+                    d = ram[m];
+                    pc + 1
+                }
+                Op::DEqualsA => {
+                    d = a;
+                    pc + 1
+                }
+                Op::MEqualsD => {
+                    ram[m] = d;
+                    pc + 1
+                }
+                Op::AEqualsM => {
+                    a = ram[m];
+                    pc + 1
+                }
+                Op::MEquals(value) => {
+                    ram[m] = value;
+                    pc + 1
+                }
+                Op::MPlusOne => {
+                    ram[m] = ram[m].wrapping_add(1);
+                    pc + 1
+                }
+                Op::MMinusOne => {
+                    ram[m] = ram[m].wrapping_sub(1);
+                    pc + 1
+                }
+                Op::AmPlusOne => {
+                    ram[m] = ram[m].wrapping_add(1);
+                    a = ram[m];
+                    pc + 1
+                }
+                Op::AmMinusOne => {
+                    ram[m] = ram[m].wrapping_sub(1);
+                    a = ram[m];
+                    pc + 1
+                }
+                Op::MdPlusOne => {
+                    ram[m] = ram[m].wrapping_add(1);
+                    d = ram[m];
+                    pc + 1
+                }
+                Op::MdMinusOne => {
+                    ram[m] = ram[m].wrapping_sub(1);
+                    d = ram[m];
+                    pc + 1
+                }
+                Op::DPlusM => {
+                    d = d.wrapping_add(ram[m]);
+                    pc + 1
+                }
+                Op::DMinusM => {
+                    d = d.wrapping_sub(ram[m]);
+                    pc + 1
+                }
+                Op::DPlusA => {
+                    d = d.wrapping_add(a);
+                    pc + 1
+                }
+                Op::DMinusA => {
+                    d = d.wrapping_sub(a);
+                    pc + 1
+                }
+                Op::MPlusD => {
+                    ram[m] = ram[m].wrapping_add(d);
+                    pc + 1
+                }
+                Op::Compute { comp, dest, jump } => {
+                    let y = if comp & 0b1_000000 != 0 { ram[m] } else { a };
+                    let out = alu(d, y, comp & 0b111111);
+                    // the jump target is the value of A from before this instruction
+                    let next = if jumps(out, jump) { a } else { pc + 1 };
+                    if dest & 0b001 != 0 {
+                        ram[m] = out;
+                    }
+                    if dest & 0b100 != 0 {
+                        a = out;
+                    }
+                    if dest & 0b010 != 0 {
+                        d = out;
+                    }
+                    next
+                }
+            };
+            if pc <= from && pc >= self.spins_from[from as usize] {
+                // going round again from the same registers with RAM untouched repeats forever
+                let length = (from - pc) as u64 + 1;
+                match spin {
+                    Some(last) if last == (from, a0, d0, remaining + length) => {
+                        remaining %= length;
+                        spin = None;
+                    }
+                    _ => spin = Some((from, a0, d0, remaining)),
+                }
+            }
+        };
+        emulator.a = a;
+        emulator.d = d;
+        emulator.pc = pc;
+        emulator.cycles += max_cycles - remaining;
+        halted
+    }
+}
@@ -0,0 +1,479 @@
+use crate::assembler::STACK_POINTER;
+use crate::decoded::Decoded;
+use crate::os::Os;
+use crate::snapshot::{rom_hash, Snapshot};
+use crate::types::parse_word;
+use std::collections::VecDeque;
+
+/// The number of words of RAM addressable by the A register.
+pub const RAM_SIZE: usize = 0x8000;
+
+/// Where the keyboard's memory map puts the key being pressed.
+const KBD: usize = 24576;
+
+/// A Hack CPU with its ROM and RAM.
+pub struct Emulator {
+    rom: Vec<u16>,
+    decoded: Decoded,
+    pub ram: Vec<u16>,
+    pub a: u16,
+    pub d: u16,
+    pub pc: u16,
+    pub cycles: u64,
+    /// Runs calls to the Jack OS natively when set.
+    pub os: Option<Os>,
+    /// Stops the program when the stack pointer goes below this, into variable space.
+    pub stack_limit: Option<u16>,
+    /// Why the program was stopped, if it was.
+    pub trap: Option<String>,
+    /// The keys to press, as the cycle to press each one at and its code, with 0 letting go.
+    pub keyboard: Vec<(u64, u16)>,
+    /// How many of the keys in `keyboard` have been pressed so far.
+    pub keys_pressed: usize,
+    /// What the most recent instructions changed, newest last, so they can be undone.
+    history: VecDeque<Change>,
+    history_limit: usize,
+}
+
+/// Computes the Hack ALU's output.  The control bits are zx, nx, zy, ny, f and no from most to
+/// least significant.
+pub(crate) fn alu(x: u16, y: u16, control: u16) -> u16 {
+    let x = if control & 0b100000 != 0 { 0 } else { x };
+    let x = if control & 0b010000 != 0 { !x } else { x };
+    let y = if control & 0b001000 != 0 { 0 } else { y };
+    let y = if control & 0b000100 != 0 { !y } else { y };
+    let out = if control & 0b000010 != 0 {
+        x.wrapping_add(y)
+    } else {
+        x & y
+    };
+    if control & 0b000001 != 0 {
+        !out
+    } else {
+        out
+    }
+}
+
+/// Whether the instruction unconditionally jumps without storing anything.
+fn is_plain_jump(instruction: u16) -> bool {
+    instruction & 0x8000 != 0 && instruction & 0b111_111 == 0b000_111
+}
+
+/// A word of RAM changed by an instruction.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Write {
+    pub address: u16,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// The registers from before an instruction along with the word of RAM it changed, which is
+/// everything needed to undo it.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Change {
+    pub pc: u16,
+    pub a: u16,
+    pub d: u16,
+    pub write: Option<Write>,
+}
+
+/// Reads a `.hack` file, one 16-bit binary word per line.
+pub fn read_rom(text: &str) -> Result<Vec<u16>, String> {
+    text.lines()
+        .map(|x| x.trim())
+        .enumerate()
+        .filter(|(_, x)| !x.is_empty())
+        .map(|(i, x)| match u16::from_str_radix(x, 2) {
+            Ok(word) if x.len() == 16 => Ok(word),
+            _ => Err(format!(
+                "Line {}: {:?} is not a 16-bit binary word",
+                i + 1,
+                x
+            )),
+        })
+        .collect()
+}
+
+/// Reads a keyboard script, one `CYCLE KEY` line per key, where the key is any literal such as
+/// `65`, `'A'` or `0x8C` and 0 lets go.  Blank lines and `//` comments are skipped.
+pub fn read_keyboard(text: &str) -> Result<Vec<(u64, u16)>, String> {
+    let mut keys: Vec<(u64, u16)> = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split("//").next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |e: String| format!("Line {}: {}", i + 1, e);
+        let (cycle, key) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| error(format!("Expected CYCLE KEY, not {:?}", line)))?;
+        let cycle = cycle
+            .parse::<u64>()
+            .map_err(|_| error(format!("{:?} is not a cycle", cycle)))?;
+        let key = parse_word(key.trim()).map_err(error)?;
+        match keys.last() {
+            Some((last, _)) if cycle < *last => {
+                return Err(error(format!(
+                    "Cycle {} comes before cycle {}",
+                    cycle, last
+                )))
+            }
+            _ => keys.push((cycle, key)),
+        }
+    }
+    Ok(keys)
+}
+
+impl Emulator {
+    pub fn new(rom: Vec<u16>) -> Emulator {
+        Emulator {
+            decoded: Decoded::new(&rom),
+            rom,
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+            cycles: 0,
+            os: None,
+            stack_limit: None,
+            trap: None,
+            keyboard: vec![],
+            keys_pressed: 0,
+            history: VecDeque::new(),
+            history_limit: 0,
+        }
+    }
+
+    /// Remembers what the last `limit` instructions changed so `step_back` can undo them.  A limit
+    /// of 0, the default, keeps nothing.
+    pub fn keep_history(&mut self, limit: usize) {
+        self.history_limit = limit;
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+    }
+
+    /// Undoes the last instruction executed, giving what it changed, or `None` if there is no
+    /// history left.
+    pub fn step_back(&mut self) -> Option<Change> {
+        let change = self.history.pop_back()?;
+        self.trap = None;
+        self.pc = change.pc;
+        self.a = change.a;
+        self.d = change.d;
+        if let Some(write) = change.write {
+            self.ram[write.address as usize] = write.old;
+        }
+        self.cycles -= 1;
+        Some(change)
+    }
+
+    pub fn rom(&self) -> &[u16] {
+        &self.rom
+    }
+
+    /// Carries on from a snapshot of the same program, forgetting the history.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        if self.os.is_some() {
+            return Err("Snapshots can't include the Jack OS run with --os".to_string());
+        }
+        if snapshot.rom != rom_hash(&self.rom) {
+            return Err("The snapshot is of a different ROM".to_string());
+        }
+        if snapshot.keys_pressed > self.keyboard.len() {
+            return Err(format!(
+                "The snapshot has pressed {} keys, but the keyboard script only has {}",
+                snapshot.keys_pressed,
+                self.keyboard.len()
+            ));
+        }
+        if snapshot.ram.len() != RAM_SIZE {
+            return Err(format!(
+                "The snapshot has {} words of RAM rather than {}",
+                snapshot.ram.len(),
+                RAM_SIZE
+            ));
+        }
+        self.ram.clone_from(&snapshot.ram);
+        self.pc = snapshot.pc;
+        self.a = snapshot.a;
+        self.d = snapshot.d;
+        self.cycles = snapshot.cycles;
+        self.keys_pressed = snapshot.keys_pressed;
+        self.trap = None;
+        self.history.clear();
+        Ok(())
+    }
+
+    /// Presses the keys in the keyboard script which are due by now.  Like an OS call, a key isn't
+    /// an instruction to undo, so the history starts again after it.
+    fn press_keys(&mut self) {
+        while let Some(&(cycle, key)) = self.keyboard.get(self.keys_pressed) {
+            if cycle > self.cycles {
+                break;
+            }
+            self.ram[KBD] = key;
+            self.keys_pressed += 1;
+            self.history.clear();
+        }
+    }
+
+    /// Executes the instruction at the program counter, giving the word of RAM it changed if any.
+    pub fn step(&mut self) -> Option<Write> {
+        self.press_keys();
+        let (pc, a, d) = (self.pc, self.a, self.d);
+        // an OS call changes too much to undo, so the history starts again after it
+        if let Some(os) = self.os.as_mut() {
+            if let Some(pc) = os.call(pc, &mut self.ram) {
+                self.pc = pc;
+                self.cycles += 1;
+                self.history.clear();
+                return None;
+            }
+        }
+        let write = self.execute();
+        match (write, self.stack_limit) {
+            (Some(write), Some(limit)) if write.address == STACK_POINTER && write.new < limit => {
+                self.trap = Some(format!(
+                    "the stack pointer reaching {} at PC {}, below the last variable at {}",
+                    write.new,
+                    pc,
+                    limit - 1
+                ));
+            }
+            _ => {}
+        }
+        if self.history_limit > 0 {
+            if self.history.len() == self.history_limit {
+                self.history.pop_front();
+            }
+            self.history.push_back(Change { pc, a, d, write });
+        }
+        write
+    }
+
+    fn execute(&mut self) -> Option<Write> {
+        let instruction = self.rom[self.pc as usize];
+        self.cycles += 1;
+        if instruction & 0x8000 == 0 {
+            self.a = instruction;
+            self.pc += 1;
+            return None;
+        }
+
+        let address = self.a & 0x7FFF;
+        let y = if instruction & 0x1000 != 0 {
+            self.ram[address as usize]
+        } else {
+            self.a
+        };
+        let out = alu(self.d, y, (instruction >> 6) & 0b111111);
+        let jump = match out as i16 {
+            x if x < 0 => instruction & 0b100 != 0,
+            0 => instruction & 0b010 != 0,
+            _ => instruction & 0b001 != 0,
+        };
+        // the jump target is the value of A from before this instruction
+        self.pc = if jump { self.a } else { self.pc + 1 };
+        let write = if instruction & 0b001_000 != 0 {
+            let old = std::mem::replace(&mut self.ram[address as usize], out);
+            Some(Write {
+                address,
+                old,
+                new: out,
+            })
+        } else {
+            None
+        };
+        if instruction & 0b100_000 != 0 {
+            self.a = out;
+        }
+        if instruction & 0b010_000 != 0 {
+            self.d = out;
+        }
+        write
+    }
+
+    /// Whether the program has finished, either by running off the end of the ROM, by reaching the
+    /// `(END) @END 0;JMP` loop Hack programs end with or by being trapped.
+    pub fn halted(&self) -> bool {
+        if self.trap.is_some() {
+            return true;
+        }
+        match &self.os {
+            Some(os) if os.halted() => return true,
+            Some(os) if os.handles(self.pc) => return false,
+            _ => {}
+        }
+        let pc = self.pc as usize;
+        match (self.rom.get(pc), self.rom.get(pc + 1)) {
+            (None, _) => true,
+            (Some(&x), Some(&next)) if x as usize == pc && is_plain_jump(next) => true,
+            (Some(&x), _) if is_plain_jump(x) && pc > 0 => {
+                self.a as usize == pc || (self.a as usize == pc - 1 && self.rom[pc - 1] == self.a)
+            }
+            _ => false,
+        }
+    }
+
+    /// Runs until the program halts or has executed `max_cycles` instructions.  Returns whether the
+    /// program halted.
+    pub fn run(&mut self, max_cycles: u64) -> bool {
+        // the decoded ROM is much faster, but only does what the CPU does
+        let plain = self.os.is_none() && self.history_limit == 0 && self.stack_limit.is_none();
+        if plain && self.trap.is_none() && self.ram.len() == RAM_SIZE {
+            let decoded = std::mem::take(&mut self.decoded);
+            let end = self.cycles.saturating_add(max_cycles);
+            // it runs up to each key in turn, so the keys are pressed at the right cycle
+            let halted = loop {
+                self.press_keys();
+                let until = match self.keyboard.get(self.keys_pressed) {
+                    Some(&(cycle, _)) => cycle.min(end),
+                    None => end,
+                };
+                let halted = decoded.run(self, until - self.cycles);
+                if halted || self.cycles >= end {
+                    break halted;
+                }
+            };
+            self.decoded = decoded;
+            return halted;
+        }
+        for _ in 0..max_cycles {
+            if self.halted() {
+                return true;
+            }
+            self.step();
+        }
+        self.halted()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assemblable;
+    use crate::emulator::{read_keyboard, Emulator};
+    use crate::parser::parse;
+    use crate::preprocessor::Preprocessable;
+    use std::fs;
+
+    fn run(program: &str) -> Emulator {
+        let mut emulator = Emulator::new(parse(program.to_string()).assemble());
+        assert!(emulator.run(10_000));
+        emulator
+    }
+
+    #[test]
+    fn computes() {
+        let emulator = run("@7\nD=A\n@3\nD=D-A\n@0\nM=D\nM=M+1\nD=!D\n@1\nM=D\n@2\nM=-1");
+        assert_eq!(emulator.ram[0], 5);
+        assert_eq!(emulator.ram[1], !4);
+        assert_eq!(emulator.ram[2], 0xFFFF);
+    }
+
+    #[test]
+    fn jumps() {
+        // multiplies R0 by R1 into R2 with repeated addition
+        let emulator = run("@6\nD=A\n@R0\nM=D\n@7\nD=A\n@R1\nM=D\n@R2\nM=0\n\
+                 (LOOP)\n@R1\nD=M\n@END\nD;JEQ\n@R0\nD=M\n@R2\nM=D+M\n@R1\nM=M-1\n@LOOP\n0;JMP\n\
+                 (END)\n@END\n0;JMP");
+        assert_eq!(emulator.ram[2], 42);
+    }
+
+    #[test]
+    fn calls_and_returns() {
+        let program = fs::read_to_string("test_cases/function_test_depth.asm")
+            .unwrap()
+            .split('\n')
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>()
+            .preprocess();
+        let emulator = run(&program.join("\n"));
+        assert_eq!(emulator.ram[..3], [5, 5, 5]);
+        assert_eq!(emulator.ram[16383], 16382);
+    }
+
+    #[test]
+    fn traps_stack_overflow() {
+        // recurses forever, so the stack runs down into the variables
+        let program = "@x\nM=1\n(f)\n#call f"
+            .split('\n')
+            .map(String::from)
+            .collect::<Vec<String>>()
+            .preprocess();
+        let mut emulator = Emulator::new(parse(program.join("\n")).assemble());
+        emulator.stack_limit = Some(17);
+        assert!(emulator.run(1_000_000));
+        assert_eq!(emulator.ram[16383], 16);
+        assert_eq!(emulator.ram[16], 1);
+        assert_eq!(
+            emulator.trap.as_deref(),
+            Some("the stack pointer reaching 16 at PC 11, below the last variable at 16")
+        );
+    }
+
+    #[test]
+    fn skips_spinning_loops() {
+        // counts D down to 0 without touching RAM, then waits on a key which never comes
+        let program = "@5\nD=A\n(COUNT)\nD=D-1\n@COUNT\nD;JGT\n(WAIT)\n@KBD\nD=M\n@WAIT\nD;JEQ";
+        let rom = parse(program.to_string()).assemble();
+        for cycles in [0, 1, 25, 1_000, 1_003] {
+            let mut decoded = Emulator::new(rom.clone());
+            let mut stepped = Emulator::new(rom.clone());
+            stepped.keep_history(1);
+            assert!(!decoded.run(cycles) && !stepped.run(cycles));
+            assert_eq!(
+                (decoded.pc, decoded.a, decoded.d, decoded.cycles),
+                (stepped.pc, stepped.a, stepped.d, stepped.cycles)
+            );
+        }
+        let mut emulator = Emulator::new(rom);
+        assert!(!emulator.run(1 << 50));
+        assert_eq!(emulator.cycles, 1 << 50);
+    }
+
+    #[test]
+    fn presses_keys() {
+        assert_eq!(
+            read_keyboard("// press A then let go\n100 'A'\n\n250 0"),
+            Ok(vec![(100, 65), (250, 0)])
+        );
+        assert_eq!(
+            read_keyboard("100 65\n50 0"),
+            Err("Line 2: Cycle 50 comes before cycle 100".into())
+        );
+        assert_eq!(
+            read_keyboard("100"),
+            Err("Line 1: Expected CYCLE KEY, not \"100\"".into())
+        );
+        // waits for a key and counts how long it was held down for
+        let program = "(WAIT)\n@KBD\nD=M\n@WAIT\nD;JEQ\n(HELD)\n@held\nM=M+1\n@KBD\nD=M\n\
+                       @HELD\nD;JNE\n(END)\n@END\n0;JMP";
+        let rom = parse(program.to_string()).assemble();
+        let keyboard = read_keyboard("1000000 'A'\n1000600 0").unwrap();
+        let mut decoded = Emulator::new(rom.clone());
+        let mut stepped = Emulator::new(rom);
+        stepped.keep_history(1);
+        for emulator in [&mut decoded, &mut stepped] {
+            emulator.keyboard = keyboard.clone();
+            assert!(emulator.run(2_000_000));
+            assert_eq!((emulator.ram[16], emulator.keys_pressed), (100, 2));
+        }
+        assert_eq!(decoded.cycles, stepped.cycles);
+    }
+
+    #[test]
+    fn steps_back() {
+        let mut emulator =
+            Emulator::new(parse("@5\nD=A\n@0\nM=D\nMD=M+1\n@0\nM=D".to_string()).assemble());
+        emulator.keep_history(3);
+        emulator.run(10);
+        assert_eq!(emulator.ram[0], 6);
+        for _ in 0..3 {
+            assert!(emulator.step_back().is_some());
+        }
+        assert_eq!(emulator.step_back(), None);
+        assert_eq!((emulator.pc, emulator.a, emulator.d), (4, 0, 5));
+        assert_eq!((emulator.ram[0], emulator.cycles), (5, 4));
+    }
+}
@@ -0,0 +1,335 @@
+//! Integer expressions, such as the operand of `@SCREEN+32*ROW`.
+
+use crate::types::parse_literal;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Expression {
+    Number(i64),
+    Symbol(String),
+    Negate(Box<Expression>),
+    Binary(Box<Expression>, Operator, Box<Expression>),
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Operator {
+    Multiply,
+    Divide,
+    Remainder,
+    Add,
+    Subtract,
+    ShiftLeft,
+    ShiftRight,
+    And,
+    Or,
+}
+
+const OPERATORS: [(&str, Operator); 9] = [
+    ("<<", Operator::ShiftLeft),
+    (">>", Operator::ShiftRight),
+    ("*", Operator::Multiply),
+    ("/", Operator::Divide),
+    ("%", Operator::Remainder),
+    ("+", Operator::Add),
+    ("-", Operator::Subtract),
+    ("&", Operator::And),
+    ("|", Operator::Or),
+];
+
+impl Operator {
+    /// How tightly the operator binds, as in C.
+    fn precedence(self) -> u8 {
+        match self {
+            Operator::Multiply | Operator::Divide | Operator::Remainder => 5,
+            Operator::Add | Operator::Subtract => 4,
+            Operator::ShiftLeft | Operator::ShiftRight => 3,
+            Operator::And => 2,
+            Operator::Or => 1,
+        }
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (text, _) = OPERATORS.iter().find(|(_, x)| x == self).unwrap();
+        write!(f, "{}", text)
+    }
+}
+
+/// Whether a character can be part of a symbol.
+pub fn is_symbol_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || "_.$:".contains(ch)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Token {
+    Number(i64),
+    Symbol(String),
+    Operator(Operator),
+    Open,
+    Close,
+}
+
+fn tokens(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut rest = text.trim_start();
+    while let Some(ch) = rest.chars().next() {
+        let length = if ch == '\'' {
+            // a character literal, which can be any character at all
+            rest.char_indices()
+                .nth(2)
+                .map_or(rest.len(), |(i, x)| i + x.len_utf8())
+        } else if is_symbol_char(ch) {
+            rest.find(|x| !is_symbol_char(x)).unwrap_or(rest.len())
+        } else {
+            0
+        };
+        let token = if length > 0 {
+            let word = &rest[..length];
+            match parse_literal(word) {
+                Some(Ok(x)) => Token::Number(x as i64),
+                Some(Err(e)) => return Err(e),
+                None => Token::Symbol(word.to_string()),
+            }
+        } else if let Some((operator, x)) = OPERATORS.iter().find(|(x, _)| rest.starts_with(x)) {
+            rest = &rest[operator.len()..];
+            tokens.push(Token::Operator(*x));
+            rest = rest.trim_start();
+            continue;
+        } else {
+            match ch {
+                '(' => Token::Open,
+                ')' => Token::Close,
+                _ => return Err(format!("Unexpected {:?} in {}", ch, text)),
+            }
+        };
+        rest = &rest[length.max(ch.len_utf8())..];
+        tokens.push(token);
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn error(&self) -> String {
+        match self.peek() {
+            Some(_) => format!("Could not parse expression {}", self.text),
+            None => format!("Expression {} ends too soon", self.text),
+        }
+    }
+
+    /// Parses operators which bind at least as tightly as `precedence`.
+    fn binary(&mut self, precedence: u8) -> Result<Expression, String> {
+        let mut lhs = self.unary()?;
+        while let Some(Token::Operator(operator)) = self.peek().cloned() {
+            if operator.precedence() < precedence {
+                break;
+            }
+            self.next += 1;
+            let rhs = self.binary(operator.precedence() + 1)?;
+            lhs = Expression::Binary(Box::new(lhs), operator, Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        let token = self.peek().cloned().ok_or_else(|| self.error())?;
+        self.next += 1;
+        match token {
+            Token::Number(x) => Ok(Expression::Number(x)),
+            Token::Symbol(x) => Ok(Expression::Symbol(x)),
+            Token::Operator(Operator::Subtract) => Ok(Expression::Negate(Box::new(self.unary()?))),
+            Token::Open => {
+                let inner = self.binary(0)?;
+                match self.peek() {
+                    Some(Token::Close) => {
+                        self.next += 1;
+                        Ok(inner)
+                    }
+                    _ => Err(self.error()),
+                }
+            }
+            _ => {
+                self.next -= 1;
+                Err(self.error())
+            }
+        }
+    }
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Expression, String> {
+        let mut parser = Parser {
+            text,
+            tokens: tokens(text)?,
+            next: 0,
+        };
+        let expression = parser.binary(0)?;
+        match parser.peek() {
+            None => Ok(expression),
+            Some(_) => Err(parser.error()),
+        }
+    }
+
+    /// Every symbol the expression uses, in the order they appear.
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expression::Number(_) => vec![],
+            Expression::Symbol(x) => vec![x.as_str()],
+            Expression::Negate(x) => x.symbols(),
+            Expression::Binary(lhs, _, rhs) => {
+                let mut symbols = lhs.symbols();
+                symbols.extend(rhs.symbols());
+                symbols
+            }
+        }
+    }
+
+    /// Works out the value, looking symbols up with `lookup`.  Errors name the part of the
+    /// expression they come from.
+    pub fn evaluate(&self, lookup: &dyn Fn(&str) -> Option<i64>) -> Result<i64, String> {
+        let (lhs, operator, rhs) = match self {
+            Expression::Number(x) => return Ok(*x),
+            Expression::Symbol(x) => return lookup(x).ok_or(format!("Unknown symbol {}", x)),
+            Expression::Negate(x) => {
+                return x
+                    .evaluate(lookup)?
+                    .checked_neg()
+                    .ok_or_else(|| format!("{} overflows", self))
+            }
+            Expression::Binary(lhs, operator, rhs) => {
+                (lhs.evaluate(lookup)?, *operator, rhs.evaluate(lookup)?)
+            }
+        };
+        let value = match operator {
+            Operator::Multiply => lhs.checked_mul(rhs),
+            Operator::Divide => lhs.checked_div(rhs),
+            Operator::Remainder => lhs.checked_rem(rhs),
+            Operator::Add => lhs.checked_add(rhs),
+            Operator::Subtract => lhs.checked_sub(rhs),
+            Operator::ShiftLeft | Operator::ShiftRight if !(0..16).contains(&rhs) => {
+                return Err(format!("Can't shift by {} in {}", rhs, self));
+            }
+            // checked_shl only checks the shift amount, not the bits shifted out
+            Operator::ShiftLeft => lhs.checked_mul(1 << rhs),
+            Operator::ShiftRight => lhs.checked_shr(rhs as u32),
+            Operator::And => Some(lhs & rhs),
+            Operator::Or => Some(lhs | rhs),
+        };
+        value.ok_or_else(|| match operator {
+            Operator::Divide | Operator::Remainder if rhs == 0 => {
+                format!("Division by zero in {}", self)
+            }
+            _ => format!("{} overflows", self),
+        })
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expression::Binary(_, operator, _) => operator.precedence(),
+            _ => u8::MAX,
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expression::Number(x) => write!(f, "{}", x),
+            Expression::Symbol(x) => write!(f, "{}", x),
+            Expression::Negate(x) if x.precedence() == u8::MAX => write!(f, "-{}", x),
+            Expression::Negate(x) => write!(f, "-({})", x),
+            Expression::Binary(lhs, operator, rhs) => {
+                // operators group to the left, so the right needs brackets at the same precedence
+                if lhs.precedence() < operator.precedence() {
+                    write!(f, "({})", lhs)?;
+                } else {
+                    write!(f, "{}", lhs)?;
+                }
+                write!(f, "{}", operator)?;
+                if rhs.precedence() <= operator.precedence() {
+                    write!(f, "({})", rhs)
+                } else {
+                    write!(f, "{}", rhs)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expression::Expression;
+
+    fn evaluate(text: &str) -> Result<i64, String> {
+        Expression::parse(text)?.evaluate(&|x| match x {
+            "SCREEN" => Some(16384),
+            "ROW" => Some(3),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn evaluates() {
+        assert_eq!(evaluate("SCREEN+32*ROW"), Ok(16480));
+        assert_eq!(evaluate("(SCREEN + 32) * 0"), Ok(0));
+        assert_eq!(evaluate("1<<4|1"), Ok(17));
+        assert_eq!(evaluate("10-3-2"), Ok(5));
+        assert_eq!(evaluate("-(2+3)*-2"), Ok(10));
+        assert_eq!(evaluate("0x10 + 'A' - 0b1 % 7 / 1"), Ok(80));
+        assert_eq!(evaluate("SCREEN >> 14 & 3"), Ok(1));
+    }
+
+    #[test]
+    fn points_at_errors() {
+        assert_eq!(
+            evaluate("ROW+(2/(ROW-3))"),
+            Err("Division by zero in 2/(ROW-3)".into())
+        );
+        assert_eq!(
+            evaluate("1+(2<<16)"),
+            Err("Can't shift by 16 in 2<<16".into())
+        );
+        assert_eq!(
+            evaluate("(32768*32768*32768*32768)<<4"),
+            Err("32768*32768*32768*32768<<4 overflows".into())
+        );
+        assert_eq!(
+            evaluate("-(-(32768*32768*32768*32768*4)*2)"),
+            Err("-(-(32768*32768*32768*32768*4)*2) overflows".into())
+        );
+        assert_eq!(
+            evaluate("SCREEN+COLUMN"),
+            Err("Unknown symbol COLUMN".into())
+        );
+        assert_eq!(
+            evaluate("(1+2"),
+            Err("Expression (1+2 ends too soon".into())
+        );
+        assert_eq!(
+            evaluate("1+*2"),
+            Err("Could not parse expression 1+*2".into())
+        );
+    }
+
+    #[test]
+    fn formats_what_it_parses() {
+        for text in [
+            "A-(B-C)", "(A-B)-C", "A*(B+C)", "-(A+B)", "A|B&C", "(A|B)&C", "-A",
+        ] {
+            let expression = Expression::parse(text).unwrap();
+            assert_eq!(Expression::parse(&expression.to_string()), Ok(expression));
+        }
+        assert_eq!(Expression::parse("(A-B)-C").unwrap().to_string(), "A-B-C");
+    }
+}
@@ -0,0 +1,184 @@
+//! The formats assembled machine code can be written in.
+
+/// How many words go on each line of the text formats which put more than one on a line.
+const WORDS_PER_LINE: usize = 8;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Format {
+    /// One 16-digit binary word per line, as the Nand2Tetris tools read.
+    Hack,
+    /// Two bytes per word, most significant first.
+    RawBigEndian,
+    RawLittleEndian,
+    /// Intel HEX, byte addressed with each word big-endian.
+    IntelHex,
+    /// A Logisim "v2.0 raw" memory image.
+    Logisim,
+    /// For Verilog's `$readmemb`.
+    Memb,
+    /// For Verilog's `$readmemh`.
+    Memh,
+    C,
+    Rust,
+}
+
+pub const FORMATS: [(&str, Format); 9] = [
+    ("hack", Format::Hack),
+    ("raw-be", Format::RawBigEndian),
+    ("raw-le", Format::RawLittleEndian),
+    ("ihex", Format::IntelHex),
+    ("logisim", Format::Logisim),
+    ("memb", Format::Memb),
+    ("memh", Format::Memh),
+    ("c", Format::C),
+    ("rust", Format::Rust),
+];
+
+impl Format {
+    pub fn from_name(name: &str) -> Result<Format, String> {
+        FORMATS
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, format)| *format)
+            .ok_or_else(|| {
+                let names = FORMATS.iter().map(|(x, _)| *x).collect::<Vec<&str>>();
+                format!(
+                    "Unknown format {}, expected one of {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+
+    /// Writes the words out.  Only the raw formats aren't text.
+    pub fn write(self, words: &[u16]) -> Vec<u8> {
+        match self {
+            Format::Hack | Format::Memb => lines(words.iter().map(|x| format!("{:016b}", x))),
+            Format::Memh => lines(words.iter().map(|x| format!("{:04x}", x))),
+            Format::RawBigEndian => words.iter().flat_map(|x| x.to_be_bytes()).collect(),
+            Format::RawLittleEndian => words.iter().flat_map(|x| x.to_le_bytes()).collect(),
+            Format::IntelHex => intel_hex(words).into_bytes(),
+            Format::Logisim => logisim(words).into_bytes(),
+            Format::C => array(
+                words,
+                &format!(
+                    "#include <stdint.h>\n\nconst uint16_t rom[{}] = {{",
+                    words.len()
+                ),
+                "};",
+            ),
+            Format::Rust => array(
+                words,
+                &format!("pub const ROM: [u16; {}] = [", words.len()),
+                "];",
+            ),
+        }
+    }
+}
+
+/// Parses `FORMAT` or `FORMAT:FILE`, as given to `--format`.
+pub fn parse_output(text: &str) -> Result<(Format, Option<&str>), String> {
+    match text.find(':') {
+        Some(i) => Ok((Format::from_name(&text[..i])?, Some(&text[i + 1..]))),
+        None => Ok((Format::from_name(text)?, None)),
+    }
+}
+
+fn lines(lines: impl Iterator<Item = String>) -> Vec<u8> {
+    lines.map(|x| x + "\n").collect::<String>().into_bytes()
+}
+
+fn array(words: &[u16], start: &str, end: &str) -> Vec<u8> {
+    let rows = words.chunks(WORDS_PER_LINE).map(|chunk| {
+        let row = chunk.iter().map(|x| format!("0x{:04x},", x));
+        format!("    {}", row.collect::<Vec<String>>().join(" "))
+    });
+    lines(
+        std::iter::once(start.to_string())
+            .chain(rows)
+            .chain(std::iter::once(end.to_string())),
+    )
+}
+
+/// A data record for every 16 bytes followed by an end of file record.  ROM is at most 64KiB, so
+/// it never needs an extended address record.
+fn intel_hex(words: &[u16]) -> String {
+    fn record(kind: u8, address: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend(address.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend(data);
+        let checksum = bytes
+            .iter()
+            .fold(0u8, |acc, x| acc.wrapping_add(*x))
+            .wrapping_neg();
+        bytes.push(checksum);
+        let hex = bytes
+            .iter()
+            .map(|x| format!("{:02X}", x))
+            .collect::<String>();
+        format!(":{}\n", hex)
+    }
+
+    let bytes = Format::RawBigEndian.write(words);
+    let mut output = String::new();
+    for (i, chunk) in bytes.chunks(16).enumerate() {
+        output += &record(0, (i * 16) as u16, chunk);
+    }
+    output + &record(1, 0, &[])
+}
+
+/// Words in hex, with runs of four or more of the same word written `count*word` as Logisim does.
+fn logisim(words: &[u16]) -> String {
+    let mut runs: Vec<(usize, u16)> = vec![];
+    for word in words {
+        match runs.last_mut() {
+            Some((count, last)) if last == word => *count += 1,
+            _ => runs.push((1, *word)),
+        }
+    }
+    let items = runs.into_iter().flat_map(|(count, word)| {
+        if count >= 4 {
+            vec![format!("{}*{:x}", count, word)]
+        } else {
+            vec![format!("{:x}", word); count]
+        }
+    });
+    let items = items.collect::<Vec<String>>();
+    let rows = items.chunks(WORDS_PER_LINE).map(|x| x.join(" ") + "\n");
+    "v2.0 raw\n".to_string() + &rows.collect::<String>()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::formats::{parse_output, Format};
+
+    #[test]
+    fn writes_intel_hex() {
+        let words = (0..9).map(|x| x * 0x0101).collect::<Vec<u16>>();
+        assert_eq!(
+            String::from_utf8(Format::IntelHex.write(&words)).unwrap(),
+            ":1000000000000101020203030404050506060707B8\n\
+             :020010000808DE\n\
+             :00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn writes_runs_for_logisim() {
+        assert_eq!(
+            String::from_utf8(Format::Logisim.write(&[1, 0, 0, 0, 0, 0, 0xABCD, 7, 7])).unwrap(),
+            "v2.0 raw\n1 5*0 abcd 7 7\n"
+        );
+    }
+
+    #[test]
+    fn parses_outputs() {
+        assert_eq!(parse_output("ihex"), Ok((Format::IntelHex, None)));
+        assert_eq!(
+            parse_output("RAW-LE:out/rom.bin"),
+            Ok((Format::RawLittleEndian, Some("out/rom.bin")))
+        );
+        assert!(parse_output("srec:rom.s19").is_err());
+    }
+}
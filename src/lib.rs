@@ -0,0 +1,67 @@
+//! An assembler, preprocessor, optimizer and emulator for the Hack computer from Nand2Tetris.
+
+pub mod assembler;
+pub mod cfg;
+pub mod debugger;
+pub mod decoded;
+pub mod emulator;
+pub mod expression;
+pub mod formats;
+pub mod lint;
+pub mod listing;
+pub mod lsp;
+pub mod memory;
+pub mod object;
+pub mod optimizer;
+pub mod os;
+pub mod parser;
+pub mod preprocessor;
+pub mod profiler;
+#[cfg(test)]
+mod properties;
+pub mod snapshot;
+pub mod stdlib;
+pub mod symbols;
+pub mod trace;
+pub mod types;
+
+use assembler::Assemblable;
+use preprocessor::Preprocessable;
+use types::{Instruction, Line};
+
+/// Preprocesses, parses and assembles a whole file, giving the first error in it.
+pub fn assemble(file: &str, text: &str) -> Result<Vec<u16>, String> {
+    parser::try_parse_lines(Line::read(file, text).try_preprocess()?)?
+        .into_iter()
+        .map(|(x, _)| x)
+        .collect::<Vec<Instruction>>()
+        .try_assemble()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    /// Inputs which used to panic.  Each file should be an error, and each of its lines should
+    /// give an error or assemble on its own.
+    #[test]
+    fn rejects_the_fuzz_corpus() {
+        let mut paths = fs::read_dir("test_cases/fuzz")
+            .unwrap()
+            .map(|x| x.unwrap().path())
+            .collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            let text = fs::read_to_string(&path).unwrap();
+            let file = path.to_string_lossy();
+            assert!(
+                super::assemble(&file, &text).is_err(),
+                "{:?} assembled",
+                path
+            );
+            for line in text.lines() {
+                let _ = super::assemble(&file, line);
+            }
+        }
+    }
+}
@@ -0,0 +1,371 @@
+use crate::assembler::{SymbolKind, SymbolTable, FIRST_VARIABLE, STACK_POINTER};
+use crate::cfg::Cfg;
+use crate::types::{Instruction, Jump, Line, Location, Macro, Register};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// A check over a preprocessed program, giving the index of each offending instruction along with
+/// what is wrong with it.
+type Check = fn(&[(Instruction, Line)], &SymbolTable) -> Vec<(usize, String)>;
+
+pub struct Rule {
+    pub name: &'static str,
+    pub description: &'static str,
+    check: Check,
+}
+
+pub const RULES: [Rule; 6] = [
+    Rule {
+        name: "writes-a-and-m",
+        description: "M used after a C-instruction like AM=M+1 moved A off a variable",
+        check: writes_a_and_m,
+    },
+    Rule {
+        name: "jump-to-variable",
+        description: "a jump whose target was last loaded from a variable rather than a label",
+        check: jump_to_variable,
+    },
+    Rule {
+        name: "unreachable",
+        description: "code after an unconditional jump which no label leads to",
+        check: unreachable,
+    },
+    Rule {
+        name: "unused-label",
+        description: "a label which nothing jumps to",
+        check: unused_label,
+    },
+    Rule {
+        name: "single-use-variable",
+        description: "a variable which is only used once, which is often a typo",
+        check: single_use_variable,
+    },
+    Rule {
+        name: "call-without-ret",
+        description: "a #call to a label which never reaches a #ret",
+        check: call_without_ret,
+    },
+];
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct Warning {
+    pub rule: String,
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {} [{}]",
+            self.file, self.line, self.message, self.rule
+        )
+    }
+}
+
+/// Whether the preprocessor wrote the line rather than the user.
+fn is_generated(line: &Line) -> bool {
+    line.directive.is_some() || line.number == 0
+}
+
+/// Whether a C-instruction reads or writes M.
+fn uses_m(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::C(dest, computation, _) => {
+            dest.contains(&Register::M) || computation.to_string().contains('M')
+        }
+        _ => false,
+    }
+}
+
+/// Finds `@x` followed by a C-instruction which writes both A and M, such as `AM=M+1`, and then M
+/// being used before A is loaded again.  That M is at the address `x` now holds rather than `x`.
+/// Stepping through a stack or array that way is fine, so pointers are left alone: the registers
+/// below 16, the call stack's pointer and anything dereferenced with `A=M` somewhere else.
+fn writes_a_and_m(program: &[(Instruction, Line)], symbols: &SymbolTable) -> Vec<(usize, String)> {
+    let mut pointers = vec![];
+    for pair in program.windows(2) {
+        if let [(Instruction::A(location), _), (Instruction::C(dest, _, _), _)] = pair {
+            if dest.contains(&Register::A) && !dest.contains(&Register::M) && uses_m(&pair[1].0) {
+                pointers.push(location);
+            }
+        }
+    }
+    let is_pointer = |location: &Location| {
+        let address = match location {
+            Location::Address(x) => Some(*x),
+            Location::Label(name) => symbols.get(name),
+            Location::Expression(_) => None,
+        };
+        pointers.contains(&location)
+            || address.is_some_and(|x| x < FIRST_VARIABLE || x == STACK_POINTER)
+    };
+
+    let mut warnings = vec![];
+    for (i, pair) in program.windows(2).enumerate() {
+        let (location, instruction) = match pair {
+            [(Instruction::A(location), _), (instruction, line)]
+                if !is_generated(line) && !is_pointer(location) =>
+            {
+                (location, instruction)
+            }
+            _ => continue,
+        };
+        match instruction {
+            Instruction::C(dest, _, Jump::None)
+                if dest.contains(&Register::A) && dest.contains(&Register::M) => {}
+            _ => continue,
+        }
+        // the block carries on through C-instructions until one jumps or loads A again
+        for (later, _) in &program[i + 2..] {
+            let (dest, jump) = match later {
+                Instruction::C(dest, _, jump) => (dest, jump),
+                _ => break,
+            };
+            if uses_m(later) {
+                warnings.push((
+                    i + 1,
+                    format!(
+                        "{} moves A off {}, so the {} after it doesn't use {}",
+                        instruction, location, later, location
+                    ),
+                ));
+                break;
+            }
+            if dest.contains(&Register::A) || *jump != Jump::None {
+                break;
+            }
+        }
+    }
+    warnings
+}
+
+fn jump_to_variable(
+    program: &[(Instruction, Line)],
+    symbols: &SymbolTable,
+) -> Vec<(usize, String)> {
+    let mut warnings = vec![];
+    for (i, (instruction, line)) in program.iter().enumerate() {
+        match instruction {
+            Instruction::C(_, _, jump) if *jump != Jump::None && !is_generated(line) => {}
+            _ => continue,
+        }
+        // find whatever last put a value in A, giving up at labels as A could come from anywhere
+        let target = program[..i].iter().rev().find_map(|(x, _)| match x {
+            Instruction::A(location) => Some(Some(location)),
+            Instruction::C(dest, _, _) if dest.contains(&Register::A) => Some(None),
+            Instruction::Label(_) | Instruction::Macro(_) => Some(None),
+            _ => None,
+        });
+        if let Some(Some(Location::Label(name))) = target {
+            match symbols.kind(name) {
+                Some(SymbolKind::Variable) | Some(SymbolKind::Predefined) => warnings.push((
+                    i,
+                    format!("Jumps to {}, which is a variable rather than a label", name),
+                )),
+                _ => {}
+            }
+        }
+    }
+    warnings
+}
+
+fn unreachable(program: &[(Instruction, Line)], _: &SymbolTable) -> Vec<(usize, String)> {
+    let cfg = Cfg::new(program);
+    let predecessors = cfg.predecessors();
+    let mut warnings = vec![];
+    // blocks starting with a label are left to unused-label
+    for (block, count) in cfg.blocks.iter().zip(predecessors).skip(1) {
+        if count > 0 || matches!(program[block.start].0, Instruction::Label(_)) {
+            continue;
+        }
+        if let Some(i) = (block.start..block.end).find(|x| !is_generated(&program[*x].1)) {
+            warnings.push((i, format!("{} can never be reached", program[i].0)));
+        }
+    }
+    warnings
+}
+
+fn unused_label(program: &[(Instruction, Line)], _: &SymbolTable) -> Vec<(usize, String)> {
+    let used = program
+        .iter()
+        .flat_map(|(x, _)| match x {
+            Instruction::A(Location::Label(label)) => vec![label.as_str()],
+            Instruction::A(Location::Expression(expression)) => expression.symbols(),
+            _ => vec![],
+        })
+        .collect::<HashSet<&str>>();
+    program
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (instruction, line))| match instruction {
+            Instruction::Label(label) if !used.contains(label.as_str()) && !is_generated(line) => {
+                Some((i, format!("Nothing jumps to {}", label)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn single_use_variable(
+    program: &[(Instruction, Line)],
+    symbols: &SymbolTable,
+) -> Vec<(usize, String)> {
+    let mut uses: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, (instruction, _)) in program.iter().enumerate() {
+        let names = match instruction {
+            Instruction::A(Location::Label(name)) => vec![name.as_str()],
+            Instruction::A(Location::Expression(expression)) => expression.symbols(),
+            // declaring a variable counts as using it
+            Instruction::Macro(Macro::Var(variable)) => vec![variable.name.as_str()],
+            _ => continue,
+        };
+        for name in names {
+            if symbols.kind(name) == Some(SymbolKind::Variable) {
+                uses.entry(name).or_default().push(i);
+            }
+        }
+    }
+    let mut warnings = uses
+        .into_iter()
+        .filter(|(_, uses)| uses.len() == 1 && !is_generated(&program[uses[0]].1))
+        .map(|(name, uses)| (uses[0], format!("{} is only used once", name)))
+        .collect::<Vec<(usize, String)>>();
+    warnings.sort();
+    warnings
+}
+
+fn call_without_ret(program: &[(Instruction, Line)], _: &SymbolTable) -> Vec<(usize, String)> {
+    let cfg = Cfg::new(program);
+    let mut warnings = vec![];
+    for block in &cfg.blocks {
+        // the block ends with the jump of the call, which points back at the directive
+        let line = &program[block.end - 1].1;
+        let label = match line
+            .directive
+            .as_deref()
+            .map(|x| x.split_whitespace().collect::<Vec<&str>>())
+        {
+            Some(words) => match words[..] {
+                [directive, label] if directive.eq_ignore_ascii_case("#call") => label,
+                _ => continue,
+            },
+            None => continue,
+        };
+        match cfg.labels.get(label) {
+            None => warnings.push((
+                block.end - 1,
+                format!("Calls {}, which is not a label", label),
+            )),
+            Some(entry) if !cfg.function(*entry).iter().any(|x| cfg.blocks[*x].returns) => warnings
+                .push((
+                    block.end - 1,
+                    format!("Calls {}, which never reaches a #ret", label),
+                )),
+            _ => {}
+        }
+    }
+    warnings
+}
+
+/// Runs the named rules over a preprocessed program, giving the warnings in program order.
+pub fn lint(program: &[(Instruction, Line)], rules: &[&str]) -> Vec<Warning> {
+    let instructions = program
+        .iter()
+        .map(|(x, _)| x.clone())
+        .collect::<Vec<Instruction>>();
+    let symbols = SymbolTable::new(&instructions);
+    let mut warnings = vec![];
+    for rule in RULES.iter().filter(|x| rules.contains(&x.name)) {
+        for (i, message) in (rule.check)(program, &symbols) {
+            warnings.push((i, rule.name, message));
+        }
+    }
+    warnings.sort_by_key(|(i, _, _)| *i);
+    warnings
+        .into_iter()
+        .map(|(i, rule, message)| Warning {
+            rule: rule.to_string(),
+            file: program[i].1.file.clone(),
+            line: program[i].1.number,
+            message,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lint::{lint, RULES};
+    use crate::parser::parse_lines;
+    use crate::preprocessor::Preprocessable;
+    use crate::types::Line;
+
+    /// The lines the rule warns about.
+    fn warnings(program: &str, rule: &str) -> Vec<usize> {
+        let program = parse_lines(Line::read("main.asm", program).preprocess());
+        lint(&program, &[rule]).iter().map(|x| x.line).collect()
+    }
+
+    #[test]
+    fn finds_writes_to_a_and_m() {
+        assert_eq!(warnings("@x\nAM=M+1\nM=0\n#ret", "writes-a-and-m"), vec![2]);
+        assert_eq!(
+            warnings("@i\nAM=M+1\nD=D+1\nD=M", "writes-a-and-m"),
+            vec![2]
+        );
+        // popping off a stack, walking a pointer and not using M afterwards are all fine
+        assert_eq!(
+            warnings("@SP\nAM=M-1\nD=M", "writes-a-and-m"),
+            Vec::<usize>::new()
+        );
+        let program = "@p\nA=M\nD=M\n@p\nAM=M+1\nM=D\n@i\nAM=M+1\n@i\nD=M";
+        assert_eq!(warnings(program, "writes-a-and-m"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn finds_jumps_to_variables() {
+        let program = "@x\n0;JMP\n@R13\nD;JGT\n@R13\nA=M\n0;JMP\n(L)\n@L\n0;JMP";
+        assert_eq!(warnings(program, "jump-to-variable"), vec![2, 4]);
+    }
+
+    #[test]
+    fn finds_unreachable_code() {
+        let program = "@L\n0;JMP\nD=0\nD=1\n(L)\nD;JGT\nD=0\nD;JEQ\nD=1\n#ret\nD=0";
+        assert_eq!(warnings(program, "unreachable"), vec![3, 11]);
+    }
+
+    #[test]
+    fn finds_unused_labels() {
+        let program = "(A)\n(B)\n@B\n#call C\n(C)\n#ret";
+        assert_eq!(warnings(program, "unused-label"), vec![1]);
+    }
+
+    #[test]
+    fn finds_single_use_variables() {
+        let program = "#data T 1, 2\n@i\nM=1\n@i\nD=M\n@typo\nM=D";
+        assert_eq!(warnings(program, "single-use-variable"), vec![6]);
+    }
+
+    #[test]
+    fn finds_calls_without_ret() {
+        let program = "#call ok\n#call loops\n#call missing\n#call nested\n\
+                       (ok)\n@x\nD;JEQ\n#ret\n\
+                       (loops)\n@loops\n0;JMP\n\
+                       (nested)\n#call loops\n#ret";
+        assert_eq!(warnings(program, "call-without-ret"), vec![2, 3, 13]);
+    }
+
+    #[test]
+    fn runs_only_enabled_rules() {
+        let program = parse_lines(Line::read("main.asm", "(A)\n@i\nAM=M+1\nD=M").preprocess());
+        let all = RULES.iter().map(|x| x.name).collect::<Vec<&str>>();
+        assert_eq!(lint(&program, &all).len(), 3);
+        assert_eq!(
+            lint(&program, &["unused-label"])[0].to_string(),
+            "main.asm:1: Nothing jumps to A [unused-label]"
+        );
+    }
+}
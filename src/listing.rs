@@ -0,0 +1,162 @@
+use crate::assembler::{encode, SymbolKind, SymbolTable};
+use crate::types::{Instruction, Line};
+
+/// Where the line came from, or `<prologue>` for the setup code the preprocessor adds.
+fn location(line: &Line) -> String {
+    if line.file.is_empty() && line.number == 0 {
+        "<prologue>".to_string()
+    } else {
+        format!("{}:{}", line.file, line.number)
+    }
+}
+
+/// Marks code which was generated by a directive or pulled in by `#include`.
+fn marker(line: &Line) -> String {
+    match &line.directive {
+        Some(directive) => directive
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_lowercase(),
+        None if line.included => "#include".to_string(),
+        None => String::new(),
+    }
+}
+
+/// Lays out every instruction with its ROM address, machine code and the source it came from,
+/// followed by the addresses of the labels and variables.
+pub fn listing(program: &[(Instruction, Line)], symbols: &SymbolTable) -> String {
+    let rows = program
+        .iter()
+        .map(|(instruction, line)| {
+            let source = match &line.directive {
+                Some(directive) => directive.clone(),
+                None => line.text.trim().to_string(),
+            };
+            (
+                encode(instruction, symbols),
+                instruction.to_string(),
+                location(line),
+                marker(line),
+                source,
+            )
+        })
+        .collect::<Vec<(Option<u16>, String, String, String, String)>>();
+    let instruction_width = rows.iter().map(|x| x.1.len()).max().unwrap_or(0).max(11);
+    let location_width = rows.iter().map(|x| x.2.len()).max().unwrap_or(0).max(8);
+    let marker_width = rows.iter().map(|x| x.3.len()).max().unwrap_or(0).max(8);
+
+    let mut output = vec![format!(
+        "{:>5}  {:<16}  {:<4}  {:<iw$}  {:<lw$}  {:<mw$}  {}",
+        "ROM",
+        "Binary",
+        "Hex",
+        "Instruction",
+        "Location",
+        "Expanded",
+        "Source",
+        iw = instruction_width,
+        lw = location_width,
+        mw = marker_width,
+    )];
+    let mut address = 0;
+    for (word, instruction, location, marker, source) in rows {
+        let (binary, hex) = match word {
+            Some(word) => (format!("{:016b}", word), format!("{:04X}", word)),
+            None => (String::new(), String::new()),
+        };
+        output.push(
+            format!(
+                "{:>5}  {:<16}  {:<4}  {:<iw$}  {:<lw$}  {:<mw$}  {}",
+                address,
+                binary,
+                hex,
+                instruction,
+                location,
+                marker,
+                source,
+                iw = instruction_width,
+                lw = location_width,
+                mw = marker_width,
+            )
+            .trim_end()
+            .to_string(),
+        );
+        if word.is_some() {
+            address += 1;
+        }
+    }
+
+    for (title, kind) in &[
+        ("Labels", SymbolKind::Label),
+        ("Variables", SymbolKind::Variable),
+    ] {
+        output.push(String::new());
+        output.push(title.to_string());
+        output.push(format!("{:>7}  {}", "Address", "Name"));
+        for (name, address) in symbols.of_kind(*kind) {
+            output.push(format!("{:>7}  {}", address, name));
+        }
+    }
+    output.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::SymbolTable;
+    use crate::listing::listing;
+    use crate::parser::parse_lines;
+    use crate::preprocessor::Preprocessable;
+    use crate::types::{Instruction, Line};
+
+    #[test]
+    fn lists_program() {
+        let program =
+            parse_lines(Line::read("main.asm", "#call f\n(f)\n@x\nM=1\n#ret").preprocess());
+        let instructions = program
+            .iter()
+            .map(|(x, _)| x.clone())
+            .collect::<Vec<Instruction>>();
+        let listing = listing(&program, &SymbolTable::new(&instructions));
+        let lines = listing.split('\n').collect::<Vec<&str>>();
+        assert_eq!(
+            lines[0],
+            "  ROM  Binary            Hex   Instruction  Location    Expanded  Source"
+        );
+        assert_eq!(
+            lines[1],
+            "    0  0011111111111111  3FFF  @16383       <prologue>            @16383"
+        );
+        assert_eq!(
+            lines[4],
+            "    3  0000000000001100  000C  @RETURN$0    main.asm:1  #call     #call f"
+        );
+        assert_eq!(
+            lines[13],
+            "   12                          (RETURN$0)   main.asm:1  #call     #call f"
+        );
+        assert_eq!(
+            lines[14],
+            "   12                          (f)          main.asm:2            (f)"
+        );
+        assert_eq!(
+            lines[15],
+            "   12  0000000000010000  0010  @x           main.asm:3            @x"
+        );
+        assert_eq!(
+            &lines[21..],
+            &[
+                "",
+                "Labels",
+                "Address  Name",
+                "     12  RETURN$0",
+                "     12  f",
+                "",
+                "Variables",
+                "Address  Name",
+                "     16  x",
+                ""
+            ]
+        );
+    }
+}
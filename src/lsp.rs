@@ -0,0 +1,733 @@
+use crate::assembler::{predefined_symbols, Assemblable, SymbolKind};
+use crate::parser::{parse_line, try_parse_lines};
+use crate::preprocessor::{check_directive, Preprocessable};
+use crate::stdlib::{self, library_path};
+use crate::symbols::{try_symbols, Symbol};
+use crate::types::{Instruction, Line, Location};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+const DIRECTIVES: [&str; 23] = [
+    "#call",
+    "#ret",
+    "#include",
+    "#load",
+    "#data",
+    "#string",
+    "#define",
+    "#rep",
+    "#endrep",
+    "#for",
+    "#endfor",
+    "#if",
+    "#else",
+    "#endif",
+    "#while",
+    "#endwhile",
+    "#break",
+    "#continue",
+    "#var",
+    "#struct",
+    "#stack",
+    "#export",
+    "#extern",
+];
+
+/// A run of characters within one line of a document.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Span {
+    line: usize,
+    start: usize,
+    end: usize,
+}
+
+impl Span {
+    /// Finds `name` in a line of code, starting the search at byte `from`.
+    fn find(line: usize, code: &str, name: &str, from: usize) -> Span {
+        let index = code[from..].find(name).map_or(from, |x| x + from);
+        let start = code[..index].chars().count();
+        Span {
+            line,
+            start,
+            end: start + name.chars().count(),
+        }
+    }
+
+    fn contains(&self, line: usize, character: usize) -> bool {
+        self.line == line && self.start <= character && character <= self.end
+    }
+
+    fn to_json(self) -> Value {
+        json!({
+            "start": { "line": self.line, "character": self.start },
+            "end": { "line": self.line, "character": self.end },
+        })
+    }
+}
+
+/// A use of a symbol, or the place it is defined by a label or a `#data` or `#string` table.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Reference {
+    name: String,
+    span: Span,
+    definition: bool,
+}
+
+/// Everything the server knows about an open document.
+#[derive(Debug, Default)]
+struct Analysis {
+    diagnostics: Vec<(Span, String)>,
+    references: Vec<Reference>,
+    /// The `(LABEL)`s in the document, in order.
+    labels: Vec<Reference>,
+    /// The span of each `#include` path along with the file it points at.
+    includes: Vec<(Span, Option<PathBuf>)>,
+    /// Where the assembler put each symbol, which is only known once the program assembles.
+    addresses: HashMap<String, Symbol>,
+    lines: usize,
+}
+
+/// Looks for an included file next to the document and then relative to the working directory,
+/// which is where the preprocessor looks.
+fn resolve_include(document: Option<&Path>, include: &str) -> Option<PathBuf> {
+    document
+        .and_then(|x| x.parent())
+        .map(|x| x.join(include))
+        .into_iter()
+        .chain(std::iter::once(PathBuf::from(include)))
+        .find(|x| x.is_file())
+}
+
+impl Analysis {
+    fn new(file: Option<&Path>, text: &str) -> Analysis {
+        let name = file.map_or(String::new(), |x| x.to_string_lossy().to_string());
+        let mut analysis = Analysis::default();
+        let lines = Line::read(&name, text);
+        analysis.lines = lines.len();
+        for line in &lines {
+            analysis.analyse_line(file, line);
+        }
+
+        if analysis.diagnostics.is_empty() {
+            // errors such as unclosed blocks, overlapping variables and those in included files
+            // only show up once the whole program goes through the assembler
+            let result = lines
+                .clone()
+                .try_preprocess()
+                .and_then(try_parse_lines)
+                .and_then(|program| {
+                    let symbols = try_symbols(&program)?;
+                    analysis.addresses = symbols.into_iter().map(|x| (x.name.clone(), x)).collect();
+                    program
+                        .into_iter()
+                        .map(|(x, _)| x)
+                        .collect::<Vec<Instruction>>()
+                        .try_assemble()
+                });
+            if let Err(e) = result {
+                let diagnostic = analysis.locate(&name, &lines, e);
+                analysis.diagnostics.push(diagnostic);
+            }
+        }
+        analysis
+    }
+
+    /// Places an error on the line named by its `file:line:` or `Line N:` prefix, or on the first
+    /// line when it doesn't point into this document.
+    fn locate(&self, name: &str, lines: &[Line], e: String) -> (Span, String) {
+        let prefix = if name.is_empty() {
+            "Line ".to_string()
+        } else {
+            format!("{}:", name)
+        };
+        let located = e.strip_prefix(&prefix).and_then(|rest| {
+            let (number, message) = rest.split_at(rest.find(':')?);
+            let line = lines.get(number.parse::<usize>().ok()?.checked_sub(1)?)?;
+            Some((line, message[1..].trim_start().to_string()))
+        });
+        match located {
+            Some((line, message)) => (
+                Span {
+                    line: line.number - 1,
+                    start: line.text.chars().take_while(|x| x.is_whitespace()).count(),
+                    end: line.text.trim_end().chars().count(),
+                },
+                message,
+            ),
+            None => (
+                Span {
+                    line: 0,
+                    start: 0,
+                    end: lines.first().map_or(0, |x| x.text.chars().count()),
+                },
+                e,
+            ),
+        }
+    }
+
+    fn analyse_line(&mut self, file: Option<&Path>, line: &Line) {
+        let number = line.number - 1;
+        let code = match line.text.find("//") {
+            Some(i) => &line.text[..i],
+            None => &line.text,
+        };
+        let trimmed = code.trim();
+        if trimmed.is_empty() {
+            return;
+        }
+        let whole = Span {
+            line: number,
+            start: code.chars().take_while(|x| x.is_whitespace()).count(),
+            end: code.trim_end().chars().count(),
+        };
+        let reference = |name: &str, from: usize, definition: bool| Reference {
+            name: name.to_string(),
+            span: Span::find(number, code, name, from),
+            definition,
+        };
+
+        if trimmed.starts_with('#') {
+            let table = match check_directive(trimmed) {
+                Ok(table) => table,
+                Err(e) => {
+                    self.diagnostics.push((whole, e));
+                    return;
+                }
+            };
+            let mut words = trimmed.split_whitespace();
+            let directive = words.next().unwrap_or("").to_lowercase();
+            let argument = words.last();
+            // skip past the directive so the search doesn't find the argument inside it
+            let from = code.find('#').unwrap_or(0) + directive.len();
+            match (directive.as_ref(), argument, table) {
+                ("#call", Some(label), _) => self.references.push(reference(label, from, false)),
+                ("#include", Some(include), _) => {
+                    let span = Span::find(number, code, include, from);
+                    match library_path(include) {
+                        // the standard library is built in, so there's no file to go to
+                        Some(path) if stdlib::get(path).is_none() => self
+                            .diagnostics
+                            .push((span, format!("No standard library file {}", path))),
+                        Some(_) => {}
+                        None => {
+                            let path = resolve_include(file, include);
+                            if path.is_none() {
+                                self.diagnostics
+                                    .push((span, format!("Could not find file {:?}", include)));
+                            }
+                            self.includes.push((span, path));
+                        }
+                    }
+                }
+                (_, _, Some(table)) => self.references.push(reference(&table, from, true)),
+                _ => {}
+            }
+            return;
+        }
+
+        // code such as `(ROW{i})` in a `#for` loop only makes sense once the loop is unrolled
+        if code.contains('{') {
+            return;
+        }
+        match parse_line(code) {
+            Err(e) => self.diagnostics.push((whole, e)),
+            Ok(Some(Instruction::Label(label))) => {
+                let definition = reference(&label, 0, true);
+                if self.labels.iter().any(|x| x.name == label) {
+                    self.diagnostics.push((
+                        definition.span,
+                        format!("Label {} is defined more than once", label),
+                    ));
+                } else {
+                    self.labels.push(definition.clone());
+                }
+                self.references.push(definition);
+            }
+            Ok(Some(Instruction::A(Location::Label(name)))) => {
+                let from = code.find('@').unwrap_or(0) + 1;
+                self.references.push(reference(&name, from, false));
+            }
+            Ok(Some(Instruction::A(Location::Expression(expression)))) => {
+                let mut from = code.find('@').unwrap_or(0) + 1;
+                for name in expression.symbols() {
+                    self.references.push(reference(name, from, false));
+                    from = code[from..]
+                        .find(name)
+                        .map_or(from, |x| x + from + name.len());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn reference_at(&self, line: usize, character: usize) -> Option<&Reference> {
+        self.references
+            .iter()
+            .find(|x| x.span.contains(line, character))
+    }
+
+    fn definition(&self, name: &str) -> Option<&Reference> {
+        self.references
+            .iter()
+            .find(|x| x.definition && x.name == name)
+    }
+
+    fn is_label(&self, name: &str) -> bool {
+        match self.addresses.get(name) {
+            Some(symbol) => symbol.kind.in_rom(),
+            None => self.labels.iter().any(|x| x.name == name),
+        }
+    }
+
+    /// Describes a symbol for hovers and completions.
+    fn describe(&self, name: &str) -> String {
+        let predefined = predefined_symbols();
+        match (self.addresses.get(name), predefined.get(name)) {
+            (Some(symbol), _) => match symbol.kind {
+                SymbolKind::Predefined => format!("predefined symbol for RAM[{}]", symbol.address),
+                SymbolKind::Label => format!("label at ROM[{}]", symbol.address),
+                SymbolKind::Generated => format!("generated label at ROM[{}]", symbol.address),
+                SymbolKind::Variable => format!("variable at RAM[{}]", symbol.address),
+            },
+            (None, Some(address)) => format!("predefined symbol for RAM[{}]", address),
+            (None, None) if self.is_label(name) => "label".to_string(),
+            (None, None) => "variable".to_string(),
+        }
+    }
+}
+
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let mut bytes = vec![];
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match (byte, tail.get(..2).map(std::str::from_utf8)) {
+            (b'%', Some(Ok(hex))) if u8::from_str_radix(hex, 16).is_ok() => {
+                bytes.push(u8::from_str_radix(hex, 16).unwrap());
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let path = std::env::current_dir()
+        .map(|x| x.join(path))
+        .unwrap_or_else(|_| path.to_path_buf());
+    let mut uri = "file://".to_string();
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                uri.push(byte as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+/// A language server for Hack ASM, kept separate from the transport so it can be driven directly.
+#[derive(Debug, Default)]
+pub struct Server {
+    documents: HashMap<String, (String, Analysis)>,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    fn open(&mut self, uri: &str, text: String) -> Value {
+        let analysis = Analysis::new(uri_to_path(uri).as_deref(), &text);
+        let diagnostics = analysis
+            .diagnostics
+            .iter()
+            .map(|(span, message)| {
+                json!({
+                    "range": span.to_json(),
+                    "severity": 1,
+                    "source": "hack-asm",
+                    "message": message,
+                })
+            })
+            .collect::<Vec<Value>>();
+        self.documents.insert(uri.to_string(), (text, analysis));
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        })
+    }
+
+    fn definition(&self, uri: &str, analysis: &Analysis, line: usize, character: usize) -> Value {
+        if let Some((_, path)) = analysis
+            .includes
+            .iter()
+            .find(|(span, _)| span.contains(line, character))
+        {
+            return match path {
+                Some(path) => json!({
+                    "uri": path_to_uri(path),
+                    "range": Span { line: 0, start: 0, end: 0 }.to_json(),
+                }),
+                None => Value::Null,
+            };
+        }
+        analysis
+            .reference_at(line, character)
+            .and_then(|x| analysis.definition(&x.name))
+            .map_or(
+                Value::Null,
+                |x| json!({ "uri": uri, "range": x.span.to_json() }),
+            )
+    }
+
+    fn completion(&self, text: &str, analysis: &Analysis, line: usize, character: usize) -> Value {
+        let before = text
+            .lines()
+            .nth(line)
+            .unwrap_or("")
+            .chars()
+            .take(character)
+            .collect::<String>();
+        if before.trim_start().starts_with('#') && !before.trim().contains(' ') {
+            return DIRECTIVES
+                .iter()
+                .map(|x| json!({ "label": x, "kind": 14 }))
+                .collect();
+        }
+
+        let mut names = analysis
+            .references
+            .iter()
+            .map(|x| x.name.clone())
+            .chain(predefined_symbols().into_keys())
+            .collect::<Vec<String>>();
+        names.sort();
+        names.dedup();
+        names
+            .into_iter()
+            .map(|name| {
+                let kind = if analysis.is_label(&name) {
+                    3
+                } else if predefined_symbols().contains_key(&name) {
+                    21
+                } else {
+                    6
+                };
+                json!({ "label": name, "kind": kind, "detail": analysis.describe(&name) })
+            })
+            .collect()
+    }
+
+    fn document_symbols(&self, analysis: &Analysis) -> Value {
+        // each label's range runs until the next one
+        let ends = analysis
+            .labels
+            .iter()
+            .skip(1)
+            .map(|x| x.span.line)
+            .chain(std::iter::once(analysis.lines))
+            .collect::<Vec<usize>>();
+        analysis
+            .labels
+            .iter()
+            .zip(ends)
+            .map(|(label, end)| {
+                json!({
+                    "name": label.name,
+                    "detail": analysis.describe(&label.name),
+                    "kind": 12,
+                    "range": {
+                        "start": { "line": label.span.line, "character": 0 },
+                        "end": { "line": end, "character": 0 },
+                    },
+                    "selectionRange": label.span.to_json(),
+                })
+            })
+            .collect()
+    }
+
+    /// Answers a request about an open document.
+    fn request(&self, method: &str, params: &Value) -> Result<Value, String> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let (text, analysis) = match self.documents.get(uri) {
+            Some(document) => document,
+            None => return Err(format!("{} is not open", uri)),
+        };
+        let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+        let character = params["position"]["character"].as_u64().unwrap_or(0) as usize;
+        Ok(match method {
+            "textDocument/definition" => self.definition(uri, analysis, line, character),
+            "textDocument/references" => match analysis.reference_at(line, character) {
+                Some(reference) => {
+                    let declarations = params["context"]["includeDeclaration"]
+                        .as_bool()
+                        .unwrap_or(true);
+                    analysis
+                        .references
+                        .iter()
+                        .filter(|x| x.name == reference.name && (declarations || !x.definition))
+                        .map(|x| json!({ "uri": uri, "range": x.span.to_json() }))
+                        .collect()
+                }
+                None => Value::Null,
+            },
+            "textDocument/hover" => match analysis.reference_at(line, character) {
+                Some(reference) => json!({
+                    "contents": {
+                        "kind": "markdown",
+                        "value": format!("`{}`: {}", reference.name, analysis.describe(&reference.name)),
+                    },
+                    "range": reference.span.to_json(),
+                }),
+                None => Value::Null,
+            },
+            "textDocument/completion" => self.completion(text, analysis, line, character),
+            "textDocument/documentSymbol" => self.document_symbols(analysis),
+            _ => unreachable!(),
+        })
+    }
+
+    /// Handles a message from the client, giving the messages to send back.
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": ["@", "#"] },
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "hack-asm" },
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                let text = document["text"].as_str().unwrap_or("").to_string();
+                return vec![self.open(document["uri"].as_str().unwrap_or(""), text)];
+            }
+            "textDocument/didChange" => {
+                // the server asks for full text sync, so the last change is the whole document
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|x| x.last())
+                    .and_then(|x| x["text"].as_str())
+                    .unwrap_or("")
+                    .to_string();
+                return vec![self.open(params["textDocument"]["uri"].as_str().unwrap_or(""), text)];
+            }
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+                self.documents.remove(uri);
+                return vec![json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] },
+                })];
+            }
+            "textDocument/definition"
+            | "textDocument/references"
+            | "textDocument/hover"
+            | "textDocument/completion"
+            | "textDocument/documentSymbol" => self.request(method, params),
+            _ => Err(format!("Unknown method {}", method)),
+        };
+
+        // notifications don't get a response, even if they weren't understood
+        match (&message["id"], result) {
+            (Value::Null, _) => vec![],
+            (id, Ok(result)) => vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })],
+            (id, Err(e)) => vec![json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": -32601, "message": e },
+            })],
+        }
+    }
+}
+
+/// Reads a message framed with a `Content-Length` header, or gives `None` at the end of input.
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+fn write_message(output: &mut impl Write, message: &Value) {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .and_then(|_| output.flush())
+        .expect("Could not write to output");
+}
+
+/// Runs the server over a pair of streams until the client sends `exit`.
+pub fn serve(mut input: impl BufRead, mut output: impl Write) {
+    let mut server = Server::new();
+    while let Some(message) = read_message(&mut input) {
+        if message["method"] == "exit" {
+            break;
+        }
+        for response in server.handle(&message) {
+            write_message(&mut output, &response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lsp::{serve, Server};
+    use serde_json::{json, Value};
+
+    fn open(server: &mut Server, text: &str) -> Value {
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": "file:///tmp/main.asm", "text": text } },
+        }))[0]["params"]["diagnostics"]
+            .clone()
+    }
+
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Value {
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": {
+                "textDocument": { "uri": "file:///tmp/main.asm" },
+                "position": { "line": line, "character": character },
+            },
+        }))[0]["result"]
+            .clone()
+    }
+
+    #[test]
+    fn reports_diagnostics() {
+        let mut server = Server::new();
+        let diagnostics = open(
+            &mut server,
+            "(A)\n  @70000\nD=M\n(A)\n#load M, 1\n#include missing.asm",
+        );
+        let messages = diagnostics
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| {
+                (
+                    x["range"]["start"]["line"].as_u64().unwrap(),
+                    x["range"]["start"]["character"].as_u64().unwrap(),
+                )
+            })
+            .collect::<Vec<(u64, u64)>>();
+        assert_eq!(messages, vec![(1, 2), (3, 1), (4, 0), (5, 9)]);
+        assert_eq!(open(&mut server, "@x\nM=1"), json!([]));
+    }
+
+    #[test]
+    fn reports_whole_program_errors() {
+        let mut server = Server::new();
+        let diagnostics = open(&mut server, "#if D>0\n@1\n  #endwhile");
+        assert_eq!(
+            diagnostics[0]["range"]["start"],
+            json!({ "line": 2, "character": 2 })
+        );
+        assert_eq!(
+            diagnostics[0]["message"],
+            "#endwhile doesn't close the #if on line 1"
+        );
+        let diagnostics = open(&mut server, "#var a @ 20\n#var b @ 20\n@a");
+        assert_eq!(diagnostics[0]["range"]["start"]["line"], 0);
+        assert_eq!(diagnostics[0]["message"], "b at 20 overlaps a at 20");
+        let diagnostics = open(&mut server, "@a+40000");
+        assert_eq!(diagnostics[0]["message"], "Unknown symbol a");
+    }
+
+    #[test]
+    fn navigates_symbols() {
+        let mut server = Server::new();
+        let text = "(LOOP)\n  @i  // counter\nM=M+1\n#call LOOP\n@LOOP\n0;JMP\n(END)";
+        open(&mut server, text);
+        assert_eq!(
+            request(&mut server, "textDocument/definition", 3, 7)["range"],
+            json!({ "start": { "line": 0, "character": 1 }, "end": { "line": 0, "character": 5 } })
+        );
+        assert_eq!(
+            request(&mut server, "textDocument/references", 4, 2)
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+        assert_eq!(
+            request(&mut server, "textDocument/hover", 1, 3)["contents"]["value"],
+            "`i`: variable at RAM[16]"
+        );
+        assert_eq!(
+            request(&mut server, "textDocument/hover", 4, 1)["contents"]["value"],
+            "`LOOP`: label at ROM[3]"
+        );
+        let symbols = request(&mut server, "textDocument/documentSymbol", 0, 0);
+        assert_eq!(symbols[0]["name"], "LOOP");
+        assert_eq!(symbols[0]["range"]["end"]["line"], 6);
+        assert_eq!(symbols[1]["name"], "END");
+        let completions = request(&mut server, "textDocument/completion", 2, 0);
+        assert!(completions.as_array().unwrap().contains(
+            &json!({ "label": "SCREEN", "kind": 21, "detail": "predefined symbol for RAM[16384]" })
+        ));
+        open(&mut server, "#");
+        assert_eq!(
+            request(&mut server, "textDocument/completion", 0, 1)
+                .as_array()
+                .unwrap()
+                .len(),
+            23
+        );
+    }
+
+    #[test]
+    fn frames_messages() {
+        let message = |body: Value| {
+            let body = body.to_string();
+            format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+        };
+        let input = [
+            message(json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} })),
+            message(json!({ "jsonrpc": "2.0", "id": 2, "method": "shutdown" })),
+            message(json!({ "jsonrpc": "2.0", "method": "exit" })),
+            message(json!({ "jsonrpc": "2.0", "id": 3, "method": "shutdown" })),
+        ]
+        .join("");
+        let mut output = vec![];
+        serve(input.as_bytes(), &mut output);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("Content-Length: "));
+        assert!(output.contains("\"documentSymbolProvider\":true"));
+        assert!(output.contains("\"id\":2"));
+        assert!(!output.contains("\"id\":3"));
+    }
+}
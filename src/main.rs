@@ -1,17 +1,29 @@
-use clap::{crate_authors, crate_description, crate_name, crate_version, App, Arg};
-
-use std::io::Read;
-use std::{env, fs, io};
-mod assembler;
-mod parser;
-mod preprocessor;
-mod types;
+use clap::{
+    crate_authors, crate_description, crate_name, crate_version, App, AppSettings, Arg, ArgMatches,
+    SubCommand,
+};
+
+use hack_asm::{
+    assembler, cfg, debugger, emulator, formats, lint, listing, lsp, memory, object, optimizer, os,
+    parser, preprocessor, profiler, snapshot, symbols, trace, types,
+};
+
+use assembler::{Assemblable, SymbolKind, SymbolTable};
+use debugger::Debugger;
+use emulator::Emulator;
+use optimizer::Optimizable;
+use preprocessor::Preprocessable;
+use snapshot::Snapshot;
+use std::io::{Read, Write};
+use std::{fmt, fs, io, process};
+use symbols::{Names, Symbol};
+use types::{Instruction, Line};
 pub fn read_string_from_stdin() -> String {
     let mut response = String::new();
     io::stdin()
         .read_to_string(&mut response)
         .expect("Unable to read from stdin");
-    return response;
+    response
 }
 
 fn main() {
@@ -19,38 +31,725 @@ fn main() {
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
-        // .arg(Arg::with_name("Assemble")
-        //     .short("a")
-        //     .help("Assemble Hack ASM into machine code"))
+        .arg(
+            Arg::with_name("Assemble")
+                .short("a")
+                .help("Assemble Hack ASM into machine code"),
+        )
         .arg(
             Arg::with_name("Preprocess")
                 .short("p")
                 .help("Preprocess Hack ASM code"),
         )
+        .arg(
+            Arg::with_name("Optimize")
+                .short("O")
+                .help("Optimize Hack ASM code, after preprocessing it if -p is given"),
+        )
+        .arg(
+            Arg::with_name("Listing")
+                .long("listing")
+                .value_name("FILE")
+                .help("Writes a listing of the ROM layout and symbol table to a file"),
+        )
+        .arg(
+            Arg::with_name("Symbols")
+                .long("symbols")
+                .value_name("FILE")
+                .help("Writes the symbol table to a file, as JSON or in the .sym format"),
+        )
+        .arg(
+            Arg::with_name("MemoryMap")
+                .long("memory-map")
+                .value_name("FILE")
+                .help(
+                    "Writes a map of what lives where in RAM to a file, checking nothing overlaps",
+                ),
+        )
+        .arg(format_arg())
+        .arg(
+            Arg::with_name("Object")
+                .short("c")
+                .help("Assemble into a relocatable object for the link subcommand"),
+        )
         .arg(
             Arg::with_name("FILE")
                 .help("Sets the input ASM file to use")
                 .required(true)
                 .index(1),
         )
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("link")
+                .about("Links objects assembled with -c into a program")
+                .arg(
+                    Arg::with_name("OBJECTS")
+                        .help("The objects to link, starting with the one with the entry point")
+                        .required(true)
+                        .multiple(true),
+                )
+                .arg(format_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Runs a program on the emulator and shows the RAM it changed")
+                .arg(program_arg())
+                .arg(symbols_arg())
+                .arg(os_arg())
+                .arg(stack_guard_arg())
+                .arg(keyboard_arg())
+                .arg(restore_arg())
+                .arg(cycles_arg())
+                .arg(
+                    Arg::with_name("Save")
+                        .long("save")
+                        .value_name("SNAPSHOT")
+                        .help("Writes a snapshot of the registers and RAM to a file afterwards"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Steps through a program on the emulator")
+                .arg(program_arg())
+                .arg(symbols_arg())
+                .arg(os_arg())
+                .arg(stack_guard_arg())
+                .arg(keyboard_arg())
+                .arg(restore_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("profile")
+                .about("Runs a program on the emulator and shows where the cycles went")
+                .arg(program_arg())
+                .arg(symbols_arg())
+                .arg(os_arg())
+                .arg(stack_guard_arg())
+                .arg(keyboard_arg())
+                .arg(cycles_arg())
+                .arg(
+                    Arg::with_name("Addresses")
+                        .long("addresses")
+                        .help("Also shows how many times each ROM address was executed"),
+                )
+                .arg(
+                    Arg::with_name("Folded")
+                        .long("folded")
+                        .value_name("FILE")
+                        .help("Writes the call stacks in the folded format flame graph tools read"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cfg")
+                .about("Prints the control-flow graph of a program in Graphviz's DOT language")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("Sets the input ASM file to use")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("lint")
+                .about("Checks a program for common mistakes")
+                .after_help(lint_rules().as_str())
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("Sets the input ASM file to use")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("Enable")
+                        .long("enable")
+                        .value_name("RULE")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Only runs the given rules"),
+                )
+                .arg(
+                    Arg::with_name("Disable")
+                        .long("disable")
+                        .value_name("RULE")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Skips the given rules"),
+                )
+                .arg(
+                    Arg::with_name("Json")
+                        .long("json")
+                        .help("Prints the warnings as JSON"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("lsp").about("Runs a language server over stdin and stdout"),
+        )
+        .subcommand(
+            SubCommand::with_name("trace")
+                .about("Records, replays and compares traces of the emulator")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("record")
+                        .about("Runs a program and writes a trace of PC, A, D and RAM writes")
+                        .arg(program_arg())
+                        .arg(symbols_arg())
+                        .arg(os_arg())
+                        .arg(stack_guard_arg())
+                        .arg(keyboard_arg())
+                        .arg(cycles_arg())
+                        .arg(
+                            Arg::with_name("Output")
+                                .short("o")
+                                .long("output")
+                                .value_name("TRACE")
+                                .required(true)
+                                .help("The file to write the trace to"),
+                        )
+                        .arg(
+                            Arg::with_name("Optimize")
+                                .short("O")
+                                .help("Optimizes an ASM program before running it"),
+                        )
+                        .arg(
+                            Arg::with_name("Range")
+                                .long("range")
+                                .value_name("LO-HI")
+                                .multiple(true)
+                                .number_of_values(1)
+                                .help("Only records instructions in this range of ROM"),
+                        )
+                        .arg(
+                            Arg::with_name("Label")
+                                .long("label")
+                                .value_name("LABEL")
+                                .multiple(true)
+                                .number_of_values(1)
+                                .help("Only records instructions from this label up to the next"),
+                        )
+                        .arg(
+                            Arg::with_name("Writes")
+                                .long("writes")
+                                .value_name("LO-HI")
+                                .help("Only records instructions which write to this range of RAM"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("replay")
+                        .about("Prints a trace one cycle per line")
+                        .arg(
+                            Arg::with_name("TRACE")
+                                .help("A trace written by trace record")
+                                .required(true)
+                                .index(1),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("diff")
+                        .about("Shows the first cycle where two traces differ")
+                        .arg(
+                            Arg::with_name("A")
+                                .help("A trace written by trace record")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("B")
+                                .help("The trace to compare it with")
+                                .required(true)
+                                .index(2),
+                        )
+                        .arg(
+                            Arg::with_name("Writes")
+                                .long("writes")
+                                .help("Only compares the RAM writes, ignoring the path taken"),
+                        ),
+                ),
+        )
         .get_matches();
 
-    let _asm = match fs::read_to_string(matches.value_of("FILE").unwrap()) {
+    match matches.subcommand() {
+        ("run", Some(matches)) => return run(matches),
+        ("debug", Some(matches)) => {
+            let (emulator, symbols, _) = load_program(matches);
+            let stdin = io::stdin();
+            Debugger::new(emulator, Names::new(&symbols)).repl(stdin.lock(), io::stdout());
+            return;
+        }
+        ("profile", Some(matches)) => return run_profile(matches),
+        ("cfg", Some(matches)) => {
+            let program = read_program(matches.value_of("FILE").unwrap());
+            print!("{}", cfg::Cfg::new(&program).to_dot(&program));
+            return;
+        }
+        ("lint", Some(matches)) => return run_lint(matches),
+        ("lsp", Some(_)) => {
+            let stdin = io::stdin();
+            lsp::serve(stdin.lock(), io::stdout());
+            return;
+        }
+        ("trace", Some(matches)) => return run_trace(matches),
+        ("link", Some(matches)) => return run_link(matches),
+        _ => {}
+    }
+
+    let file = matches.value_of("FILE").unwrap();
+    let mut lines = match fs::read_to_string(file) {
+        Ok(f) => Line::read(file, &f),
+        Err(e) => fail(format!("Could not read file {:?}: {:?}", file, e)),
+    };
+
+    if matches.is_present("Preprocess") {
+        lines = lines.try_preprocess().unwrap_or_else(|e| fail(e));
+    }
+    if matches.is_present("Object") {
+        let object =
+            object::Object::new(lines, matches.is_present("Optimize")).unwrap_or_else(|e| fail(e));
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&object).expect("Could not serialize object")
+        );
+        return;
+    }
+    let output: Vec<String> = if matches.is_present("Assemble")
+        || matches.is_present("Optimize")
+        || matches.is_present("Listing")
+        || matches.is_present("Symbols")
+        || matches.is_present("MemoryMap")
+        || matches.is_present("Format")
+    {
+        let mut program = parser::try_parse_lines(lines).unwrap_or_else(|e| fail(e));
+        if matches.is_present("Optimize") {
+            program = program.optimize();
+        }
+        let instructions = program
+            .iter()
+            .map(|(x, _)| x.clone())
+            .collect::<Vec<Instruction>>();
+        let mut words = vec![];
+        if let Some(path) = matches.value_of("Listing") {
+            let symbols = SymbolTable::try_new(&instructions).unwrap_or_else(|e| fail(e));
+            let listing = listing::listing(&program, &symbols);
+            fs::write(path, listing)
+                .unwrap_or_else(|e| fail(format!("Could not write listing {:?}: {:?}", path, e)));
+        }
+        if let Some(path) = matches.value_of("Symbols") {
+            symbols::try_symbols(&program)
+                .and_then(|x| symbols::save(path, &x))
+                .unwrap_or_else(|e| fail(e));
+        }
+        if let Some(path) = matches.value_of("MemoryMap") {
+            let regions = memory::memory_map(&program).unwrap_or_else(|e| fail(e));
+            fs::write(path, memory::report(&regions)).unwrap_or_else(|e| {
+                fail(format!("Could not write memory map {:?}: {:?}", path, e))
+            });
+        }
+        if matches.is_present("Format") || matches.is_present("Assemble") {
+            words = instructions
+                .clone()
+                .try_assemble()
+                .unwrap_or_else(|e| fail(e));
+        }
+        if matches.is_present("Format") && write_formats(&matches, &words) {
+            return;
+        }
+        if matches.is_present("Assemble") {
+            words.iter().map(|x| format!("{:016b}", x)).collect()
+        } else if matches.is_present("Optimize") {
+            instructions.iter().map(|x| x.to_string()).collect()
+        } else {
+            program.into_iter().map(|(_, line)| line.text).collect()
+        }
+    } else {
+        lines.into_iter().map(|x| x.text).collect()
+    };
+    println!("{}", output.join("\n"));
+}
+
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("Format")
+        .long("format")
+        .value_name("FORMAT[:FILE]")
+        .multiple(true)
+        .number_of_values(1)
+        .validator(|x| formats::parse_output(&x).map(|_| ()))
+        .help(
+            "Writes the machine code as hack, raw-be, raw-le, ihex, logisim, memb, memh, c or \
+             rust, to a file if one is given and stdout otherwise",
+        )
+}
+
+/// Writes the machine code in each format given to `--format`, giving whether any of them went to
+/// stdout.
+fn write_formats(matches: &ArgMatches, words: &[u16]) -> bool {
+    let mut printed = false;
+    for output in matches.values_of("Format").into_iter().flatten() {
+        let (format, path) = formats::parse_output(output).unwrap();
+        match path {
+            Some(path) => fs::write(path, format.write(words))
+                .unwrap_or_else(|e| fail(format!("Could not write {:?}: {:?}", path, e))),
+            None => {
+                io::stdout()
+                    .write_all(&format.write(words))
+                    .expect("Unable to write to stdout");
+                printed = true;
+            }
+        }
+    }
+    printed
+}
+
+fn run_link(matches: &ArgMatches) {
+    let objects = matches
+        .values_of("OBJECTS")
+        .unwrap()
+        .map(|path| {
+            let text = fs::read_to_string(path)
+                .unwrap_or_else(|e| fail(format!("Could not read object {:?}: {:?}", path, e)));
+            let object = serde_json::from_str(&text)
+                .unwrap_or_else(|e| fail(format!("Could not load object {:?}: {}", path, e)));
+            (path.to_string(), object)
+        })
+        .collect::<Vec<(String, object::Object)>>();
+    let words = object::link(&objects).unwrap_or_else(|e| fail(e));
+    if !write_formats(matches, &words) {
+        io::stdout()
+            .write_all(&formats::Format::Hack.write(&words))
+            .expect("Unable to write to stdout");
+    }
+}
+
+fn program_arg() -> Arg<'static, 'static> {
+    Arg::with_name("FILE")
+        .help("A .hack file, or an ASM file to preprocess and assemble")
+        .required(true)
+        .index(1)
+}
+
+fn cycles_arg() -> Arg<'static, 'static> {
+    Arg::with_name("Cycles")
+        .long("cycles")
+        .value_name("N")
+        .default_value("1000000")
+        .help("Gives up after this many instructions")
+}
+
+fn cycles(matches: &ArgMatches) -> u64 {
+    matches
+        .value_of("Cycles")
+        .unwrap()
+        .parse::<u64>()
+        .expect("--cycles must be a number")
+}
+
+fn symbols_arg() -> Arg<'static, 'static> {
+    Arg::with_name("Symbols")
+        .long("symbols")
+        .value_name("FILE")
+        .help("Reads symbol names from a file written by --symbols")
+}
+
+/// Loads a program into the emulator, giving its symbols and, for ASM files, the program it was
+/// assembled from.  ASM files are assembled first, which also gives their symbols without needing
+/// a symbol file.  With `--os`, calls to the Jack OS are run natively, and with `--stack-guard` the
+/// program is stopped when its call stack runs into its variables.  With `--keyboard`, keys are
+/// pressed from a script as it runs, and with `--restore` it carries on from a snapshot.
+fn load_program(matches: &ArgMatches) -> (Emulator, Vec<Symbol>, Vec<(Instruction, Line)>) {
+    let file = matches.value_of("FILE").unwrap();
+    let mut os = None;
+    let (rom, mut symbols, program) = if file.ends_with(".hack") {
+        let text = match fs::read_to_string(file) {
+            Ok(f) => f,
+            Err(e) => fail(format!("Could not read file {:?}: {:?}", file, e)),
+        };
+        let rom = emulator::read_rom(&text)
+            .unwrap_or_else(|e| fail(format!("Could not read ROM {:?}: {}", file, e)));
+        (rom, vec![], vec![])
+    } else {
+        let mut program = read_program(file);
+        if matches.is_present("Optimize") {
+            program = program.optimize();
+        }
+        if matches.is_present("Os") {
+            os = Some(os::link(&mut program));
+        }
+        let rom = program
+            .iter()
+            .map(|(x, _)| x.clone())
+            .collect::<Vec<Instruction>>()
+            .try_assemble()
+            .unwrap_or_else(|e| fail(e));
+        let symbols = symbols::try_symbols(&program).unwrap_or_else(|e| fail(e));
+        (rom, symbols, program)
+    };
+    if let Some(path) = matches.value_of("Symbols") {
+        symbols = symbols::load(path).unwrap_or_else(|e| fail(e));
+    }
+    // a .hack file can only call the OS functions its symbol file has labels for
+    if matches.is_present("Os") && os.is_none() {
+        let mut linked = os::Os::default();
+        for symbol in symbols.iter().filter(|x| x.kind.in_rom()) {
+            linked.define(&symbol.name, symbol.address);
+        }
+        os = Some(linked);
+    }
+    let mut emulator = Emulator::new(rom);
+    emulator.os = os;
+    if matches.is_present("StackGuard") {
+        // a symbol file doesn't say how big arrays are, so it's the ASM if there is any
+        let regions = if program.is_empty() {
+            memory::from_symbols(&symbols)
+        } else {
+            memory::memory_map(&program)
+        };
+        let regions = regions.unwrap_or_else(|e| fail(e));
+        emulator.stack_limit = Some(memory::stack_limit(&regions));
+    }
+    if let Some(path) = matches.value_of("Keyboard") {
+        let text = fs::read_to_string(path).unwrap_or_else(|e| {
+            fail(format!(
+                "Could not read keyboard script {:?}: {:?}",
+                path, e
+            ))
+        });
+        emulator.keyboard = emulator::read_keyboard(&text)
+            .unwrap_or_else(|e| fail(format!("Could not read keyboard script {:?}: {}", path, e)));
+    }
+    if let Some(path) = matches.value_of("Restore") {
+        Snapshot::load(path)
+            .and_then(|x| emulator.restore(&x))
+            .unwrap_or_else(|e| fail(e));
+    }
+    (emulator, symbols, program)
+}
+
+fn restore_arg() -> Arg<'static, 'static> {
+    Arg::with_name("Restore")
+        .long("restore")
+        .value_name("SNAPSHOT")
+        .help("Carries on from a snapshot of the same program written by --save")
+}
+
+fn keyboard_arg() -> Arg<'static, 'static> {
+    Arg::with_name("Keyboard")
+        .long("keyboard")
+        .value_name("SCRIPT")
+        .help("Presses keys from a script of CYCLE KEY lines as the program runs")
+}
+
+fn stack_guard_arg() -> Arg<'static, 'static> {
+    Arg::with_name("StackGuard")
+        .long("stack-guard")
+        .help("Stops the program when the call stack grows into its variables")
+}
+
+fn os_arg() -> Arg<'static, 'static> {
+    Arg::with_name("Os")
+        .long("os")
+        .help("Runs calls to Jack OS functions such as Math.multiply natively")
+}
+
+fn run(matches: &ArgMatches) {
+    let (mut emulator, symbols, _) = load_program(matches);
+    let names = Names::new(&symbols);
+    if emulator.run(cycles(matches)) {
+        println!("Halted after {} cycles", emulator.cycles);
+    } else {
+        println!("Still running after {} cycles", emulator.cycles);
+    }
+    println!(
+        "PC={} A={} D={}",
+        emulator.pc, emulator.a, emulator.d as i16
+    );
+    if let Some(path) = matches.value_of("Save") {
+        Snapshot::new(&emulator)
+            .and_then(|x| x.save(path))
+            .unwrap_or_else(|e| fail(e));
+        println!("Saved a snapshot to {}", path);
+    }
+    for (address, value) in emulator.ram.iter().enumerate() {
+        if *value != 0 {
+            let name = names.ram(address as u16).unwrap_or("");
+            println!("{:>5}  {:<16}  {}", address, name, *value as i16);
+        }
+    }
+    if let Some(os) = &emulator.os {
+        println!("Output:\n{}", os.output);
+        if let Some(error) = &os.error {
+            println!("Stopped by {}", error);
+            std::process::exit(1);
+        }
+    }
+    if let Some(trap) = &emulator.trap {
+        println!("Stopped by {}", trap);
+        std::process::exit(1);
+    }
+}
+
+fn lint_rules() -> String {
+    let mut help = "RULES:\n".to_string();
+    for rule in &lint::RULES {
+        help.push_str(&format!("    {:<22}{}\n", rule.name, rule.description));
+    }
+    help
+}
+
+fn run_lint(matches: &ArgMatches) {
+    let names = lint::RULES.iter().map(|x| x.name).collect::<Vec<&str>>();
+    let enabled = matches
+        .values_of("Enable")
+        .map_or(names.clone(), |x| x.collect());
+    let disabled = matches.values_of("Disable").map_or(vec![], |x| x.collect());
+    for rule in enabled.iter().chain(disabled.iter()) {
+        if !names.contains(rule) {
+            fail(format!(
+                "Unknown lint rule {}, expected one of {}",
+                rule,
+                names.join(", ")
+            ));
+        }
+    }
+    let rules = enabled
+        .into_iter()
+        .filter(|x| !disabled.contains(x))
+        .collect::<Vec<&str>>();
+
+    let program = read_program(matches.value_of("FILE").unwrap());
+    let warnings = lint::lint(&program, &rules);
+    if matches.is_present("Json") {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&warnings).expect("Could not serialize warnings")
+        );
+    } else {
+        for warning in &warnings {
+            println!("{}", warning);
+        }
+    }
+    if !warnings.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Reads, preprocesses and parses an ASM file.
+fn read_program(file: &str) -> Vec<(Instruction, Line)> {
+    let text = match fs::read_to_string(file) {
         Ok(f) => f,
-        Err(e) => panic!(
-            "Could not read file {:?}: {:?}",
-            matches.value_of("FILE").unwrap(),
-            e
-        ),
+        Err(e) => fail(format!("Could not read file {:?}: {:?}", file, e)),
     };
+    Line::read(file, &text)
+        .try_preprocess()
+        .and_then(parser::try_parse_lines)
+        .unwrap_or_else(|e| fail(e))
+}
+
+/// Prints an error in the input, such as a typo in a program, and exits.
+fn fail<T: fmt::Display>(message: T) -> ! {
+    eprintln!("{}", message);
+    process::exit(1)
+}
+
+fn run_profile(matches: &ArgMatches) {
+    let (mut emulator, symbols, program) = load_program(matches);
+    let labels = symbols
+        .iter()
+        .filter(|x| x.kind == SymbolKind::Label)
+        .map(|x| (x.address, x.name.clone()))
+        .collect::<Vec<(u16, String)>>();
+    let profile = profiler::profile(
+        &mut emulator,
+        &profiler::CallSites::new(&program),
+        cycles(matches),
+    );
+    print!("{}", profile.report(&labels));
+    if matches.is_present("Addresses") {
+        let names = Names::new(&symbols);
+        println!();
+        println!("{:>7}  {:>12}  Instruction", "Address", "Count");
+        for (address, count) in profile.counts.iter().enumerate() {
+            if *count > 0 {
+                let instruction = assembler::decode(emulator.rom()[address])
+                    .map_or("?".to_string(), |x| x.to_string());
+                match names.rom(address as u16) {
+                    Some(name) => println!(
+                        "{:>7}  {:>12}  {:<16}  // {}",
+                        address, count, instruction, name
+                    ),
+                    None => println!("{:>7}  {:>12}  {}", address, count, instruction),
+                }
+            }
+        }
+    }
+    if let Some(path) = matches.value_of("Folded") {
+        fs::write(path, profile.folded())
+            .unwrap_or_else(|e| fail(format!("Could not write folded stacks {:?}: {:?}", path, e)));
+    }
+}
 
-    // assembler::assemble(asm);
+fn read_trace(file: &str) -> trace::Trace {
+    let input = fs::File::open(file)
+        .unwrap_or_else(|e| fail(format!("Could not read trace {:?}: {:?}", file, e)));
+    trace::Trace::read(io::BufReader::new(input))
+        .unwrap_or_else(|e| fail(format!("Could not read trace {:?}: {}", file, e)))
+}
 
-    // if matches.is_present("Preprocess") {
-    //     asm = asm.preprocess();
-    // }
-    // if matches.is_present("Assemble") {
-    //     asm = asm.assemble();
-    // }
-    // println!("{}", asm.join("\n"));
+fn run_trace(matches: &ArgMatches) {
+    match matches.subcommand() {
+        ("record", Some(matches)) => {
+            let (mut emulator, symbols, program) = load_program(matches);
+            let rom = emulator.rom().to_vec();
+            let mut filter = trace::Filter::default();
+            for range in matches.values_of("Range").into_iter().flatten() {
+                filter
+                    .rom
+                    .push(trace::parse_range(range).unwrap_or_else(|e| fail(e)));
+            }
+            for label in matches.values_of("Label").into_iter().flatten() {
+                filter.rom.push(
+                    trace::label_range(&symbols, label, rom.len()).unwrap_or_else(|e| fail(e)),
+                );
+            }
+            if let Some(range) = matches.value_of("Writes") {
+                filter.ram = Some(trace::parse_range(range).unwrap_or_else(|e| fail(e)));
+            }
+            let path = matches.value_of("Output").unwrap();
+            let file = fs::File::create(path)
+                .unwrap_or_else(|e| fail(format!("Could not write trace {:?}: {:?}", path, e)));
+            let mut output = io::BufWriter::new(file);
+            let count = trace::record(
+                &mut emulator,
+                &trace::sources(&rom, &program),
+                &filter,
+                cycles(matches),
+                &mut output,
+            )
+            .and_then(|count| io::Write::flush(&mut output).map(|_| count))
+            .unwrap_or_else(|e| fail(format!("Could not write trace {:?}: {:?}", path, e)));
+            let status = if emulator.halted() {
+                "halted"
+            } else {
+                "still running"
+            };
+            println!(
+                "Recorded {} of {} cycles, {}",
+                count, emulator.cycles, status
+            );
+        }
+        ("replay", Some(matches)) => {
+            let trace = read_trace(matches.value_of("TRACE").unwrap());
+            for record in &trace.records {
+                println!("{}", trace.describe(record));
+            }
+        }
+        ("diff", Some(matches)) => {
+            let a = read_trace(matches.value_of("A").unwrap());
+            let b = read_trace(matches.value_of("B").unwrap());
+            match trace::diff(&a, &b, matches.is_present("Writes")) {
+                Some(difference) => {
+                    println!("{}", difference);
+                    std::process::exit(1);
+                }
+                None => println!("The traces are the same"),
+            }
+        }
+        _ => unreachable!(),
+    }
 }
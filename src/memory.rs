@@ -0,0 +1,258 @@
+use crate::assembler::{describe, SymbolKind, SymbolTable, FIRST_VARIABLE, STACK_POINTER};
+use crate::preprocessor::{is_directive, CALL_STACK};
+use crate::symbols::Symbol;
+use crate::types::{Instruction, Line, Location, Macro};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum RegionKind {
+    Registers,
+    Variable,
+    /// A table written into RAM by `#data` or `#string`.
+    Table,
+    /// The call stack, or just its pointer if no depth is reserved with `#stack`.
+    Stack,
+    Screen,
+    Keyboard,
+}
+
+impl fmt::Display for RegionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            RegionKind::Registers => "registers",
+            RegionKind::Variable => "variable",
+            RegionKind::Table => "table",
+            RegionKind::Stack => "stack",
+            RegionKind::Screen => "screen",
+            RegionKind::Keyboard => "keyboard",
+        };
+        f.pad(name)
+    }
+}
+
+/// A run of RAM set aside for one thing.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub name: String,
+    pub start: u16,
+    pub size: u16,
+}
+
+impl Region {
+    fn new(kind: RegionKind, name: &str, start: u16, size: u16) -> Region {
+        Region {
+            kind,
+            name: name.to_string(),
+            start,
+            size,
+        }
+    }
+
+    /// The address just past the region.
+    pub fn end(&self) -> u32 {
+        self.start as u32 + self.size as u32
+    }
+}
+
+/// The regions every program has: the registers, the screen and the keyboard.
+fn fixed_regions() -> Vec<Region> {
+    vec![
+        Region::new(RegionKind::Registers, "R0-R15", 0, FIRST_VARIABLE),
+        Region::new(RegionKind::Screen, "SCREEN", 16384, 8192),
+        Region::new(RegionKind::Keyboard, "KBD", 24576, 1),
+    ]
+}
+
+/// Sorts regions by address, giving an error if any of them overlap.
+fn check(mut regions: Vec<Region>) -> Result<Vec<Region>, String> {
+    regions.sort_by_key(|x| (x.start, x.size));
+    let mut last: Option<&Region> = None;
+    for region in &regions {
+        match last {
+            Some(other) if (region.start as u32) < other.end() => {
+                return Err(format!(
+                    "{} overlaps {}",
+                    describe(&region.name, region.start, region.size),
+                    describe(&other.name, other.start, other.size)
+                ))
+            }
+            Some(other) if other.end() >= region.end() => {}
+            _ => last = Some(region),
+        }
+    }
+    Ok(regions)
+}
+
+/// Lays out where everything in a program lives in RAM, giving an error if any of it overlaps.
+/// Without `#stack` the call stack's depth isn't known, so only its pointer is in the map when
+/// the program uses it.
+pub fn memory_map(program: &[(Instruction, Line)]) -> Result<Vec<Region>, String> {
+    let instructions = program
+        .iter()
+        .map(|(x, _)| x.clone())
+        .collect::<Vec<Instruction>>();
+    let symbols = SymbolTable::try_new(&instructions)?;
+    let tables = program
+        .iter()
+        .filter_map(|(instruction, line)| match (instruction, &line.directive) {
+            (Instruction::Macro(Macro::Var(variable)), Some(directive))
+                if is_directive(directive, "#data") || is_directive(directive, "#string") =>
+            {
+                Some(variable.name.as_str())
+            }
+            _ => None,
+        })
+        .collect::<Vec<&str>>();
+
+    let mut regions = fixed_regions();
+    for (name, address) in symbols.of_kind(SymbolKind::Variable) {
+        let kind = match name {
+            CALL_STACK => RegionKind::Stack,
+            x if tables.contains(&x) => RegionKind::Table,
+            _ => RegionKind::Variable,
+        };
+        regions.push(Region::new(kind, name, address, symbols.size(name)));
+    }
+    let uses_stack = instructions.contains(&Instruction::A(Location::Address(STACK_POINTER)));
+    if uses_stack && symbols.get(CALL_STACK).is_none() {
+        regions.push(Region::new(
+            RegionKind::Stack,
+            "stack pointer",
+            STACK_POINTER,
+            1,
+        ));
+    }
+    check(regions)
+}
+
+/// Lays out RAM from a symbol file, which only records where each variable starts, so each one is
+/// taken to be a word long.
+pub fn from_symbols(symbols: &[Symbol]) -> Result<Vec<Region>, String> {
+    let mut regions = fixed_regions();
+    for symbol in symbols.iter().filter(|x| x.kind == SymbolKind::Variable) {
+        let kind = match symbol.name.as_str() {
+            CALL_STACK => RegionKind::Stack,
+            _ => RegionKind::Variable,
+        };
+        regions.push(Region::new(kind, &symbol.name, symbol.address, 1));
+    }
+    check(regions)
+}
+
+/// The lowest address the stack pointer can reach before the next push would overwrite a variable
+/// or data table.
+pub fn stack_limit(regions: &[Region]) -> u16 {
+    regions
+        .iter()
+        .filter(|x| matches!(x.kind, RegionKind::Variable | RegionKind::Table))
+        .filter(|x| x.start < STACK_POINTER)
+        .map(|x| x.end() as u16)
+        .max()
+        .unwrap_or(FIRST_VARIABLE)
+}
+
+/// Prints a memory map one region per line, along with the free space between them.
+pub fn report(regions: &[Region]) -> String {
+    let mut output = vec![format!(
+        "{:>5}  {:>5}  {:>5}  {:<9}  Name",
+        "Start", "End", "Size", "Kind"
+    )];
+    let row = |start: u32, end: u32, kind: &str, name: &str| {
+        format!(
+            "{:>5}  {:>5}  {:>5}  {:<9}  {}",
+            start,
+            end - 1,
+            end - start,
+            kind,
+            name
+        )
+        .trim_end()
+        .to_string()
+    };
+    let mut free = 0;
+    for region in regions {
+        if region.start as u32 > free {
+            output.push(row(free, region.start as u32, "free", ""));
+        }
+        output.push(row(
+            region.start as u32,
+            region.end(),
+            &region.kind.to_string(),
+            &region.name,
+        ));
+        free = free.max(region.end());
+    }
+    output.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::{memory_map, report, stack_limit, RegionKind};
+    use crate::parser::parse_lines;
+    use crate::preprocessor::Preprocessable;
+    use crate::types::Line;
+
+    fn map(text: &str) -> Result<Vec<(RegionKind, String, u16, u16)>, String> {
+        let program = parse_lines(Line::read("main.asm", text).try_preprocess()?);
+        Ok(memory_map(&program)?
+            .into_iter()
+            .map(|x| (x.kind, x.name, x.start, x.size))
+            .collect())
+    }
+
+    #[test]
+    fn maps_memory() {
+        let regions =
+            map("#string S \"hi\"\n#stack 15\n#var buf[4]\n@x\nM=0\n#call f\n(f)\n#ret").unwrap();
+        assert_eq!(
+            regions,
+            vec![
+                (RegionKind::Registers, "R0-R15".into(), 0, 16),
+                (RegionKind::Table, "S".into(), 16, 3),
+                (RegionKind::Variable, "buf".into(), 19, 4),
+                (RegionKind::Variable, "x".into(), 23, 1),
+                (RegionKind::Stack, "CALL$STACK".into(), 16368, 16),
+                (RegionKind::Screen, "SCREEN".into(), 16384, 8192),
+                (RegionKind::Keyboard, "KBD".into(), 24576, 1),
+            ]
+        );
+        let text = "#var buf[4]\n@x";
+        let program = parse_lines(Line::read("main.asm", text).preprocess());
+        let regions = memory_map(&program).unwrap();
+        assert_eq!(stack_limit(&regions), 21);
+        assert_eq!(
+            report(&regions).lines().collect::<Vec<&str>>(),
+            vec![
+                "Start    End   Size  Kind       Name",
+                "    0     15     16  registers  R0-R15",
+                "   16     19      4  variable   buf",
+                "   20     20      1  variable   x",
+                "   21  16382  16362  free",
+                "16383  16383      1  stack      stack pointer",
+                "16384  24575   8192  screen     SCREEN",
+                "24576  24576      1  keyboard   KBD",
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_overlaps() {
+        assert_eq!(
+            map("#var buf[4] @ 14"),
+            Err("buf at 14 to 17 overlaps R0-R15 at 0 to 15".into())
+        );
+        assert_eq!(
+            map("#var frame[2] @ 16382"),
+            Err("stack pointer at 16383 overlaps frame at 16382 to 16383".into())
+        );
+        assert_eq!(
+            map("#var key @ 24576"),
+            Err("key at 24576 overlaps KBD at 24576".into())
+        );
+        assert_eq!(
+            map("#stack 100\n#var big[16300]"),
+            Err("Out of addresses for variable big".into())
+        );
+    }
+}
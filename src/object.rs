@@ -0,0 +1,352 @@
+//! Relocatable objects, which modules are assembled into separately and then linked together.
+//!
+//! A module's labels are its own unless it names them with `#export`, and it names the labels
+//! and variables it uses from other modules with `#extern`.  Variables are private to a module in
+//! the same way, so two modules can both use `@i` without sharing it.
+
+use crate::assembler::{try_encode, Allocator, Assemblable, SymbolKind, SymbolTable};
+use crate::expression::{is_symbol_char, Expression};
+use crate::optimizer::Optimizable;
+use crate::parser::try_parse_lines;
+use crate::preprocessor::{prologue, split_prologue};
+use crate::types::{check_address, Instruction, Line, Location, Macro, Variable, MAX_ADDRESS};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Written at the start of every object so the linker can tell what it's been given.
+pub const FORMAT: &str = "hack-object 1";
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Object {
+    pub format: String,
+    /// Code which sets up the module's data tables, run before any module's own code.
+    pub setup: Section,
+    pub code: Section,
+    /// Where each label is in `code`.
+    pub labels: BTreeMap<String, u16>,
+    pub exports: Vec<String>,
+    pub externs: Vec<String>,
+    /// The variables the module needs RAM for, in order of first use.
+    pub variables: Vec<String>,
+    /// The variables declared with `#var`, which are given RAM before the others.
+    #[serde(default)]
+    pub declarations: Vec<Variable>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+pub struct Section {
+    /// The machine code, with a zero wherever a relocation goes.
+    pub words: Vec<u16>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// An `@symbol` whose address isn't known until the modules are linked.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Relocation {
+    pub offset: u16,
+    /// A symbol, or an expression using symbols such as `TABLE+2`.
+    pub symbol: String,
+}
+
+/// Gives the names a list of directives such as `#export NAME` declare.
+fn declared(lines: &[Line], directive: &str) -> Vec<String> {
+    let mut names = vec![];
+    for line in lines {
+        let mut words = line.directive.iter().flat_map(|x| x.split_whitespace());
+        if words
+            .next()
+            .is_some_and(|x| x.eq_ignore_ascii_case(directive))
+        {
+            // a directive's expansion can be several lines, which all point back at it
+            let name = words.last().unwrap_or_default().to_string();
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+impl Object {
+    /// Assembles a preprocessed module.
+    pub fn new(lines: Vec<Line>, optimize: bool) -> Result<Object, String> {
+        let exports = declared(&lines, "#export");
+        let externs = declared(&lines, "#extern");
+        let (setup, code) = split_prologue(lines);
+        let setup = try_parse_lines(setup)?;
+        let mut code = try_parse_lines(code)?;
+        if optimize {
+            code = code.optimize();
+        }
+        let code = code
+            .into_iter()
+            .map(|(x, _)| x)
+            .collect::<Vec<Instruction>>();
+
+        let table = SymbolTable::try_new(&code)?;
+        let labels = table
+            .of_kind(SymbolKind::Label)
+            .into_iter()
+            .map(|(name, address)| (name.to_string(), address))
+            .collect::<BTreeMap<String, u16>>();
+        if let Some(name) = externs.iter().find(|x| labels.contains_key(*x)) {
+            return Err(format!("{} is defined here but declared #extern", name));
+        }
+
+        let mut object = Object {
+            format: FORMAT.to_string(),
+            setup: Section::default(),
+            code: Section::default(),
+            labels,
+            exports,
+            externs,
+            variables: vec![],
+            declarations: vec![],
+        };
+        let setup = setup
+            .into_iter()
+            .map(|(x, _)| x)
+            .collect::<Vec<Instruction>>();
+        for instruction in setup.iter().chain(&code) {
+            if let Instruction::Macro(Macro::Var(variable)) = instruction {
+                if object.is_variable(&variable.name) || object.labels.contains_key(&variable.name)
+                {
+                    return Err(format!("{} is declared more than once", variable.name));
+                }
+                object.declarations.push(variable.clone());
+            }
+        }
+        object.setup = object.section(&setup)?;
+        object.code = object.section(&code)?;
+        if let Some(name) = object
+            .exports
+            .iter()
+            .find(|x| !object.labels.contains_key(*x) && !object.is_variable(x))
+        {
+            return Err(format!("{} is exported but never defined or used", name));
+        }
+        Ok(object)
+    }
+
+    fn is_variable(&self, name: &str) -> bool {
+        self.variables.iter().any(|x| x == name) || self.declarations.iter().any(|x| x.name == name)
+    }
+
+    /// Encodes the instructions, leaving a relocation for every symbol which isn't predefined.
+    fn section(&mut self, program: &[Instruction]) -> Result<Section, String> {
+        let predefined = SymbolTable::try_new(&[])?;
+        let mut section = Section::default();
+        for instruction in program {
+            match instruction {
+                Instruction::Macro(Macro::Var(_)) => {}
+                Instruction::A(Location::Label(name)) if predefined.get(name).is_none() => {
+                    let local = self.labels.contains_key(name) || self.externs.contains(name);
+                    if !local && !self.is_variable(name) {
+                        self.variables.push(name.clone());
+                    }
+                    section.relocations.push(Relocation {
+                        offset: section.words.len() as u16,
+                        symbol: name.clone(),
+                    });
+                    section.words.push(0);
+                }
+                Instruction::A(Location::Expression(expression)) => {
+                    section.relocations.push(Relocation {
+                        offset: section.words.len() as u16,
+                        symbol: expression.to_string(),
+                    });
+                    section.words.push(0);
+                }
+                instruction => section.words.extend(try_encode(instruction, &predefined)?),
+            }
+        }
+        Ok(section)
+    }
+}
+
+/// Lays out the objects' setup code, then the prologue, then their own code in the order they are
+/// given, so the first object's code is the entry point.  Variables are allocated a module at a
+/// time, each in order of first use.
+pub fn link(objects: &[(String, Object)]) -> Result<Vec<u16>, String> {
+    for (name, object) in objects {
+        if object.format != FORMAT {
+            return Err(format!("{} is not a {} file", name, FORMAT));
+        }
+    }
+    let prologue = try_parse_lines(prologue())?
+        .into_iter()
+        .map(|(x, _)| x)
+        .collect::<Vec<Instruction>>()
+        .try_assemble()?;
+    let setup_length = objects
+        .iter()
+        .map(|(_, x)| x.setup.words.len())
+        .sum::<usize>();
+    let mut code_base = setup_length + prologue.len();
+
+    // pinned variables go where they ask to be before anything else is given RAM
+    let mut allocator = Allocator::new();
+    let mut pinned = vec![];
+    for (name, object) in objects {
+        let mut addresses = HashMap::new();
+        for variable in &object.declarations {
+            if let Some(address) = variable.address {
+                allocator
+                    .pin(&variable.name, address, variable.size)
+                    .map_err(|e| format!("{}: {}", name, e))?;
+                addresses.insert(variable.name.as_str(), address);
+            }
+        }
+        pinned.push(addresses);
+    }
+
+    // where every module's labels and variables are, and the ones they export
+    let mut modules = vec![];
+    let mut exports: HashMap<&str, (&str, u16)> = HashMap::new();
+    for ((name, object), mut addresses) in objects.iter().zip(pinned) {
+        for (label, offset) in &object.labels {
+            addresses.insert(label.as_str(), (code_base + *offset as usize) as u16);
+        }
+        let unpinned = object.declarations.iter().filter(|x| x.address.is_none());
+        let variables = unpinned
+            .map(|x| (x.name.as_str(), x.size))
+            .chain(object.variables.iter().map(|x| (x.as_str(), 1)));
+        for (variable, size) in variables {
+            let address = allocator
+                .allocate(variable, size)
+                .map_err(|e| format!("{} in {}", e, name))?;
+            addresses.insert(variable, address);
+        }
+        code_base += object.code.words.len();
+        if code_base > MAX_ADDRESS as usize + 1 {
+            return Err(format!("Program is longer than {} words", MAX_ADDRESS + 1));
+        }
+        for export in &object.exports {
+            let address = *addresses
+                .get(export.as_str())
+                .ok_or_else(|| format!("{} exports {}, which it doesn't define", name, export))?;
+            if let Some((other, _)) = exports.insert(export, (name, address)) {
+                return Err(format!(
+                    "{} is exported by both {} and {}",
+                    export, other, name
+                ));
+            }
+        }
+        modules.push(addresses);
+    }
+
+    let mut rom = vec![];
+    for ((name, object), addresses) in objects.iter().zip(&modules) {
+        place(&mut rom, &object.setup, (name, object), addresses, &exports)?;
+    }
+    rom.extend(prologue);
+    for ((name, object), addresses) in objects.iter().zip(&modules) {
+        place(&mut rom, &object.code, (name, object), addresses, &exports)?;
+    }
+    Ok(rom)
+}
+
+/// Adds a section onto the end of ROM, filling in its relocations with the module's own addresses
+/// and those exported by other modules.
+fn place(
+    rom: &mut Vec<u16>,
+    section: &Section,
+    (name, object): (&str, &Object),
+    addresses: &HashMap<&str, u16>,
+    exports: &HashMap<&str, (&str, u16)>,
+) -> Result<(), String> {
+    let base = rom.len();
+    rom.extend(&section.words);
+    let predefined = SymbolTable::try_new(&[])?;
+    let lookup = |symbol: &str| -> Result<u16, String> {
+        match addresses
+            .get(symbol)
+            .copied()
+            .or_else(|| predefined.get(symbol))
+        {
+            Some(address) => Ok(address),
+            None if object.externs.iter().any(|x| x == symbol) => {
+                let (_, address) = exports
+                    .get(symbol)
+                    .ok_or_else(|| format!("{} needs {}, which nothing exports", name, symbol))?;
+                Ok(*address)
+            }
+            None => Err(format!("{} doesn't define {}", name, symbol)),
+        }
+    };
+    for relocation in &section.relocations {
+        let symbol = relocation.symbol.as_str();
+        let address = if symbol.chars().all(is_symbol_char) {
+            lookup(symbol)?
+        } else {
+            let expression = Expression::parse(symbol)?;
+            for symbol in expression.symbols() {
+                lookup(symbol)?;
+            }
+            let value = expression.evaluate(&|x| lookup(x).ok().map(i64::from))?;
+            check_address(&expression, value).map_err(|e| format!("{}: {}", name, e))?
+        };
+        let offset = base + relocation.offset as usize;
+        *rom.get_mut(offset)
+            .ok_or_else(|| format!("{} has a relocation outside its code", name))? = address;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::Emulator;
+    use crate::object::{link, Object};
+    use crate::preprocessor::Preprocessable;
+    use crate::types::Line;
+
+    const MAIN: &str = "#extern double\n#extern result\n#data TABLE 7\n@21\nD=A\n@result\nM=D\n\
+                        @i\nM=1\n#call double\n(END)\n@END\n0;JMP";
+    const DOUBLE: &str = "#export double\n#export result\n(double)\n@result\nD=M\nM=D+M\n\
+                          @i\nM=-1\n#ret";
+
+    fn object(name: &str, text: &str) -> (String, Object) {
+        let lines = Line::read(name, text).preprocess();
+        (name.to_string(), Object::new(lines, false).unwrap())
+    }
+
+    #[test]
+    fn links_modules() {
+        let rom = link(&[object("main.asm", MAIN), object("double.asm", DOUBLE)]).unwrap();
+        let mut emulator = Emulator::new(rom);
+        assert!(emulator.run(1000));
+        // main's TABLE and i, then double's result and i
+        assert_eq!(emulator.ram[16..20], [7, 1, 42, 0xFFFF]);
+    }
+
+    #[test]
+    fn links_a_single_module_like_the_assembler() {
+        let text = "#data TABLE 1, 2\n#var pin @ 17\n(LOOP)\n@i\nM=M+1\n#var buf[3]\n#call f\n\
+                    @LOOP\n0;JMP\n(f)\n@TABLE+1\n@buf+2\n@pin\n#ret";
+        assert_eq!(
+            link(&[object("main.asm", text)]),
+            crate::assemble("main.asm", text)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_links() {
+        assert_eq!(
+            link(&[object("main.asm", MAIN)]),
+            Err("main.asm needs result, which nothing exports".to_string())
+        );
+        assert_eq!(
+            link(&[
+                object("main.asm", MAIN),
+                object("double.asm", DOUBLE),
+                object("again.asm", DOUBLE)
+            ]),
+            Err("double is exported by both double.asm and again.asm".to_string())
+        );
+        let lines = Line::read("main.asm", "#export missing\n@0").preprocess();
+        assert_eq!(
+            Object::new(lines, false),
+            Err("missing is exported but never defined or used".to_string())
+        );
+    }
+}
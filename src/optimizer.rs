@@ -0,0 +1,394 @@
+use crate::types::*;
+
+/// Peephole optimisations over preprocessed programs.  Instructions are removed, so jumps must go
+/// to labels rather than literal ROM addresses.
+pub trait Optimizable {
+    fn optimize(self) -> Self;
+}
+
+/// Every pass shrinks the program or retargets a jump, so this is only hit if jump threading keeps
+/// going around a cycle of jumps.
+const MAX_PASSES: usize = 64;
+
+fn reads(instruction: &Instruction, register: Register) -> bool {
+    match instruction {
+        Instruction::C(dest, computation, jump) => match register {
+            // M is addressed by A, and jumps go to A
+            Register::A => {
+                computation.uses(Register::A)
+                    || computation.uses(Register::M)
+                    || dest.contains(&Register::M)
+                    || *jump != Jump::None
+            }
+            register => computation.uses(register),
+        },
+        Instruction::Macro(Macro::Var(_)) => false,
+        Instruction::Macro(_) => true,
+        _ => false,
+    }
+}
+
+fn writes(instruction: &Instruction, register: Register) -> bool {
+    match instruction {
+        Instruction::A(_) => register == Register::A,
+        Instruction::C(dest, _, _) => dest.contains(&register),
+        Instruction::Macro(Macro::Var(_)) => false,
+        Instruction::Macro(_) => true,
+        Instruction::Label(_) => false,
+    }
+}
+
+fn is_jump(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::C(_, _, jump) => *jump != Jump::None,
+        Instruction::Macro(Macro::Var(_)) => false,
+        Instruction::Macro(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether the instruction jumps no matter what is in the registers.
+pub fn always_jumps(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::C(_, computation, jump) => match computation.to_string().as_ref() {
+            "0" => jump.bits() & 0b010 != 0,
+            "1" => jump.bits() & 0b001 != 0,
+            "-1" => jump.bits() & 0b100 != 0,
+            _ => *jump == Jump::JMP,
+        },
+        _ => false,
+    }
+}
+
+/// A jump which doesn't store anything and whose condition doesn't depend on A, so it behaves the
+/// same whatever label it was pointed at.
+fn is_plain_jump(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::C(dest, computation, jump) => {
+            dest.is_empty()
+                && *jump != Jump::None
+                && !computation.uses(Register::A)
+                && !computation.uses(Register::M)
+        }
+        _ => false,
+    }
+}
+
+/// Whether the value in the register may be read by the program carrying on from `index`.  Jumps
+/// are assumed to read everything, as the code they go to isn't followed.
+fn is_live(program: &[Instruction], index: usize, register: Register) -> bool {
+    for instruction in &program[index.min(program.len())..] {
+        match instruction {
+            Instruction::Label(_) => continue,
+            x if reads(x, register) || is_jump(x) => return true,
+            x if writes(x, register) => return false,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// If the code at the label immediately jumps unconditionally, where it jumps to.
+fn jump_target(program: &[Instruction], label: &str) -> Option<Location> {
+    let start = program
+        .iter()
+        .position(|x| *x == Instruction::Label(label.to_string()))?;
+    let mut rest = program[start..]
+        .iter()
+        .skip_while(|x| matches!(x, Instruction::Label(_)));
+    match (rest.next(), rest.next()) {
+        (Some(Instruction::A(target)), Some(jump)) if is_plain_jump(jump) && always_jumps(jump) => {
+            Some(target.clone())
+        }
+        _ => None,
+    }
+}
+
+/// Points jumps to a label which immediately jumps somewhere else straight at the final target.
+fn thread_jumps(program: &mut [Instruction]) {
+    for i in 1..program.len() {
+        if !is_plain_jump(&program[i]) {
+            continue;
+        }
+        let label = match &program[i - 1] {
+            Instruction::A(Location::Label(label)) => label.clone(),
+            _ => continue,
+        };
+        // if the jump isn't taken the code after it sees the new target in A
+        if !always_jumps(&program[i]) && is_live(program, i + 1, Register::A) {
+            continue;
+        }
+        if let Some(target) = jump_target(program, &label) {
+            program[i - 1] = Instruction::A(target);
+        }
+    }
+}
+
+/// Removes jumps to the label directly after them.
+fn remove_jumps_to_next(program: &[Instruction]) -> Vec<bool> {
+    (0..program.len())
+        .map(|i| {
+            let label = match (i.checked_sub(1).map(|x| &program[x]), &program[i]) {
+                (Some(Instruction::A(Location::Label(label))), jump) if is_plain_jump(jump) => {
+                    Instruction::Label(label.clone())
+                }
+                _ => return true,
+            };
+            !program[i + 1..]
+                .iter()
+                .take_while(|x| matches!(x, Instruction::Label(_)))
+                .any(|x| *x == label)
+        })
+        .collect()
+}
+
+/// Removes code after an unconditional jump up until the next label.
+fn remove_unreachable(program: &[Instruction]) -> Vec<bool> {
+    let mut reachable = true;
+    program
+        .iter()
+        .map(|x| match x {
+            Instruction::Label(_) => {
+                reachable = true;
+                true
+            }
+            // declarations have to stay wherever they are
+            Instruction::Macro(Macro::Var(_)) => true,
+            x => {
+                let keep = reachable;
+                if always_jumps(x) {
+                    reachable = false;
+                }
+                keep
+            }
+        })
+        .collect()
+}
+
+/// Removes A-instructions which load the value A already holds or whose value is never used.
+fn remove_redundant_loads(program: &[Instruction]) -> Vec<bool> {
+    let mut keep = vec![true; program.len()];
+    // what A holds, as long as it is known; code after a label can be reached from anywhere
+    let mut current: Option<&Location> = None;
+    for (i, instruction) in program.iter().enumerate() {
+        match instruction {
+            Instruction::A(location) => {
+                if current == Some(location) || !is_live(program, i + 1, Register::A) {
+                    keep[i] = false;
+                } else {
+                    current = Some(location);
+                }
+            }
+            Instruction::Label(_) => current = None,
+            x if writes(x, Register::A) => current = None,
+            _ => {}
+        }
+    }
+    keep
+}
+
+/// Removes C-instructions which only store into A and D when nothing reads those values.
+fn remove_dead_stores(program: &[Instruction]) -> Vec<bool> {
+    program
+        .iter()
+        .enumerate()
+        .map(|(i, x)| match x {
+            Instruction::C(dest, _, Jump::None) => dest
+                .iter()
+                .any(|x| *x == Register::M || is_live(program, i + 1, *x)),
+            _ => true,
+        })
+        .collect()
+}
+
+fn instructions<S>(program: &[(Instruction, S)]) -> Vec<Instruction> {
+    program.iter().map(|(x, _)| x.clone()).collect()
+}
+
+/// Drops the instructions a pass doesn't keep, along with whatever is attached to them.
+fn retain<S>(
+    program: Vec<(Instruction, S)>,
+    pass: fn(&[Instruction]) -> Vec<bool>,
+) -> Vec<(Instruction, S)> {
+    let keep = pass(&instructions(&program));
+    program
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(x, keep)| if keep { Some(x) } else { None })
+        .collect()
+}
+
+/// Optimizes instructions tagged with where they came from, keeping the tags with the instructions
+/// which survive.
+impl<S> Optimizable for Vec<(Instruction, S)> {
+    fn optimize(self) -> Self {
+        let mut program = self;
+        for _ in 0..MAX_PASSES {
+            let before = instructions(&program);
+            let mut threaded = before.clone();
+            thread_jumps(&mut threaded);
+            program = program
+                .into_iter()
+                .zip(threaded)
+                .map(|((_, tag), x)| (x, tag))
+                .collect();
+            for pass in &[
+                remove_jumps_to_next,
+                remove_unreachable,
+                remove_redundant_loads,
+                remove_dead_stores,
+            ] {
+                program = retain(program, *pass);
+            }
+            if instructions(&program) == before {
+                break;
+            }
+        }
+        program
+    }
+}
+
+impl Optimizable for Vec<Instruction> {
+    fn optimize(self) -> Self {
+        self.into_iter()
+            .map(|x| (x, ()))
+            .collect::<Vec<(Instruction, ())>>()
+            .optimize()
+            .into_iter()
+            .map(|(x, _)| x)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assemblable;
+    use crate::emulator::Emulator;
+    use crate::optimizer::Optimizable;
+    use crate::parser::parse;
+    use crate::preprocessor::Preprocessable;
+    use std::fs;
+
+    fn optimize(program: &str) -> String {
+        parse(program.to_string())
+            .optimize()
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn run(program: Vec<u16>) -> Emulator {
+        let mut emulator = Emulator::new(program);
+        assert!(emulator.run(1_000_000), "program did not halt");
+        emulator
+    }
+
+    /// Checks the optimised program leaves RAM the same as the original, apart from the return
+    /// addresses left behind on the call stack.
+    fn assert_equivalent(program: &str) {
+        let program = parse(
+            program
+                .split('\n')
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+                .preprocess()
+                .join("\n"),
+        );
+        let original = run(program.clone().assemble());
+        let optimized = run(program.clone().optimize().assemble());
+        assert!(optimized.cycles <= original.cycles);
+        assert_eq!(original.ram[..16383 - 256], optimized.ram[..16383 - 256]);
+        assert_eq!(original.ram[16383..], optimized.ram[16383..]);
+    }
+
+    #[test]
+    fn removes_redundant_loads() {
+        assert_eq!(
+            optimize("@5\n@6\nD=A\n@6\nM=D\n@6\nM=M+1"),
+            "@6\nD=A\nM=D\nM=M+1"
+        );
+        assert_eq!(optimize("@6\nAM=M+1\n@6\nM=0"), "@6\nAM=M+1\n@6\nM=0");
+        assert_eq!(optimize("@6\nM=0\n(L)\n@6\nM=0"), "@6\nM=0\n(L)\n@6\nM=0");
+    }
+
+    #[test]
+    fn removes_dead_stores() {
+        assert_eq!(optimize("@1\nD=A\n@2\nD=A\n@0\nM=D"), "@2\nD=A\n@0\nM=D");
+        assert_eq!(
+            optimize("@1\nD=A\n@L\nD;JGT\n@0\nM=0\n(L)"),
+            "@1\nD=A\n@L\nD;JGT\n@0\nM=0\n(L)"
+        );
+        assert_eq!(optimize("@1\nD=A\n@0\nM=D\nD=0"), "@1\nD=A\n@0\nM=D");
+    }
+
+    #[test]
+    fn removes_unreachable() {
+        assert_eq!(
+            optimize("@M\n0;JMP\n@5\nD=A\n(L)\n@6\nM=0\n(M)\n@M\n0;JMP\nM=1"),
+            "@M\n0;JMP\n(L)\n@6\nM=0\n(M)\n@M\n0;JMP"
+        );
+        assert_eq!(
+            optimize("@L\nD;JGT\n@5\nM=0\n(L)\n@L\n0;JMP"),
+            "@L\nD;JGT\n@5\nM=0\n(L)\n@L\n0;JMP"
+        );
+    }
+
+    #[test]
+    fn removes_jumps_to_next() {
+        assert_eq!(optimize("@L\n0;JMP\n(L)\n@0\nM=0"), "(L)\n@0\nM=0");
+        assert_eq!(
+            optimize("@L\nD;JEQ\n(M)\n(L)\n@0\nM=0"),
+            "(M)\n(L)\n@0\nM=0"
+        );
+        // the code after the label relies on A holding the label's address
+        assert_eq!(optimize("@L\n0;JMP\n(L)\nM=0"), "@L\n(L)\nM=0");
+        // jumping along a chain of jumps to the next instruction collapses the whole chain
+        assert_eq!(
+            optimize("@A\n0;JMP\n(B)\n@C\n0;JMP\n(A)\n@B\n0;JMP\n(C)\n@C\n0;JMP"),
+            "(B)\n(A)\n(C)\n@C\n0;JMP"
+        );
+    }
+
+    #[test]
+    fn threads_jumps() {
+        assert_eq!(
+            optimize(
+                "@A\nD;JGT\n@0\nM=0\n@END\n0;JMP\n(A)\n@B\n0;JMP\n(X)\nM=1\n(B)\n@1\nM=1\n\
+                 (END)\n@END\n0;JMP"
+            ),
+            "@B\nD;JGT\n@0\nM=0\n@END\n0;JMP\n(A)\n@B\n0;JMP\n(X)\nM=1\n(B)\n@1\nM=1\n\
+             (END)\n@END\n0;JMP"
+        );
+        // A is used if the jump isn't taken
+        assert_eq!(
+            optimize("@A\nD;JGT\nM=D\n(A)\n@B\n0;JMP\n(X)\n@X\n0;JMP\n(B)\n@B\n0;JMP"),
+            "@A\nD;JGT\nM=D\n(A)\n@B\n0;JMP\n(X)\n@X\n0;JMP\n(B)\n@B\n0;JMP"
+        );
+        assert_eq!(
+            optimize("(A)\n@B\n0;JMP\n(B)\n@A\n0;JMP"),
+            "(A)\n@A\n0;JMP\n(B)\n@A\n0;JMP"
+        );
+    }
+
+    #[test]
+    fn keeps_behaviour() {
+        assert_equivalent(
+            "@6\nD=A\n@R0\nM=D\n@7\nD=A\n@R1\nM=D\n@R2\nM=0\n\
+             (LOOP)\n@R1\nD=M\n@END\nD;JEQ\n@R0\nD=M\n@R2\nM=D+M\n@R1\nM=M-1\n@LOOP\n0;JMP\n\
+             @R2\nM=0\n(END)\n@END\n0;JMP",
+        );
+        assert_equivalent(
+            "@START\n0;JMP\n(START)\n@ONE\n0;JMP\n(ONE)\n@TWO\n0;JMP\n(TWO)\n@5\n@6\nD=A\n@x\nM=D\n\
+             @x\nM=M+1\nD=0\n@END\n0;JMP\n(END)\n@END\n0;JMP",
+        );
+        assert_equivalent("#data T 1, 2, 3\n#string S \"hi\"\n@T\nD=M\n@S\nM=D+M");
+        for file in &[
+            "test_cases/function_test_easy.asm",
+            "test_cases/function_test_depth.asm",
+            "test_cases/include_test.asm",
+        ] {
+            assert_equivalent(&fs::read_to_string(file).unwrap());
+        }
+    }
+}
@@ -0,0 +1,437 @@
+use crate::types::{Instruction, Line, Location, MAX_ADDRESS};
+use std::collections::{BTreeMap, HashMap};
+
+/// Where the Jack OS's heap starts, and the word after it ends.
+const HEAP: (u16, u16) = (2048, 16384);
+
+/// The Jack character codes for a new line and a backspace.
+const NEW_LINE: u16 = 128;
+const BACKSPACE: u16 = 129;
+
+/// A function of the Jack OS, run in Rust with its arguments and giving its return value.
+type Native = fn(&mut Os, &mut [u16], &[u16]) -> Result<u16, String>;
+
+pub struct Function {
+    pub name: &'static str,
+    arguments: usize,
+    native: Native,
+}
+
+macro_rules! function {
+    ($name:expr, $arguments:expr, $native:expr) => {
+        Function {
+            name: $name,
+            arguments: $arguments,
+            native: $native,
+        }
+    };
+}
+
+/// Strings are laid out as their capacity, their length and then their characters, which is all
+/// that matters as long as every string function is native.
+pub const FUNCTIONS: [Function; 33] = [
+    function!("Math.multiply", 2, |_, _, x| {
+        Ok((x[0] as i16).wrapping_mul(x[1] as i16) as u16)
+    }),
+    function!("Math.divide", 2, |_, _, x| match x[1] {
+        0 => Err("Math.divide by zero".to_string()),
+        _ => Ok((x[0] as i16).wrapping_div(x[1] as i16) as u16),
+    }),
+    function!("Math.min", 2, |_, _, x| Ok(
+        (x[0] as i16).min(x[1] as i16) as u16
+    )),
+    function!("Math.max", 2, |_, _, x| Ok(
+        (x[0] as i16).max(x[1] as i16) as u16
+    )),
+    function!("Math.abs", 1, |_, _, x| Ok(
+        (x[0] as i16).wrapping_abs() as u16
+    )),
+    function!("Math.sqrt", 1, |_, _, x| match x[0] as i16 {
+        n if n < 0 => Err(format!("Math.sqrt of {}", n)),
+        n => Ok((n as f64).sqrt() as u16),
+    }),
+    function!("Memory.peek", 1, |_, ram, x| Ok(ram[index(x[0], 0)])),
+    function!("Memory.poke", 2, |_, ram, x| {
+        ram[index(x[0], 0)] = x[1];
+        Ok(0)
+    }),
+    function!("Memory.alloc", 1, |os, _, x| os.alloc(x[0])),
+    function!("Memory.deAlloc", 1, |os, _, x| os.free(x[0]).map(|_| 0)),
+    function!("Output.printChar", 1, |os, _, x| {
+        os.print(x[0]);
+        Ok(0)
+    }),
+    function!("Output.printString", 1, |os, ram, x| {
+        for i in 0..ram[index(x[0], 1)] {
+            os.print(ram[index(x[0].wrapping_add(2), i)]);
+        }
+        Ok(0)
+    }),
+    function!("Output.printInt", 1, |os, _, x| {
+        os.output.push_str(&(x[0] as i16).to_string());
+        Ok(0)
+    }),
+    function!("Output.println", 0, |os, _, _| {
+        os.print(NEW_LINE);
+        Ok(0)
+    }),
+    function!("Output.backSpace", 0, |os, _, _| {
+        os.print(BACKSPACE);
+        Ok(0)
+    }),
+    function!("Output.moveCursor", 2, |_, _, _| Ok(0)),
+    function!("String.new", 1, |os, ram, x| {
+        let string = os.alloc(x[0].wrapping_add(2))?;
+        ram[index(string, 0)] = x[0];
+        ram[index(string, 1)] = 0;
+        Ok(string)
+    }),
+    function!("String.dispose", 1, |os, _, x| os.free(x[0]).map(|_| 0)),
+    function!("String.length", 1, |_, ram, x| Ok(ram[index(x[0], 1)])),
+    function!("String.charAt", 2, |_, ram, x| {
+        string_index(ram, x[0], x[1]).map(|i| ram[i])
+    }),
+    function!("String.setCharAt", 3, |_, ram, x| {
+        let i = string_index(ram, x[0], x[1])?;
+        ram[i] = x[2];
+        Ok(0)
+    }),
+    function!("String.appendChar", 2, |_, ram, x| {
+        let (capacity, length) = (ram[index(x[0], 0)], ram[index(x[0], 1)]);
+        if length >= capacity {
+            return Err(format!(
+                "String.appendChar to a full string of {}",
+                capacity
+            ));
+        }
+        ram[index(x[0].wrapping_add(2), length)] = x[1];
+        ram[index(x[0], 1)] = length + 1;
+        Ok(x[0])
+    }),
+    function!("String.eraseLastChar", 1, |_, ram, x| {
+        let length = &mut ram[index(x[0], 1)];
+        *length = length.saturating_sub(1);
+        Ok(0)
+    }),
+    function!("String.intValue", 1, |_, ram, x| {
+        let characters = (0..ram[index(x[0], 1)])
+            .map(|i| ram[index(x[0].wrapping_add(2), i)] as u8 as char)
+            .collect::<String>();
+        let digits = characters
+            .char_indices()
+            .take_while(|(i, c)| c.is_ascii_digit() || (*i == 0 && *c == '-'))
+            .map(|(_, c)| c)
+            .collect::<String>();
+        Ok(digits.parse::<i32>().map_or(0, |x| x as i16 as u16))
+    }),
+    function!("String.setInt", 2, |_, ram, x| {
+        let digits = (x[1] as i16).to_string();
+        if digits.len() > ram[index(x[0], 0)] as usize {
+            return Err(format!(
+                "String.setInt of {} to a string which is too short",
+                digits
+            ));
+        }
+        for (i, c) in digits.bytes().enumerate() {
+            ram[index(x[0].wrapping_add(2), i as u16)] = c as u16;
+        }
+        ram[index(x[0], 1)] = digits.len() as u16;
+        Ok(0)
+    }),
+    function!("String.newLine", 0, |_, _, _| Ok(NEW_LINE)),
+    function!("String.backSpace", 0, |_, _, _| Ok(BACKSPACE)),
+    function!("String.doubleQuote", 0, |_, _, _| Ok('"' as u16)),
+    function!("Sys.halt", 0, |os, _, _| {
+        os.halted = true;
+        Ok(0)
+    }),
+    function!("Sys.error", 1, |_, _, x| Err(format!(
+        "Sys.error({})",
+        x[0] as i16
+    ))),
+    function!("Sys.wait", 1, |_, _, _| Ok(0)),
+    function!("Keyboard.keyPressed", 0, |_, _, _| Ok(0)),
+    function!("Screen.clearScreen", 0, |_, _, _| Ok(0)),
+];
+
+/// Where a word of RAM is, wrapping around like the A register does.
+fn index(address: u16, offset: u16) -> usize {
+    (address.wrapping_add(offset) & MAX_ADDRESS) as usize
+}
+
+fn string_index(ram: &[u16], string: u16, i: u16) -> Result<usize, String> {
+    if i >= ram[index(string, 1)] {
+        return Err(format!("String index {} is out of range", i));
+    }
+    Ok(index(string.wrapping_add(2), i))
+}
+
+/// The Jack OS, run natively by the emulator when a program calls one of its functions using the
+/// VM calling convention, so programs can be run and tested without assembling the real OS.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Os {
+    /// The function at each ROM address, as an index into `FUNCTIONS`.
+    functions: HashMap<u16, usize>,
+    /// Everything printed with `Output`, with new lines as `\n`.
+    pub output: String,
+    /// The free blocks of the heap by where they start, along with their sizes.
+    free: BTreeMap<u16, u16>,
+    allocated: HashMap<u16, u16>,
+    /// Why the program stopped, if it called `Sys.error` or misused a function.
+    pub error: Option<String>,
+    halted: bool,
+}
+
+impl Default for Os {
+    fn default() -> Os {
+        Os {
+            functions: HashMap::new(),
+            output: String::new(),
+            free: std::iter::once((HEAP.0, HEAP.1 - HEAP.0)).collect(),
+            allocated: HashMap::new(),
+            error: None,
+            halted: false,
+        }
+    }
+}
+
+/// Finds the calls a program makes to the OS, pointing any to functions it doesn't define itself
+/// at addresses past the end of ROM.  OS functions it does define are replaced as well.
+pub fn link(program: &mut [(Instruction, Line)]) -> Os {
+    let mut os = Os::default();
+    let mut address = 0;
+    for (instruction, _) in program.iter() {
+        match instruction {
+            Instruction::Label(label) => {
+                os.define(label, address);
+            }
+            x if x.takes_rom() => address += 1,
+            _ => {}
+        }
+    }
+    for (instruction, _) in program.iter_mut() {
+        if let Instruction::A(Location::Label(label)) = instruction {
+            if let Some(i) = FUNCTIONS.iter().position(|x| x.name == label) {
+                let address = os
+                    .functions
+                    .iter()
+                    .find(|(_, x)| **x == i)
+                    .map_or(MAX_ADDRESS - i as u16, |(address, _)| *address);
+                os.functions.insert(address, i);
+                *instruction = Instruction::A(Location::Address(address));
+            }
+        }
+    }
+    os
+}
+
+impl Os {
+    /// Runs the OS function with the given name whenever the program reaches an address.  Gives
+    /// whether there is such a function.
+    pub fn define(&mut self, name: &str, address: u16) -> bool {
+        match FUNCTIONS.iter().position(|x| x.name == name) {
+            Some(i) => {
+                self.functions.insert(address, i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether reaching the address calls the OS.
+    pub fn handles(&self, address: u16) -> bool {
+        self.functions.contains_key(&address)
+    }
+
+    /// Whether the program called `Sys.halt` or stopped with an error.
+    pub fn halted(&self) -> bool {
+        self.halted || self.error.is_some()
+    }
+
+    fn print(&mut self, character: u16) {
+        match character {
+            NEW_LINE => self.output.push('\n'),
+            BACKSPACE => {
+                self.output.pop();
+            }
+            x => self
+                .output
+                .push(char::from_u32(x as u32).unwrap_or(char::REPLACEMENT_CHARACTER)),
+        }
+    }
+
+    /// Finds the first free block big enough.
+    fn alloc(&mut self, size: u16) -> Result<u16, String> {
+        if size == 0 || size > MAX_ADDRESS {
+            return Err(format!("Memory.alloc of {} words", size as i16));
+        }
+        let (start, free) = self
+            .free
+            .iter()
+            .map(|(x, y)| (*x, *y))
+            .find(|(_, free)| *free >= size)
+            .ok_or_else(|| format!("Memory.alloc of {} words, but the heap is full", size))?;
+        self.free.remove(&start);
+        if free > size {
+            self.free.insert(start + size, free - size);
+        }
+        self.allocated.insert(start, size);
+        Ok(start)
+    }
+
+    /// Gives a block back to the heap, merging it with the free blocks either side.
+    fn free(&mut self, block: u16) -> Result<(), String> {
+        let mut size = self
+            .allocated
+            .remove(&block)
+            .ok_or_else(|| format!("Memory.deAlloc of {}, which was never allocated", block))?;
+        let mut start = block;
+        if let Some(after) = self.free.remove(&(start + size)) {
+            size += after;
+        }
+        if let Some((before, free)) = self.free.range(..start).next_back().map(|(x, y)| (*x, *y)) {
+            if before + free == start {
+                self.free.remove(&before);
+                start = before;
+                size += free;
+            }
+        }
+        self.free.insert(start, size);
+        Ok(())
+    }
+
+    /// Runs the function at `pc` if there is one, reading its arguments and returning from it the
+    /// way the VM translator's `call` and `return` do.  Gives the address to carry on from.
+    pub fn call(&mut self, pc: u16, ram: &mut [u16]) -> Option<u16> {
+        let function = &FUNCTIONS[*self.functions.get(&pc)?];
+        let (frame, arguments) = (ram[1], ram[2]);
+        let values = (0..function.arguments as u16)
+            .map(|i| ram[index(arguments, i)])
+            .collect::<Vec<u16>>();
+        let value = match (function.native)(self, ram, &values) {
+            Ok(value) => value,
+            Err(e) => {
+                self.error = Some(e);
+                return Some(pc);
+            }
+        };
+        if self.halted {
+            return Some(pc);
+        }
+        let saved = |i: u16| ram[index(frame.wrapping_sub(i), 0)];
+        let (address, that, this, arg, lcl) = (saved(5), saved(1), saved(2), saved(3), saved(4));
+        ram[index(arguments, 0)] = value;
+        ram[0] = arguments.wrapping_add(1);
+        ram[1] = lcl;
+        ram[2] = arg;
+        ram[3] = this;
+        ram[4] = that;
+        Some(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assemblable;
+    use crate::emulator::Emulator;
+    use crate::os::{link, FUNCTIONS};
+    use crate::parser::parse_lines;
+    use crate::types::{Instruction, Line};
+
+    /// Calls a function the way the VM translator does, after pushing the arguments.  Any others
+    /// it takes are whatever is already on the stack.
+    fn call(function: &str, arguments: &[i16], n: usize) -> String {
+        let mut code = String::new();
+        for x in arguments {
+            let load = if *x < 0 {
+                format!("@{}\nD=-A\n", -x)
+            } else {
+                format!("@{}\nD=A\n", x)
+            };
+            code += &(load + "@SP\nA=M\nM=D\n@SP\nM=M+1\n");
+        }
+        code += &format!("@RETURN{n}\nD=A\n@SP\nA=M\nM=D\n@SP\nM=M+1\n", n = n);
+        for register in &["LCL", "ARG", "THIS", "THAT"] {
+            code += &format!("@{}\nD=M\n@SP\nA=M\nM=D\n@SP\nM=M+1\n", register);
+        }
+        code += &format!(
+            "@SP\nD=M\n@{}\nD=D-A\n@ARG\nM=D\n@SP\nD=M\n@LCL\nM=D\n@{}\n0;JMP\n(RETURN{})\n",
+            FUNCTIONS
+                .iter()
+                .find(|x| x.name == function)
+                .unwrap()
+                .arguments
+                + 5,
+            function,
+            n
+        );
+        code
+    }
+
+    /// Runs calls which each use whatever the last one left on the stack.
+    fn run(calls: &[(&str, &[i16])]) -> Emulator {
+        let mut program = "@256\nD=A\n@SP\nM=D\n".to_string();
+        for (i, (function, arguments)) in calls.iter().enumerate() {
+            program += &call(function, arguments, i);
+        }
+        let mut program = parse_lines(Line::read("main.asm", &(program + "(END)\n@END\n0;JMP")));
+        let os = link(&mut program);
+        let rom = program
+            .into_iter()
+            .map(|(x, _)| x)
+            .collect::<Vec<Instruction>>()
+            .assemble();
+        let mut emulator = Emulator::new(rom);
+        emulator.os = Some(os);
+        assert!(emulator.run(10_000));
+        emulator
+    }
+
+    #[test]
+    fn does_arithmetic() {
+        let emulator = run(&[("Math.multiply", &[6, -7]), ("Math.divide", &[-5])]);
+        assert_eq!(emulator.ram[0], 257);
+        assert_eq!(emulator.ram[256] as i16, 8);
+        assert_eq!(emulator.os.unwrap().error, None);
+
+        let emulator = run(&[("Math.divide", &[1, 0])]);
+        assert_eq!(
+            emulator.os.unwrap().error,
+            Some("Math.divide by zero".to_string())
+        );
+    }
+
+    #[test]
+    fn prints() {
+        let emulator = run(&[
+            ("String.new", &[2]),
+            ("String.appendChar", &['h' as i16]),
+            ("String.appendChar", &['i' as i16]),
+            ("Output.printString", &[]),
+            ("Output.printInt", &[-12]),
+            ("Output.println", &[]),
+            ("Output.printChar", &['!' as i16]),
+        ]);
+        let os = emulator.os.unwrap();
+        assert_eq!((os.output.as_str(), os.error), ("hi-12\n!", None));
+    }
+
+    #[test]
+    fn allocates() {
+        let emulator = run(&[
+            ("Memory.alloc", &[10]),
+            ("Memory.alloc", &[5]),
+            ("Memory.deAlloc", &[]),
+            ("Memory.alloc", &[3]),
+        ]);
+        assert_eq!(emulator.ram[256..259], [2048, 0, 2058]);
+        let emulator = run(&[
+            ("Memory.alloc", &[4]),
+            ("Memory.deAlloc", &[]),
+            ("Memory.alloc", &[7]),
+        ]);
+        assert_eq!(emulator.ram[256..258], [0, 2048]);
+        let emulator = run(&[("Memory.deAlloc", &[9])]);
+        assert!(emulator.os.unwrap().error.is_some());
+    }
+}
@@ -1,16 +1,23 @@
 use crate::types::*;
 use nom::branch::alt;
 use nom::bytes::complete::{tag, tag_no_case, take, take_while, take_while1};
-use nom::combinator::opt;
+use nom::combinator::{map, opt};
 use nom::error::{ErrorKind, ParseError, VerboseError};
 use nom::multi::{many0, many1};
 use nom::IResult;
+use std::convert::TryFrom;
 
 fn parse_a(text: &str) -> IResult<&str, Instruction, VerboseError<&str>> {
     let (text, _) = tag("@")(text)?;
-    let (text, location) = take_while(|ch| ch != '\n')(text)?;
-    let (text, _) = opt(tag("\n"))(text)?;
-    Ok((text, Instruction::A(Location::from(location))))
+    let (rest, location) = take_while(|ch| ch != '\n')(text)?;
+    let (rest, _) = opt(tag("\n"))(rest)?;
+    match Location::try_from(location) {
+        Ok(location) => Ok((rest, Instruction::A(location))),
+        Err(_) => Err(nom::Err::Failure(VerboseError::from_error_kind(
+            text,
+            ErrorKind::Verify,
+        ))), // TODO fix this error
+    }
 }
 
 fn parse_dest(text: &str) -> IResult<&str, Vec<Register>, VerboseError<&str>> {
@@ -32,13 +39,14 @@ fn parse_computation(text: &str) -> IResult<&str, Computation, VerboseError<&str
                 ErrorKind::Char,
             ))) // TODO fix this error
         } else {
+            let (text, _) = opt(alt((tag(";"), tag("\n"))))(text)?;
             Ok((text, Computation::Computation(lhs, Source::None, op)))
         }
     } else {
         let lhs = Source::from(first_char);
         let end = opt(take_while1(|ch| ch != ';' && ch != '\n'))(text)?;
 
-        if end.1.unwrap_or("").len() == 0 {
+        if end.1.unwrap_or("").is_empty() {
             let (text, _) = opt(alt((tag(";"), tag("\n"))))(text)?;
             Ok((
                 text,
@@ -69,13 +77,12 @@ fn parse_jmp(text: &str) -> IResult<&str, Jump, VerboseError<&str>> {
     )))(text)?;
     let (text, between) = opt(take_while1(|ch| ch != '\n'))(text)?;
     let (text, _) = opt(tag("\n"))(text)?;
-    if between.is_none() && jmp.is_some() && Jump::from(jmp.unwrap()) != Jump::None {
-        Ok((text, jmp.unwrap().into()))
-    } else {
-        Err(nom::Err::Error(VerboseError::from_error_kind(
+    match jmp {
+        Some(jmp) if between.is_none() && Jump::from(jmp) != Jump::None => Ok((text, jmp.into())),
+        _ => Err(nom::Err::Error(VerboseError::from_error_kind(
             original_text,
             ErrorKind::Char,
-        ))) // TODO fix this error
+        ))), // TODO fix this error
     }
 }
 
@@ -92,26 +99,126 @@ fn parse_macro(text: &str) -> IResult<&str, Macro, VerboseError<&str>> {
     Ok((text, Macro::from((directive, arg))))
 }
 
+fn parse_var(text: &str) -> IResult<&str, Macro, VerboseError<&str>> {
+    let (text, _) = tag_no_case("#var")(text)?;
+    let (text, _) = many1(alt((tag(" "), tag("\t"))))(text)?;
+    let (rest, declaration) = take_while(|ch| ch != '\n')(text)?;
+    let (rest, _) = opt(tag("\n"))(rest)?;
+    match Variable::parse(declaration) {
+        Ok(variable) => Ok((rest, Macro::Var(variable))),
+        Err(_) => Err(nom::Err::Failure(VerboseError::from_error_kind(
+            text,
+            ErrorKind::Verify,
+        ))),
+    }
+}
+
+fn parse_label(text: &str) -> IResult<&str, Instruction, VerboseError<&str>> {
+    let (text, _) = tag("(")(text)?;
+    let (rest, label) = take_while1(|ch| ch != ')' && ch != '\n')(text)?;
+    let (rest, _) = tag(")")(rest)?;
+    let (rest, _) = opt(tag("\n"))(rest)?;
+    match Location::try_from(label) {
+        Ok(Location::Label(label)) => Ok((rest, Instruction::Label(label))),
+        _ => Err(nom::Err::Failure(VerboseError::from_error_kind(
+            text,
+            ErrorKind::Verify,
+        ))), // TODO fix this error
+    }
+}
+
 fn parse_c(text: &str) -> IResult<&str, Instruction, VerboseError<&str>> {
-    let (text, dest) = parse_dest(text)?;
-    let (text, computation) = parse_computation(text)?;
-    let (text, jmp) = parse_jmp(text)?;
+    // the destination is optional, so only look for one if there is an = before the jump
+    let line = text.split('\n').next().unwrap_or("");
+    let (text, dest) = if line.split(';').next().unwrap_or("").contains('=') {
+        parse_dest(text)?
+    } else {
+        (text, vec![])
+    };
+    let (rest, computation) = parse_computation(text)?;
+    if computation.bits().is_none() {
+        return Err(nom::Err::Error(VerboseError::from_error_kind(
+            text,
+            ErrorKind::Verify,
+        ))); // TODO fix this error
+    }
+    let (rest, jmp) = opt(parse_jmp)(rest)?;
 
-    Ok((text, Instruction::C(dest, computation, jmp)))
+    Ok((
+        rest,
+        Instruction::C(dest, computation, jmp.unwrap_or(Jump::None)),
+    ))
 }
 
 fn parse_instruction(text: &str) -> IResult<&str, Instruction, VerboseError<&str>> {
-    let (text, instr) = alt((parse_a, parse_c))(text)?;
+    let (text, instr) = alt((
+        parse_label,
+        map(parse_var, Instruction::Macro),
+        map(parse_macro, Instruction::Macro),
+        parse_a,
+        parse_c,
+    ))(text)?;
     Ok((text, instr))
 }
 
-pub fn parse(_asm: String) -> Vec<Instruction> {
-    vec![Instruction::A(Location::Address(0))]
+/// Strips comments and, outside of directives, all whitespace from a line.
+fn clean(line: &str) -> String {
+    let line = match line.find("//") {
+        Some(i) => &line[..i],
+        None => line,
+    }
+    .trim();
+    if line.starts_with('#') {
+        line.to_string()
+    } else {
+        line.chars().filter(|ch| !ch.is_whitespace()).collect()
+    }
+}
+
+/// Parses a single line, giving `None` for blank lines and comments.
+pub fn parse_line(line: &str) -> Result<Option<Instruction>, String> {
+    let line = clean(line);
+    if line.is_empty() {
+        return Ok(None);
+    }
+    match parse_instruction(&line) {
+        Ok(("", instruction)) => Ok(Some(instruction)),
+        _ => Err(format!("Could not parse {:?}", line)),
+    }
+}
+
+/// Parses a whole program, one instruction per line.  Blank lines and comments are skipped.
+#[cfg(test)]
+pub fn parse(asm: String) -> Vec<Instruction> {
+    asm.split('\n')
+        .enumerate()
+        .filter_map(|(number, line)| {
+            parse_line(line).unwrap_or_else(|e| panic!("Line {}: {}", number + 1, e))
+        })
+        .collect()
+}
+
+/// Parses preprocessed lines, keeping the line each instruction came from.
+pub fn parse_lines(lines: Vec<Line>) -> Vec<(Instruction, Line)> {
+    try_parse_lines(lines).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Like `parse_lines`, but gives the first line which doesn't parse as an error.
+pub fn try_parse_lines(lines: Vec<Line>) -> Result<Vec<(Instruction, Line)>, String> {
+    lines
+        .into_iter()
+        .filter_map(|line| match parse_line(&line.text) {
+            Ok(instruction) => instruction.map(|x| Ok((x, line))),
+            Err(e) => Some(Err(format!("{}:{}: {}", line.file, line.number, e))),
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::parser::{parse_a, parse_c, parse_computation, parse_dest, parse_jmp, parse_macro};
+    use crate::parser::{
+        parse, parse_a, parse_c, parse_computation, parse_dest, parse_jmp, parse_label, parse_macro,
+    };
     use crate::types::*;
     use nom::error::{ErrorKind, ParseError, VerboseError};
 
@@ -141,6 +248,37 @@ mod tests {
             parse_a("@test\n\n\n"),
             Ok(("\n\n", Instruction::A(Location::Label("test".into()))))
         );
+
+        assert_eq!(
+            parse_a("@0x4000"),
+            Ok(("", Instruction::A(Location::Address(0x4000))))
+        );
+        assert_eq!(
+            parse_a("@0b101"),
+            Ok(("", Instruction::A(Location::Address(5))))
+        );
+        assert_eq!(
+            parse_a("@'A'"),
+            Ok(("", Instruction::A(Location::Address(65))))
+        );
+        assert_eq!(
+            parse_a("@32767"),
+            Ok(("", Instruction::A(Location::Address(32767))))
+        );
+        assert_eq!(
+            parse_a("@32768"),
+            Err(nom::Err::Failure(VerboseError::from_error_kind(
+                "32768",
+                ErrorKind::Verify,
+            )))
+        );
+        assert_eq!(
+            parse_a("@-1"),
+            Err(nom::Err::Failure(VerboseError::from_error_kind(
+                "-1",
+                ErrorKind::Verify,
+            )))
+        );
     }
 
     #[test]
@@ -431,14 +569,102 @@ mod tests {
             Ok(("", Macro::Call("func1".into())))
         );
 
-        assert_eq!(
-            parse_macro("#ret\n"),
-            Ok(("", Macro::Return))
-        );
+        assert_eq!(parse_macro("#ret\n"), Ok(("", Macro::Return)));
 
         assert_eq!(
             parse_macro("#include file1\n"),
             Ok(("", Macro::Include("file1".into())))
         );
     }
+
+    #[test]
+    fn parses_c_without_dest_or_jump() {
+        assert_eq!(
+            parse_c("0;JMP"),
+            Ok((
+                "",
+                Instruction::C(
+                    vec![],
+                    Computation::Computation(Source::Zero, Source::None, Operation::None),
+                    Jump::JMP
+                )
+            ))
+        );
+        assert_eq!(
+            parse_c("D;JGT"),
+            Ok((
+                "",
+                Instruction::C(
+                    vec![],
+                    Computation::Computation(
+                        Source::Register(Register::D),
+                        Source::None,
+                        Operation::None
+                    ),
+                    Jump::JGT
+                )
+            ))
+        );
+        assert_eq!(
+            parse_c("AM=M-1"),
+            Ok((
+                "",
+                Instruction::C(
+                    vec![Register::A, Register::M],
+                    Computation::Computation(
+                        Source::Register(Register::M),
+                        Source::One,
+                        Operation::Negative
+                    ),
+                    Jump::None
+                )
+            ))
+        );
+        assert!(parse_c("D=A+M").is_err());
+        assert!(parse_c("D=X").is_err());
+    }
+
+    #[test]
+    fn parses_label() {
+        assert_eq!(
+            parse_label("(LOOP)"),
+            Ok(("", Instruction::Label("LOOP".into())))
+        );
+        assert_eq!(
+            parse_label("(sys.init$ret.1)\n"),
+            Ok(("", Instruction::Label("sys.init$ret.1".into())))
+        );
+        assert!(parse_label("(1)").is_err());
+        assert!(parse_label("()").is_err());
+    }
+
+    #[test]
+    fn parses_program() {
+        assert_eq!(
+            parse("// a comment\n(LOOP)\n  @LOOP // jump back\n\n  0 ; JMP\n#call f\n".to_string()),
+            vec![
+                Instruction::Label("LOOP".into()),
+                Instruction::A(Location::Label("LOOP".into())),
+                Instruction::C(
+                    vec![],
+                    Computation::Computation(Source::Zero, Source::None, Operation::None),
+                    Jump::JMP
+                ),
+                Instruction::Macro(Macro::Call("f".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn formats_instructions() {
+        let program = "@16384\n(LOOP)\nM=-1\nAM=M-1\nD=D-A;JGT\nD;JEQ\n0;JMP\nD=!M\n#call f\n#ret";
+        assert_eq!(
+            parse(program.to_string())
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+                .join("\n"),
+            program
+        );
+    }
 }
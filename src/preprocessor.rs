@@ -1,83 +1,1471 @@
+use crate::assembler::{FIRST_VARIABLE, STACK_POINTER as STACK_ADDRESS};
+use crate::expression::{is_symbol_char, Expression};
+use crate::parser::parse_line;
+use crate::stdlib::{self, library_path};
+use crate::types::{parse_word, Instruction, Line, Variable, MAX_ADDRESS};
 use std::fs;
 use std::string::ToString;
 
-pub trait Preprocessable {
-    fn preprocess(self) -> Vec<String>;
+/// Where `#call` keeps its stack pointer.
+const STACK_POINTER: &str = "@16383";
+
+/// The name of the variable `#stack` reserves the call stack with.
+pub const CALL_STACK: &str = "CALL$STACK";
+
+pub trait Preprocessable: Sized {
+    /// Expands directives, giving the first error along with where it is.
+    fn try_preprocess(self) -> Result<Self, String>;
+
+    fn preprocess(self) -> Self {
+        self.try_preprocess().unwrap_or_else(|e| panic!("{}", e))
+    }
 }
 
-impl Preprocessable for Vec<String> {
-    fn preprocess(self) -> Vec<String> {
-        const STACK_POINTER: &str = "@16383";
-
-        fn process_line(included_files: &mut Vec<String>, line: &str) -> String {
-            return match line {
-                l if l.to_lowercase().starts_with("#call") => {
-                    let label = l.split(" ").last().unwrap();
-                    let increment_sp: String = [STACK_POINTER, "M=M-1"].join("\n");
-                    let store: String = [
-                        "// store CURRENT ADDRESS",
+/// Cuts a trailing `//` comment off a directive, leaving any `//` in a string or character.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, ch) in line.char_indices() {
+        match (quote, ch) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(ch),
+            (None, '/') if line[i..].starts_with("//") => return line[..i].trim_end(),
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Whether a line's first word is a directive, ignoring case, so `#database` isn't `#data`.
+pub(crate) fn is_directive(line: &str, directive: &str) -> bool {
+    line.split_whitespace()
+        .next()
+        .is_some_and(|x| x.eq_ignore_ascii_case(directive))
+}
+
+/// A `#data` or `#string` table which is written into RAM before the program starts.
+///
+/// The table is declared as an array with `#var LABEL[N]`, so `@LABEL` is the address of its first
+/// word and `@LABEL+1` the next.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct DataTable {
+    label: String,
+    values: Vec<u16>,
+}
+
+impl DataTable {
+    /// Parses `#data LABEL 1, 2, 3`.
+    fn from_data(line: &str) -> Result<DataTable, String> {
+        let args = line["#data".len()..].trim();
+        let (label, values) = args.split_at(args.find(char::is_whitespace).unwrap_or(args.len()));
+        if label.is_empty() {
+            return Err(format!("Missing label in data directive: {:?}", line));
+        }
+        Ok(DataTable {
+            label: label.to_string(),
+            values: values
+                .split(',')
+                .map(|x| x.trim())
+                .filter(|x| !x.is_empty())
+                .map(parse_word)
+                .collect::<Result<Vec<u16>, String>>()?,
+        })
+    }
+
+    /// Parses `#string LABEL "hello"`.  The characters are stored one per word followed by a
+    /// terminating zero.
+    fn from_string(line: &str) -> Result<DataTable, String> {
+        let args = line["#string".len()..].trim();
+        let (label, text) = args.split_at(args.find(char::is_whitespace).unwrap_or(args.len()));
+        let text = text.trim();
+        if label.is_empty() || text.len() < 2 || !text.starts_with('"') || !text.ends_with('"') {
+            return Err(format!("Could not parse string directive: {:?}", line));
+        }
+        Ok(DataTable {
+            label: label.to_string(),
+            values: text[1..text.len() - 1]
+                .chars()
+                .map(|x| x as u16)
+                .chain(std::iter::once(0))
+                .collect(),
+        })
+    }
+
+    fn initialise(&self) -> String {
+        let mut output = vec![format!("// INITIALISE {}", self.label)];
+        if !self.values.is_empty() {
+            output.push(format!("#var {}[{}]", self.label, self.values.len()));
+        }
+        for (index, value) in self.values.iter().enumerate() {
+            let symbol = match index {
+                0 => format!("@{}", self.label),
+                i => format!("@{}+{}", self.label, i),
+            };
+            match value {
+                0 | 1 | 0xFFFF => output.extend(vec![symbol, format!("M={}", *value as i16)]),
+                v => {
+                    output.extend(load("D", *v));
+                    output.extend(vec![symbol, "M=D".to_string()]);
+                }
+            }
+        }
+        output.join("\n")
+    }
+}
+
+/// Parses `#load D, -1` into the registers to load and the 16-bit value to load into them.
+fn parse_load(line: &str) -> Result<(String, u16), String> {
+    let args = &line["#load".len()..];
+    let (registers, value) = match args.find(',') {
+        Some(i) => (args[..i].trim().to_uppercase(), args[i + 1..].trim()),
+        None => return Err(format!("Could not parse load directive: {:?}", line)),
+    };
+    if registers.is_empty()
+        || !registers.chars().all(|ch| ch == 'A' || ch == 'D')
+        || registers.matches('A').count() > 1
+        || registers.matches('D').count() > 1
+    {
+        return Err(format!("Can only load into A and D, not {:?}", registers));
+    }
+    Ok((registers, parse_word(value)?))
+}
+
+/// Parses `#define NAME expression` into the name and the text to put in its place, with any
+/// earlier defines in the expression substituted first.  A constant expression is worked out
+/// there and then, so `@NAME` assembles to a plain address.
+fn parse_define(line: &str, defines: &[(String, String)]) -> Result<(String, String), String> {
+    let args = line["#define".len()..].trim();
+    let (name, body) = args.split_at(args.find(char::is_whitespace).unwrap_or(args.len()));
+    if body.trim().is_empty() {
+        return Err(format!("Missing argument in directive: {:?}", line));
+    }
+    if !name.chars().all(is_symbol_char) || name.starts_with(|x: char| x.is_ascii_digit()) {
+        return Err(format!("Bad name {} in #define", name));
+    }
+    let expression = Expression::parse(&substitute(body, defines))?;
+    let text = match expression {
+        Expression::Symbol(x) => x,
+        _ if expression.symbols().is_empty() => match expression.evaluate(&|_| None)? {
+            x if x < 0 => format!("({})", x),
+            x => x.to_string(),
+        },
+        _ => format!("({})", expression),
+    };
+    Ok((name.to_string(), text))
+}
+
+/// Replaces every defined name in an operand with its definition.
+fn substitute(text: &str, defines: &[(String, String)]) -> String {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(ch) = rest.chars().next() {
+        let length = if ch == '\'' {
+            // a character literal, which could be a letter which is also a name
+            rest.char_indices()
+                .nth(2)
+                .map_or(rest.len(), |(i, x)| i + x.len_utf8())
+        } else if is_symbol_char(ch) {
+            rest.find(|x| !is_symbol_char(x)).unwrap_or(rest.len())
+        } else {
+            ch.len_utf8()
+        };
+        let word = &rest[..length];
+        match defines.iter().rev().find(|(name, _)| name == word) {
+            Some((_, text)) => output += text,
+            None => output += word,
+        }
+        rest = &rest[length..];
+    }
+    output
+}
+
+/// A layout declared with `#struct NAME FIELD FIELD[SIZE] ...`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Struct {
+    name: String,
+    /// Every field's name and its offset from the start.
+    fields: Vec<(String, u16)>,
+    size: u16,
+}
+
+/// Works out a constant expression, such as the size of an array, after substituting defines.
+fn constant(text: &str, defines: &[(String, String)]) -> Result<i64, String> {
+    Expression::parse(&substitute(text, defines))?.evaluate(&|_| None)
+}
+
+/// Splits `NAME[SIZE]` into the name and the size, which is `1` if there isn't one.
+fn split_size(text: &str) -> Result<(&str, &str), String> {
+    match text.split_once('[') {
+        Some((name, size)) => match size.strip_suffix(']') {
+            Some(size) => Ok((name, size)),
+            None => Err(format!("Expected ] after {}", text)),
+        },
+        None => Ok((text, "1")),
+    }
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || name.starts_with(|x: char| x.is_ascii_digit())
+        || !name.chars().all(is_symbol_char)
+    {
+        return Err(format!("Bad name {:?}", name));
+    }
+    Ok(())
+}
+
+impl Struct {
+    fn parse(line: &str, defines: &[(String, String)]) -> Result<Struct, String> {
+        let mut words = line["#struct".len()..]
+            .split(|x: char| x.is_whitespace() || x == ',')
+            .filter(|x| !x.is_empty());
+        let name = words
+            .next()
+            .ok_or_else(|| format!("Missing argument in directive: {:?}", line))?;
+        check_name(name)?;
+        let mut fields: Vec<(String, u16)> = vec![];
+        let mut size = 0;
+        for field in words {
+            let (field, length) = split_size(field)?;
+            check_name(field)?;
+            if fields.iter().any(|(x, _)| x == field) {
+                return Err(format!("{} has two fields called {}", name, field));
+            }
+            fields.push((field.to_string(), size as u16));
+            size += match constant(length, defines)? {
+                x if x < 1 => return Err(format!("{}.{} can't be {} words long", name, field, x)),
+                x => x,
+            };
+            if size > MAX_ADDRESS as i64 + 1 {
+                return Err(format!("{} is too big for RAM", name));
+            }
+        }
+        if fields.is_empty() {
+            return Err(format!("{} has no fields", name));
+        }
+        Ok(Struct {
+            name: name.to_string(),
+            fields,
+            size: size as u16,
+        })
+    }
+
+    /// The defines which give the offset of every field, e.g. `Point.y`, and the size of the
+    /// whole layout as its name.
+    fn defines(&self) -> Vec<(String, String)> {
+        let fields = self
+            .fields
+            .iter()
+            .map(|(field, offset)| (format!("{}.{}", self.name, field), offset.to_string()));
+        fields
+            .chain(std::iter::once((self.name.clone(), self.size.to_string())))
+            .collect()
+    }
+}
+
+/// Parses `#var NAME`, `#var NAME[SIZE]`, `#var NAME STRUCT` or `#var NAME STRUCT[COUNT]`, any of
+/// them followed by `@ ADDRESS`.  Gives the variable along with defines which put the address of
+/// every field of a struct in place of `NAME.FIELD`.
+fn parse_var(
+    line: &str,
+    defines: &[(String, String)],
+    structs: &[Struct],
+) -> Result<(Variable, Vec<(String, String)>), String> {
+    let args = line["#var".len()..].trim();
+    let (declaration, address) = match args.split_once('@') {
+        Some((declaration, address)) => (declaration, Some(substitute(address, defines))),
+        None => (args, None),
+    };
+    let mut words = declaration.split_whitespace();
+    let (name, size, fields) = match (words.next(), words.next(), words.next()) {
+        (Some(name), None, _) => {
+            let (name, size) = split_size(name)?;
+            (name, substitute(size, defines), vec![])
+        }
+        (Some(name), Some(kind), None) => {
+            let (kind, count) = split_size(kind)?;
+            let layout = structs
+                .iter()
+                .find(|x| x.name == kind)
+                .ok_or_else(|| format!("Unknown struct {}", kind))?;
+            let fields = layout
+                .fields
+                .iter()
+                .map(|(field, offset)| {
+                    let address = match offset {
+                        0 => name.to_string(),
+                        offset => format!("({}+{})", name, offset),
+                    };
+                    (format!("{}.{}", name, field), address)
+                })
+                .collect();
+            let size = format!("{}*({})", layout.size, substitute(count, defines));
+            (name, size, fields)
+        }
+        _ => {
+            return Err(format!(
+                "Expected #var NAME[SIZE] @ ADDRESS, not {:?}",
+                line
+            ))
+        }
+    };
+    let text = match address {
+        Some(address) => format!("{}[{}] @ {}", name, size, address),
+        None => format!("{}[{}]", name, size),
+    };
+    Ok((Variable::parse(&text)?, fields))
+}
+
+/// Parses `#stack DEPTH` into a variable which reserves `DEPTH` words below the stack pointer,
+/// along with the stack pointer itself, so nothing else is given them.
+fn parse_stack(line: &str, defines: &[(String, String)]) -> Result<Variable, String> {
+    let depth = constant(line["#stack".len()..].trim(), defines)?;
+    let most = (STACK_ADDRESS - FIRST_VARIABLE) as i64;
+    if !(1..=most).contains(&depth) {
+        return Err(format!(
+            "Can't reserve {} words for the call stack, only 1 to {}",
+            depth, most
+        ));
+    }
+    Ok(Variable {
+        name: CALL_STACK.to_string(),
+        size: depth as u16 + 1,
+        address: Some(STACK_ADDRESS - depth as u16),
+    })
+}
+
+/// The most lines `#rep` and `#for` can unroll a file into, so a mistake in a count is an error
+/// rather than using up all the memory.
+const MAX_UNROLLED_LINES: usize = 1 << 20;
+
+/// The header of a `#rep COUNT` or `#for NAME in START..END` block.
+#[derive(Debug, PartialEq, Eq)]
+enum Repeat {
+    Rep(Expression),
+    For(String, Expression, Expression),
+}
+
+impl Repeat {
+    fn parse(line: &str, defines: &[(String, String)]) -> Result<Repeat, String> {
+        let line = line.trim();
+        if is_directive(line, "#rep") {
+            return match line["#rep".len()..].trim() {
+                "" => Err(format!("Missing argument in directive: {:?}", line)),
+                count => Ok(Repeat::Rep(Expression::parse(&substitute(count, defines))?)),
+            };
+        }
+        let error = || format!("Expected #for NAME in START..END, not {:?}", line);
+        let (name, range) = line["#for".len()..].split_once(" in ").ok_or_else(error)?;
+        let (start, end) = range.split_once("..").ok_or_else(error)?;
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(is_symbol_char) {
+            return Err(error());
+        }
+        Ok(Repeat::For(
+            name.to_string(),
+            Expression::parse(&substitute(start, defines))?,
+            Expression::parse(&substitute(end, defines))?,
+        ))
+    }
+}
+
+fn is_repeat_start(line: &str) -> bool {
+    let line = line.trim_start();
+    is_directive(line, "#rep") || is_directive(line, "#for")
+}
+
+fn is_repeat_end(line: &str) -> bool {
+    let line = line.trim_start();
+    is_directive(line, "#endrep") || is_directive(line, "#endfor")
+}
+
+/// Unrolls the `#rep` or `#for` block at the start of `lines`, giving the number of lines it took
+/// up, what to put in their place and how many lines it counts as against `MAX_UNROLLED_LINES`.
+/// Every iteration counts as at least one line, so loops with nothing in them still use it up.
+/// Blocks inside it are left for the caller to unroll in turn, once the loop variable has been
+/// put in place in their headers.
+fn unroll(
+    lines: &[Line],
+    defines: &[(String, String)],
+) -> Result<(usize, Vec<Line>, usize), String> {
+    let header = lines[0].text.trim();
+    let (kind, end) = if is_directive(header, "#rep") {
+        ("#rep", "#endrep")
+    } else {
+        ("#for", "#endfor")
+    };
+    let mut depth = 0;
+    let mut length = None;
+    for (i, line) in lines.iter().enumerate().skip(1) {
+        if is_repeat_start(&line.text) {
+            depth += 1;
+        } else if is_repeat_end(&line.text) && depth > 0 {
+            depth -= 1;
+        } else if is_repeat_end(&line.text) {
+            if !is_directive(line.text.trim_start(), end) {
+                return Err(format!("{} is ended by {}", kind, line.text.trim()));
+            }
+            length = Some(i + 1);
+            break;
+        }
+    }
+    let length = length.ok_or_else(|| format!("{} without {}", kind, end))?;
+    let body = &lines[1..length - 1];
+
+    let (name, range) = match Repeat::parse(header, defines)? {
+        Repeat::Rep(count) => match count.evaluate(&|_| None)? {
+            count if count < 0 => return Err(format!("Can't repeat {} times", count)),
+            count => (None, 0..count),
+        },
+        Repeat::For(name, start, end) => (
+            Some(name),
+            start.evaluate(&|_| None)?..end.evaluate(&|_| None)?,
+        ),
+    };
+    // the range can span more than an i64 can hold
+    let iterations = (range.end as i128 - range.start as i128).max(0) as u128;
+    let size = iterations * body.len().max(1) as u128;
+    if size > MAX_UNROLLED_LINES as u128 {
+        return Err(format!(
+            "{} unrolls to more than {} lines",
+            kind, MAX_UNROLLED_LINES
+        ));
+    }
+
+    let mut unrolled = Vec::with_capacity(iterations as usize * body.len());
+    for value in range {
+        for line in body {
+            let text = match &name {
+                Some(name) => put_loop_variable(&line.text, name, value),
+                None => line.text.clone(),
+            };
+            unrolled.push(Line {
+                text,
+                ..line.clone()
+            });
+        }
+    }
+    Ok((length, unrolled, size as usize))
+}
+
+/// Puts the value of a `#for` loop's variable in place of `{NAME}` anywhere in a line, and in place
+/// of `NAME` in an A-instruction or the expressions of a directive.
+fn put_loop_variable(line: &str, name: &str, value: i64) -> String {
+    let text = if value < 0 {
+        format!("({})", value)
+    } else {
+        value.to_string()
+    };
+    let line = line.replace(&format!("{{{}}}", name), &value.to_string());
+    let variable = [(name.to_string(), text)];
+    let trimmed = line.trim_start();
+    let indent = line.len() - trimmed.len();
+    let expressions = if trimmed.starts_with('@') {
+        Some(indent + 1)
+    } else if is_directive(trimmed, "#rep") {
+        Some(indent + "#rep".len())
+    } else if is_directive(trimmed, "#for") {
+        // the range is two expressions, and `..` could otherwise be part of a symbol
+        return match line
+            .split_once(" in ")
+            .and_then(|(x, range)| Some((x, range.split_once("..")?)))
+        {
+            Some((header, (start, end))) => format!(
+                "{} in {}..{}",
+                header,
+                substitute(start, &variable),
+                substitute(end, &variable)
+            ),
+            None => line,
+        };
+    } else if is_directive(trimmed, "#define") {
+        // past the name being defined
+        let args = indent + "#define".len();
+        let name = line.len() - line[args..].trim_start().len();
+        line[name..].find(char::is_whitespace).map(|x| name + x)
+    } else {
+        None
+    };
+    match expressions {
+        Some(i) => line[..i].to_string() + &substitute(&line[i..], &variable),
+        None => line,
+    }
+}
+
+/// Checks a directive without expanding it.  Gives the name of the table a `#data` or `#string`
+/// directive defines, the constant a `#define` does, the variable of a `#for` loop or `#var`, or
+/// the layout of a `#struct`.
+pub fn check_directive(line: &str) -> Result<Option<String>, String> {
+    let line = strip_comment(line.trim());
+    let mut words = line.split_whitespace();
+    match words.next().unwrap_or("").to_lowercase().as_ref() {
+        "#call" | "#include" | "#export" | "#extern" if words.next().is_none() => {
+            Err(format!("Missing argument in directive: {:?}", line))
+        }
+        "#call" | "#ret" | "#include" | "#export" | "#extern" => Ok(None),
+        "#load" => parse_load(line).map(|_| None),
+        "#data" => DataTable::from_data(line).map(|x| Some(x.label)),
+        "#string" => DataTable::from_string(line).map(|x| Some(x.label)),
+        "#define" => parse_define(line, &[]).map(|(name, _)| Some(name)),
+        "#var" => {
+            let name = line["#var".len()..].split(['@', '[']).next().unwrap_or("");
+            let name = name.split_whitespace().next().unwrap_or("");
+            check_name(name).map(|_| Some(name.to_string()))
+        }
+        "#struct" => {
+            let name = line["#struct".len()..]
+                .split([' ', '\t', ','])
+                .find(|x| !x.is_empty());
+            let name = name.unwrap_or("");
+            check_name(name).map(|_| Some(name.to_string()))
+        }
+        "#stack" => Expression::parse(line["#stack".len()..].trim()).map(|_| None),
+        "#rep" | "#for" => Repeat::parse(line, &[]).map(|x| match x {
+            Repeat::Rep(_) => None,
+            Repeat::For(name, _, _) => Some(name),
+        }),
+        "#endrep" | "#endfor" => Ok(None),
+        "#if" | "#while" => Condition::parse(line, &[]).map(|_| None),
+        "#else" | "#endif" | "#endwhile" | "#break" | "#continue" => Ok(None),
+        directive => Err(format!("Unknown directive {}", directive)),
+    }
+}
+
+/// Expands to the shortest sequence which leaves the 16-bit value in the given registers.  Only A
+/// and D can be loaded, as M would need A to hold its address.
+fn load(registers: &str, word: u16) -> Vec<String> {
+    match word {
+        0 | 1 | 0xFFFF => vec![format!("{}={}", registers, word as i16)],
+        w if w <= MAX_ADDRESS && registers == "A" => vec![format!("@{}", w)],
+        w if w <= MAX_ADDRESS => vec![format!("@{}", w), format!("{}=A", registers)],
+        // the top bit is set so !w fits in an A-instruction
+        w => vec![format!("@{}", !w), format!("{}=!A", registers)],
+    }
+}
+
+/// The condition of an `#if` or `#while`, such as `D>0`, `@n M!=0` or just `D-1`.
+#[derive(Debug, PartialEq, Eq)]
+struct Condition {
+    /// Loaded into A first, for conditions which read M.
+    address: Option<String>,
+    computation: String,
+    /// The jump to take when the condition is false.
+    jump: &'static str,
+}
+
+impl Condition {
+    /// Parses the condition of a directive.  Comparisons are only against 0 as that's all a jump
+    /// can do.
+    fn parse(line: &str, defines: &[(String, String)]) -> Result<Condition, String> {
+        const COMPARISONS: [(&str, &str); 6] = [
+            ("!=", "JEQ"),
+            ("==", "JNE"),
+            (">=", "JLT"),
+            ("<=", "JGT"),
+            (">", "JLE"),
+            ("<", "JGE"),
+        ];
+        let mut words = line.split_whitespace().skip(1).peekable();
+        let address = match words.peek() {
+            Some(x) if x.starts_with('@') => {
+                let address = substitute(words.next().unwrap_or_default(), defines);
+                match parse_line(&address) {
+                    Ok(Some(Instruction::A(_))) => Some(address),
+                    _ => return Err(format!("Bad address {} in condition", address)),
+                }
+            }
+            _ => None,
+        };
+        let condition = words.collect::<String>();
+        if condition.is_empty() {
+            return Err(format!("Missing argument in directive: {:?}", line));
+        }
+        let (computation, jump) = match COMPARISONS.iter().find(|(x, _)| condition.contains(x)) {
+            Some((comparison, jump)) => match condition.split_once(comparison) {
+                Some((computation, "0")) => (computation, *jump),
+                _ => return Err(format!("Can only compare with 0 in {}", condition)),
+            },
+            // anything other than 0 is true
+            None => (condition.as_str(), "JEQ"),
+        };
+        // `D=0` would otherwise be taken as storing 0 in D
+        if computation.contains(['=', ';']) {
+            return Err(format!("Bad condition {}", condition));
+        }
+        match parse_line(&format!("{};{}", computation, jump)) {
+            Ok(Some(Instruction::C(dest, ..))) if dest.is_empty() => Ok(Condition {
+                address,
+                computation: computation.to_string(),
+                jump,
+            }),
+            _ => Err(format!("Bad condition {}", condition)),
+        }
+    }
+
+    /// Jumps to the label if the condition is false.  A has to hold the label to jump, so a
+    /// condition using A or M is worked out into D first.
+    fn jump_unless(&self, label: &str) -> String {
+        let load = match &self.address {
+            Some(address) => format!("{}\n", address),
+            None => String::new(),
+        };
+        if self.computation.contains(['A', 'M']) {
+            format!(
+                "{}D={}\n@{}\nD;{}",
+                load, self.computation, label, self.jump
+            )
+        } else {
+            format!("{}@{}\n{};{}", load, label, self.computation, self.jump)
+        }
+    }
+}
+
+const BLOCK_DIRECTIVES: [&str; 7] = [
+    "#if",
+    "#else",
+    "#endif",
+    "#while",
+    "#endwhile",
+    "#break",
+    "#continue",
+];
+
+fn is_block_directive(line: &str) -> bool {
+    let word = line.split_whitespace().next().unwrap_or("");
+    BLOCK_DIRECTIVES
+        .iter()
+        .any(|x| x.eq_ignore_ascii_case(word))
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum BlockKind {
+    If,
+    Else,
+    While,
+}
+
+impl BlockKind {
+    fn start(self) -> &'static str {
+        match self {
+            BlockKind::If | BlockKind::Else => "#if",
+            BlockKind::While => "#while",
+        }
+    }
+
+    fn end(self) -> &'static str {
+        match self {
+            BlockKind::If | BlockKind::Else => "#endif",
+            BlockKind::While => "#endwhile",
+        }
+    }
+}
+
+/// An `#if` or `#while` block which hasn't been closed yet.
+#[derive(Debug)]
+struct Block {
+    kind: BlockKind,
+    /// Numbers the block's labels, e.g. `IF$3$ELSE`.
+    number: usize,
+    /// Where the block starts, for errors about it.
+    line: Line,
+}
+
+/// Everything the preprocessor keeps track of as it goes through the lines.
+#[derive(Debug, Default)]
+struct State {
+    included_files: Vec<String>,
+    tables: Vec<(DataTable, Line)>,
+    defines: Vec<(String, String)>,
+    structs: Vec<Struct>,
+    calls: usize,
+    /// Whether `#stack` has reserved the call stack.
+    stack: bool,
+    /// The blocks the current line is inside, innermost last.
+    blocks: Vec<Block>,
+    block_count: usize,
+    /// How many lines `#rep` and `#for` have unrolled so far, as counted by `unroll`.
+    unrolled: usize,
+}
+
+impl State {
+    /// Lowers `#if`, `#while` and the directives which go inside them into labels and jumps.  A
+    /// `#while` tests its condition at the top, so `#continue` goes back to the test.
+    fn lower_block(&mut self, directive: &str, line: &Line) -> Result<String, String> {
+        let word = directive.split_whitespace().next().unwrap_or("");
+        let word = word.to_lowercase();
+        let block = match word.as_str() {
+            "#if" | "#while" => {
+                let condition = Condition::parse(directive, &self.defines)?;
+                // after the first time round A holds the loop's label, not what the code before
+                // the loop left in it
+                if word == "#while"
+                    && condition.address.is_none()
+                    && condition.computation.contains(['A', 'M'])
+                {
+                    return Err(format!(
+                        "#while conditions using A or M need an @address, as in #while @n {}",
+                        directive[word.len()..].trim()
+                    ));
+                }
+                let number = self.block_count;
+                self.block_count += 1;
+                let (kind, text) = if word == "#if" {
+                    (
+                        BlockKind::If,
+                        condition.jump_unless(&format!("IF${}$ELSE", number)),
+                    )
+                } else {
+                    let test = condition.jump_unless(&format!("WHILE${}$END", number));
+                    (BlockKind::While, format!("(WHILE${})\n{}", number, test))
+                };
+                self.blocks.push(Block {
+                    kind,
+                    number,
+                    line: line.clone(),
+                });
+                return Ok(text);
+            }
+            "#break" | "#continue" => {
+                let block = self
+                    .blocks
+                    .iter()
+                    .rfind(|x| x.kind == BlockKind::While)
+                    .ok_or_else(|| format!("{} outside a #while", word))?;
+                let label = if word == "#break" { "$END" } else { "" };
+                return Ok(format!("@WHILE${}{}\n0;JMP", block.number, label));
+            }
+            _ => self
+                .blocks
+                .pop()
+                .ok_or_else(|| format!("{} without a #if or #while", word))?,
+        };
+        let number = block.number;
+        match (word.as_str(), block.kind) {
+            ("#else", BlockKind::If) => {
+                self.blocks.push(Block {
+                    kind: BlockKind::Else,
+                    ..block
+                });
+                Ok(format!("@IF${}$END\n0;JMP\n(IF${}$ELSE)", number, number))
+            }
+            ("#else", BlockKind::Else) => Err(format!(
+                "The #if on line {} already has an #else",
+                block.line.number
+            )),
+            ("#endif", BlockKind::If) => Ok(format!("(IF${}$ELSE)", number)),
+            ("#endif", BlockKind::Else) => Ok(format!("(IF${}$END)", number)),
+            ("#endwhile", BlockKind::While) => {
+                Ok(format!("@WHILE${}\n0;JMP\n(WHILE${}$END)", number, number))
+            }
+            (_, kind) => Err(format!(
+                "{} doesn't close the {} on line {}",
+                word,
+                kind.start(),
+                block.line.number
+            )),
+        }
+    }
+}
+
+impl Preprocessable for Vec<Line> {
+    fn try_preprocess(self) -> Result<Vec<Line>, String> {
+        // #call jumps back to a label of this followed by the number of the call
+        const RETURN_LABEL: &str = "RETURN$";
+
+        fn process_line(state: &mut State, line: &Line) -> Result<String, String> {
+            Ok(match line.text.trim_start() {
+                l if is_directive(l, "#call") => {
+                    let label = argument(l)?;
+                    let return_label = format!("{}{}", RETURN_LABEL, state.calls);
+                    state.calls += 1;
+                    let push: String = [
+                        "// STORE RETURN ADDRESS",
+                        &["@", &return_label].join(""),
                         "D=A",
                         STACK_POINTER,
                         "A=M",
                         "M=D",
-                        &increment_sp,
+                        STACK_POINTER,
+                        "M=M-1",
                         "// STORED",
                     ]
                     .join("\n");
                     [
                         &["// JUMPING TO LABEL ", label].join("") as &str,
-                        &store,
+                        &push,
                         &["@", label].join("") as &str,
                         "0;JMP",
+                        &["(", &return_label, ")"].join(""),
                     ]
                     .join("\n")
                 }
-                l if l.to_lowercase().starts_with("#ret") => {
-                    let decrement_sp: String = [STACK_POINTER, "M=M+1"].join("\n");
-                    [
-                        "// RETURN FROM STORED ADDRESS",
-                        &decrement_sp,
-                        STACK_POINTER,
-                        "A=M",
-                        "A=M",
-                        "D=A",
-                        "@12",
-                        "A=D+A",
-                        "0;JMP",
-                        "// RETURNED",
-                    ]
-                    .join("\n")
+                l if is_directive(l, "#ret") => [
+                    "// RETURN FROM STORED ADDRESS",
+                    STACK_POINTER,
+                    "AM=M+1",
+                    "A=M",
+                    "0;JMP",
+                    "// RETURNED",
+                ]
+                .join("\n"),
+                l if is_directive(l, "#include") => {
+                    let file = argument(l)?.to_string();
+                    if !state.included_files.contains(&file) {
+                        state.included_files.push(file);
+                    }
+                    String::new()
                 }
-                l if l.to_lowercase().starts_with("#include") => {
-                    included_files.push(l.split(" ").last().unwrap().to_string());
+                // only objects use these, which read them back from the line's directive
+                l if is_directive(l, "#export") || is_directive(l, "#extern") => {
+                    argument(l)?;
                     String::new()
                 }
-                _ => line.to_string(),
+                l if is_directive(l, "#load") => {
+                    let (registers, word) = parse_load(l)?;
+                    load(&registers, word).join("\n")
+                }
+                l if is_directive(l, "#data") => {
+                    state.tables.push((DataTable::from_data(l)?, line.clone()));
+                    String::new()
+                }
+                l if is_directive(l, "#string") => {
+                    state
+                        .tables
+                        .push((DataTable::from_string(l)?, line.clone()));
+                    String::new()
+                }
+                l if is_directive(l, "#define") => {
+                    let define = parse_define(l, &state.defines)?;
+                    state.defines.push(define);
+                    String::new()
+                }
+                // the assembler gives declared variables their RAM, so the declaration stays
+                l if is_directive(l, "#var") => {
+                    let (variable, fields) = parse_var(l, &state.defines, &state.structs)?;
+                    state.defines.extend(fields);
+                    format!("#var {}", variable)
+                }
+                l if is_directive(l, "#stack") => {
+                    let stack = parse_stack(l, &state.defines)?;
+                    if state.stack {
+                        return Err("The call stack is reserved more than once".to_string());
+                    }
+                    state.stack = true;
+                    format!("#var {}", stack)
+                }
+                l if is_directive(l, "#struct") => {
+                    let layout = Struct::parse(l, &state.defines)?;
+                    if state.structs.iter().any(|x| x.name == layout.name) {
+                        return Err(format!("Struct {} is declared more than once", layout.name));
+                    }
+                    state.defines.extend(layout.defines());
+                    state.structs.push(layout);
+                    String::new()
+                }
+                l if is_repeat_end(l) => {
+                    return Err(format!("{} without a #rep or #for", l.trim()));
+                }
+                l if is_block_directive(l) => state.lower_block(l, line)?,
+                l if l.starts_with('@') && !state.defines.is_empty() => {
+                    substitute(&line.text, &state.defines)
+                }
+                _ => line.text.clone(),
+            })
+        }
+
+        /// Processes a file's lines onto the end of `body`, unrolling `#rep` and `#for` blocks as
+        /// they come up so they can use everything defined before them.
+        fn process_lines(
+            state: &mut State,
+            lines: &[Line],
+            body: &mut Vec<Line>,
+        ) -> Result<(), String> {
+            let mut i = 0;
+            while let Some(line) = lines.get(i) {
+                if is_repeat_start(&line.text) {
+                    let (length, unrolled, size) =
+                        unroll(&lines[i..], &state.defines).map_err(|e| locate(line, e))?;
+                    state.unrolled += size;
+                    if body.len() + unrolled.len() > MAX_UNROLLED_LINES
+                        || state.unrolled > MAX_UNROLLED_LINES
+                    {
+                        return Err(locate(
+                            line,
+                            format!("Unrolling gives more than {} lines", MAX_UNROLLED_LINES),
+                        ));
+                    }
+                    process_lines(state, &unrolled, body)?;
+                    i += length;
+                    continue;
+                }
+                let expanded = process_line(state, line).map_err(|e| locate(line, e))?;
+                body.extend(expand(line, &expanded));
+                i += 1;
+            }
+            Ok(())
+        }
+
+        /// Processes a whole file, which has to close every block it opens.  Comments are cut off
+        /// the end of directives first, so none of them have to expect one after their arguments.
+        fn process_file(
+            state: &mut State,
+            lines: &[Line],
+            body: &mut Vec<Line>,
+        ) -> Result<(), String> {
+            let lines = lines
+                .iter()
+                .map(|x| {
+                    let text = if x.text.trim_start().starts_with('#') {
+                        strip_comment(&x.text)
+                    } else {
+                        &x.text
+                    };
+                    Line {
+                        text: text.to_string(),
+                        ..x.clone()
+                    }
+                })
+                .collect::<Vec<Line>>();
+            process_lines(state, &lines, body)?;
+            match state.blocks.pop() {
+                Some(block) => Err(locate(
+                    &block.line,
+                    format!("{} without {}", block.kind.start(), block.kind.end()),
+                )),
+                None => Ok(()),
+            }
+        }
+
+        fn argument(line: &str) -> Result<&str, String> {
+            line.split_whitespace()
+                .skip(1)
+                .last()
+                .ok_or_else(|| format!("Missing argument in directive: {:?}", line))
+        }
+
+        /// Points an error at the line it came from.
+        fn locate(line: &Line, e: String) -> String {
+            match (line.file.as_str(), line.number) {
+                ("", 0) => e,
+                ("", number) => format!("Line {}: {}", number, e),
+                (file, number) => format!("{}:{}: {}", file, number, e),
+            }
+        }
+
+        /// Splits the expansion of a directive back into lines which point at the directive.
+        fn expand(line: &Line, text: &str) -> Vec<Line> {
+            let directive = if line.text.trim_start().starts_with('#') {
+                Some(line.text.trim().to_string())
+            } else {
+                line.directive.clone()
             };
+            text.split('\n')
+                .map(|x| Line {
+                    text: x.to_string(),
+                    directive: directive.clone(),
+                    ..line.clone()
+                })
+                .collect()
         }
-        let mut included_files: Vec<String> = Vec::new();
 
-        let mut output = String::new();
-        output += &[STACK_POINTER, "D=A-1", "M=D", "@0\n"].join("\n");
+        let mut state = State::default();
+        let mut body = vec![];
+        process_file(&mut state, &self, &mut body)?;
 
-        output += &self
-            .iter()
-            .map(|x| process_line(&mut included_files, x))
-            .collect::<Vec<String>>()
-            .join("\n");
+        // included files can include more files, which go on the end in turn
+        let mut next = 0;
+        while let Some(i) = state.included_files.get(next).cloned() {
+            next += 1;
+            body.push(Line {
+                text: format!("// INCLUDED FILE {}", i),
+                ..Default::default()
+            });
+            let text = match library_path(&i) {
+                Some(path) => stdlib::get(path)
+                    .ok_or_else(|| format!("No standard library file {}", path))?
+                    .to_string(),
+                None => {
+                    fs::read_to_string(&i).map_err(|_| format!("Could not read file {:?}", i))?
+                }
+            };
+            let lines: Vec<Line> = Line::read(&i, &text)
+                .into_iter()
+                .map(|line| Line {
+                    included: true,
+                    ..line
+                })
+                .collect();
+            process_file(&mut state, &lines, &mut body)?;
+        }
 
-        for i in included_files.clone() {
-            output += &format!("// INCLUDED FILE {}", i);
-            output += &fs::read_to_string(&i)
-                .expect(&format!("Could not read file {:?}", i))
+        // the data tables and the call stack are set up before the user's entry point
+        let mut output = Vec::new();
+        for (table, line) in &state.tables {
+            output.extend(expand(line, &table.initialise()));
+        }
+        output.extend(prologue());
+        output.extend(body);
+        Ok(output)
+    }
+}
+
+/// The code which sets up the call stack, which the preprocessor puts after the data tables are
+/// set up and before the program's own code.
+pub fn prologue() -> Vec<Line> {
+    [STACK_POINTER, "D=A-1", "M=D"]
+        .iter()
+        .map(|x| Line {
+            text: x.to_string(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Splits preprocessed lines into the code which sets up data tables and the program's own code,
+/// leaving out the prologue between them.  Without a prologue it is all the program's own code.
+pub fn split_prologue(mut lines: Vec<Line>) -> (Vec<Line>, Vec<Line>) {
+    let prologue = prologue();
+    match lines.windows(prologue.len()).position(|x| x == prologue) {
+        Some(i) => {
+            let code = lines.split_off(i + prologue.len());
+            lines.truncate(i);
+            (lines, code)
+        }
+        None => (vec![], lines),
+    }
+}
+
+impl Preprocessable for Vec<String> {
+    fn try_preprocess(self) -> Result<Vec<String>, String> {
+        Ok(self
+            .into_iter()
+            .enumerate()
+            .map(|(i, text)| Line {
+                text,
+                number: i + 1,
+                ..Default::default()
+            })
+            .collect::<Vec<Line>>()
+            .try_preprocess()?
+            .into_iter()
+            .map(|x| x.text)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::Emulator;
+    use crate::preprocessor::{
+        check_directive, is_directive, load, parse_load, DataTable, Preprocessable,
+    };
+
+    #[test]
+    fn parses_data() {
+        assert_eq!(
+            DataTable::from_data("#data TABLE 1, -2,3").unwrap(),
+            DataTable {
+                label: "TABLE".into(),
+                values: vec![1, 0xFFFE, 3]
+            }
+        );
+        assert_eq!(
+            DataTable::from_data("#data HEX 0x4000, 'A', 65535").unwrap(),
+            DataTable {
+                label: "HEX".into(),
+                values: vec![0x4000, 65, 0xFFFF]
+            }
+        );
+        assert_eq!(
+            DataTable::from_data("#data EMPTY").unwrap(),
+            DataTable {
+                label: "EMPTY".into(),
+                values: vec![]
+            }
+        );
+    }
+
+    #[test]
+    fn parses_string() {
+        assert_eq!(
+            DataTable::from_string("#string GREETING \"hi there\"").unwrap(),
+            DataTable {
+                label: "GREETING".into(),
+                values: vec![104, 105, 32, 116, 104, 101, 114, 101, 0]
+            }
+        );
+    }
+
+    #[test]
+    fn initialises_data() {
+        assert_eq!(
+            DataTable::from_data("#data T 0, 5, -1, -5")
+                .unwrap()
+                .initialise()
                 .split('\n')
-                .map(|x| process_line(&mut included_files, x))
+                .collect::<Vec<&str>>(),
+            vec![
+                "// INITIALISE T",
+                "#var T[4]",
+                "@T",
+                "M=0",
+                "@5",
+                "D=A",
+                "@T+1",
+                "M=D",
+                "@T+2",
+                "M=-1",
+                "@4",
+                "D=!A",
+                "@T+3",
+                "M=D",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_load() {
+        assert_eq!(parse_load("#load D, -1"), Ok(("D".into(), 0xFFFF)));
+        assert_eq!(parse_load("#LOAD ad,0x4000"), Ok(("AD".into(), 0x4000)));
+        assert_eq!(parse_load("#load A, 'A'"), Ok(("A".into(), 65)));
+    }
+
+    #[test]
+    fn rejects_load_into_m() {
+        assert!(parse_load("#load M, 1").is_err());
+    }
+
+    #[test]
+    fn rejects_load_out_of_range() {
+        assert!(parse_load("#load D, 65536").is_err());
+    }
+
+    #[test]
+    fn checks_directives() {
+        assert_eq!(check_directive("#call f"), Ok(None));
+        assert_eq!(check_directive("  #RET"), Ok(None));
+        assert_eq!(check_directive("#string S \"hi\""), Ok(Some("S".into())));
+        assert!(check_directive("#call").is_err());
+        assert!(check_directive("#load M, 1").is_err());
+        assert!(check_directive("#data T 1, x").is_err());
+        assert!(check_directive("#jump f").is_err());
+        assert_eq!(check_directive("#define ROW 32*2"), Ok(Some("ROW".into())));
+        assert!(check_directive("#define ROW").is_err());
+        assert!(check_directive("#define 2ROW 64").is_err());
+    }
+
+    #[test]
+    fn unrolls_loops() {
+        let unrolled = |text: &str| {
+            text.split('\n')
+                .map(String::from)
                 .collect::<Vec<String>>()
-                .join("\n");
-        }
-        output
-            .split("\n")
-            .map(|x| x.to_string())
+                .try_preprocess()
+                .map(|x| x[4..].to_vec())
+        };
+        assert_eq!(
+            unrolled(
+                "#define N 2\n#for i in 0..N\n(ROW{i})\n#rep i+1\nM=0\n#endrep\n#for j in i..N\n\
+                 @i*N+j\n#endfor\n#endfor\n#rep 0\n@i\n#endrep"
+            ),
+            Ok(
+                ["(ROW0)", "M=0", "@0*2+0", "@0*2+1", "(ROW1)", "M=0", "M=0", "@1*2+1"]
+                    .map(String::from)
+                    .to_vec()
+            )
+        );
+        assert_eq!(
+            unrolled("#rep 2\n#call f\n#endrep").map(|x| x.join("\n").matches("(RETURN$").count()),
+            Ok(2)
+        );
+        assert_eq!(
+            unrolled("#rep 2\n@0\n#endfor"),
+            Err("Line 1: #rep is ended by #endfor".into())
+        );
+        assert_eq!(
+            unrolled("#for i in 0..2\n@i"),
+            Err("Line 1: #for without #endfor".into())
+        );
+        assert_eq!(
+            unrolled("@0\n#endrep"),
+            Err("Line 2: #endrep without a #rep or #for".into())
+        );
+        assert_eq!(
+            unrolled("#rep 1<<11\n#rep 1<<11\n@0\n#endrep\n#endrep"),
+            Err("Line 2: Unrolling gives more than 1048576 lines".into())
+        );
+        assert_eq!(
+            unrolled(&format!("#rep 1<<15\n{}#endrep", "@0\n".repeat(33))),
+            Err("Line 1: #rep unrolls to more than 1048576 lines".into())
+        );
+        assert_eq!(
+            unrolled("#for i in -0x7FFFFFFF*0x7FFFFFFF*2..0x7FFFFFFF*0x7FFFFFFF*2\n@i\n#endfor"),
+            Err("Line 1: #for unrolls to more than 1048576 lines".into())
+        );
+        assert_eq!(
+            unrolled("#rep 1<<15\n#rep 1<<15\n#rep 1<<15\n#endrep\n#endrep\n#endrep"),
+            Err("Line 3: Unrolling gives more than 1048576 lines".into())
+        );
+    }
+
+    #[test]
+    fn lowers_blocks() {
+        // counts n down from 10, adding up the odd numbers and stopping at 3
+        let program = "@10\nD=A\n@n\nM=D\n#while @n M>0\n  @n\n  MD=M-1\n  @3\n  D=D-A\n\
+                       #if D==0\n    #break\n  #endif\n  @n\n  D=M\n  @1\n  D=D&A\n\
+                       #if D\n    @n\n    D=M\n    @odd\n    M=D+M\n  #else\n    @even\n\
+                       M=M+1\n    #continue\n  #endif\n#endwhile\n(END)\n@END\n0;JMP";
+        let mut emulator = Emulator::new(crate::assemble("test.asm", program).unwrap());
+        assert!(emulator.run(1000));
+        // n, odd and even, which only counts 8, 6 and 4
+        assert_eq!(emulator.ram[16..19], [3, 9 + 7 + 5, 3]);
+
+        let error = |text: &str| {
+            text.split('\n')
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .try_preprocess()
+                .unwrap_err()
+        };
+        assert_eq!(error("#if D>0\n@0"), "Line 1: #if without #endif");
+        assert_eq!(
+            error("#while D\n#if D\n#endwhile"),
+            "Line 3: #endwhile doesn't close the #if on line 2"
+        );
+        assert_eq!(
+            error("#if D\n#else\n#else\n#endif"),
+            "Line 3: The #if on line 1 already has an #else"
+        );
+        assert_eq!(
+            error("#if D\n#break\n#endif"),
+            "Line 2: #break outside a #while"
+        );
+        assert_eq!(error("#endif"), "Line 1: #endif without a #if or #while");
+        assert_eq!(
+            error("#if D>1\n#endif"),
+            "Line 1: Can only compare with 0 in D>1"
+        );
+        assert_eq!(error("#if D+Q\n#endif"), "Line 1: Bad condition D+Q");
+        assert_eq!(error("#if D=0\n#endif"), "Line 1: Bad condition D=0");
+        assert_eq!(error("#if D=M==0\n#endif"), "Line 1: Bad condition D=M==0");
+        assert_eq!(error("#if D;JMP\n#endif"), "Line 1: Bad condition D;JMP");
+        assert_eq!(
+            error("#while M!=0\n#endwhile"),
+            "Line 1: #while conditions using A or M need an @address, as in #while @n M!=0"
+        );
+    }
+
+    #[test]
+    fn reloads_while_conditions() {
+        // counts n down from 3, going round the loop once for each
+        let program = "@3\nD=A\n@n\nM=D\n#while @n M!=0\n  @n\n  M=M-1\n  @count\n  M=M+1\n\
+                       #endwhile\n(END)\n@END\n0;JMP";
+        let mut emulator = Emulator::new(crate::assemble("test.asm", program).unwrap());
+        assert!(emulator.run(1000));
+        assert_eq!(emulator.ram[16..18], [0, 3]);
+    }
+
+    #[test]
+    fn declares_variables() {
+        let preprocess = |text: &str| {
+            text.split('\n')
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .try_preprocess()
+                .map(|x| x[4..].to_vec())
+        };
+        assert_eq!(
+            preprocess(
+                "#define N 4\n#struct Point x, y[2] z\n#var buf[N*2] @ 0x100\n#var p Point\n\
+                 #var ps Point[N]\n@p.x\n@p.z\n@ps.y+Point*2\n@Point.z"
+            ),
+            Ok([
+                "",
+                "#var buf[8] @ 256",
+                "#var p[4]",
+                "#var ps[16]",
+                "@p",
+                "@(p+3)",
+                "@(ps+1)+4*2",
+                "@3"
+            ]
+            .map(String::from)
+            .to_vec())
+        );
+        assert_eq!(
+            preprocess("#var p Point"),
+            Err("Line 1: Unknown struct Point".into())
+        );
+        assert_eq!(
+            preprocess("#struct Point x y x"),
+            Err("Line 1: Point has two fields called x".into())
+        );
+        assert_eq!(
+            preprocess("#var buf[0]"),
+            Err("Line 1: buf can't be 0 words long".into())
+        );
+        assert_eq!(
+            check_directive("#var ps Point[4] @ 100"),
+            Ok(Some("ps".into()))
+        );
+        assert_eq!(
+            check_directive("#struct Point x y"),
+            Ok(Some("Point".into()))
+        );
+    }
+
+    #[test]
+    fn reserves_the_call_stack() {
+        let preprocess = |text: &str| {
+            text.split('\n')
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .try_preprocess()
+                .map(|x| x[4..].to_vec())
+        };
+        assert_eq!(
+            preprocess("#define DEPTH 64\n#stack DEPTH*2"),
+            Ok(vec!["#var CALL$STACK[129] @ 16255".to_string()])
+        );
+        assert_eq!(
+            preprocess("#stack 0"),
+            Err("Line 1: Can't reserve 0 words for the call stack, only 1 to 16367".into())
+        );
+        assert_eq!(
+            preprocess("#stack 8\n#stack 8"),
+            Err("Line 2: The call stack is reserved more than once".into())
+        );
+        assert_eq!(check_directive("#stack DEPTH"), Ok(None));
+    }
+
+    #[test]
+    fn substitutes_defines() {
+        let output = vec![
+            "#define WIDTH 32",
+            "#define ROW WIDTH*2",
+            "#define NEG -ROW",
+            "#define WHERE SCREEN+ROW",
+            "#define TOP END",
+            "@WIDTH",
+            "@ROW+'W'",
+            "@WHERE*2",
+            "@NEG+ROWS",
+            "@TOP",
+            "(WIDTH)",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<String>>()
+        .preprocess();
+        assert_eq!(
+            output[output.len() - 6..],
+            [
+                "@32",
+                "@64+'W'",
+                "@(SCREEN+64)*2",
+                "@(-64)+ROWS",
+                "@END",
+                "(WIDTH)"
+            ]
+        );
+    }
+
+    #[test]
+    fn expands_load() {
+        assert_eq!(load("D", 0), vec!["D=0"]);
+        assert_eq!(load("A", 1), vec!["A=1"]);
+        assert_eq!(load("AD", 0xFFFF), vec!["AD=-1"]);
+        assert_eq!(load("A", 0x4000), vec!["@16384"]);
+        assert_eq!(load("D", 0x4000), vec!["@16384", "D=A"]);
+        assert_eq!(load("A", 0xFFFF - 5), vec!["@5", "A=!A"]);
+        assert_eq!(load("D", 0x8000), vec!["@32767", "D=!A"]);
+    }
+
+    #[test]
+    fn ignores_comments_after_directives() {
+        let preprocess = |text: &str| {
+            text.split('\n')
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .try_preprocess()
+        };
+        let commented = "#define N 2 // rows\n#data T 1, 2 // table\n#string S \"a // b\" // text\n\
+                         #var v[N] // buffer\n#load D, '/' // slash\n#rep N // twice\n@v\n\
+                         #endrep // done\n#if D>0 // positive\n#call f // go\n#endif\n(f)\n#ret // back";
+        let plain = "#define N 2\n#data T 1, 2\n#string S \"a // b\"\n#var v[N]\n#load D, '/'\n\
+                     #rep N\n@v\n#endrep\n#if D>0\n#call f\n#endif\n(f)\n#ret";
+        assert_eq!(preprocess(commented), preprocess(plain));
+        assert!(preprocess(plain).unwrap().contains(&"@f".to_string()));
+        assert_eq!(
+            check_directive("#data T 1, 2 // table"),
+            Ok(Some("T".into()))
+        );
+    }
+
+    #[test]
+    fn matches_whole_directives() {
+        let preprocess = |text: &str| {
+            text.split('\n')
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .try_preprocess()
+        };
+        // other words starting with a directive are left for the parser to reject
+        assert_eq!(
+            preprocess("#database T 1\n#calls f").map(|x| x[3..].to_vec()),
+            Ok(vec!["#database T 1".to_string(), "#calls f".to_string()])
+        );
+        assert_eq!(
+            preprocess("#repx 2\n@0\n#endrep"),
+            Err("Line 3: #endrep without a #rep or #for".into())
+        );
+        assert!(is_directive("#DATA T 1", "#data"));
+    }
+
+    #[test]
+    fn initialises_data_before_entry_point() {
+        let output = vec!["@T", "D=M", "#data T 1"]
+            .into_iter()
+            .map(String::from)
             .collect::<Vec<String>>()
+            .preprocess();
+        assert_eq!(
+            output,
+            vec![
+                "// INITIALISE T",
+                "#var T[1]",
+                "@T",
+                "M=1",
+                "@16383",
+                "D=A-1",
+                "M=D",
+                "@T",
+                "D=M",
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn includes_the_standard_library_once() {
+        let includes = |text: &str| {
+            text.split('\n')
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .try_preprocess()
+                .map(|x| {
+                    x.into_iter()
+                        .filter(|x| x.starts_with("// INCLUDED"))
+                        .collect::<Vec<_>>()
+                })
+        };
+        assert_eq!(
+            includes("#include <std/pixel.asm>\n#include <std/pixel.asm>"),
+            Ok(vec!["// INCLUDED FILE <std/pixel.asm>".to_string()])
+        );
+        assert_eq!(
+            includes("#include <std/sqrt.asm>"),
+            Err("No standard library file std/sqrt.asm".to_string())
+        );
     }
 }
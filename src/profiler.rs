@@ -0,0 +1,278 @@
+use crate::emulator::Emulator;
+use crate::types::{Instruction, Line};
+use std::collections::{HashMap, HashSet};
+
+/// The name given to code which isn't inside any `#call`ed function.
+const MAIN: &str = "<main>";
+
+/// Where a program's `#call`s and `#ret`s ended up in ROM, so calls can be followed as it runs.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct CallSites {
+    /// The jump of each `#call` along with the function it calls.
+    calls: HashMap<u16, String>,
+    /// The jump of each `#ret`.
+    returns: HashSet<u16>,
+}
+
+impl CallSites {
+    pub fn new(program: &[(Instruction, Line)]) -> CallSites {
+        let mut sites = CallSites::default();
+        let mut address = 0;
+        for (instruction, line) in program {
+            match instruction {
+                x if !x.takes_rom() => continue,
+                Instruction::C(_, _, jump) if jump.bits() != 0 => {
+                    let directive = line
+                        .directive
+                        .as_deref()
+                        .map(|x| x.split_whitespace().collect::<Vec<&str>>());
+                    match directive.as_deref() {
+                        Some([call, function]) if call.eq_ignore_ascii_case("#call") => {
+                            sites.calls.insert(address, function.to_string());
+                        }
+                        Some([ret]) if ret.eq_ignore_ascii_case("#ret") => {
+                            sites.returns.insert(address);
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+            address += 1;
+        }
+        sites
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Profile {
+    pub cycles: u64,
+    pub halted: bool,
+    /// How many times the instruction at each ROM address was executed.
+    pub counts: Vec<u64>,
+    /// The cycles spent with each call stack, outermost function first.
+    pub stacks: HashMap<Vec<String>, u64>,
+    /// How many times each function was called.
+    pub calls: HashMap<String, u64>,
+}
+
+/// Runs the emulator until it halts or has executed `max_cycles` instructions, counting where the
+/// time went.
+pub fn profile(emulator: &mut Emulator, sites: &CallSites, max_cycles: u64) -> Profile {
+    let mut profile = Profile {
+        counts: vec![0; emulator.rom().len()],
+        ..Default::default()
+    };
+    let mut stack = vec![MAIN.to_string()];
+    let start = emulator.cycles;
+    while emulator.cycles - start < max_cycles && !emulator.halted() {
+        let pc = emulator.pc;
+        profile.counts[pc as usize] += 1;
+        *profile.stacks.entry(stack.clone()).or_default() += 1;
+        emulator.step();
+        if let Some(function) = sites.calls.get(&pc) {
+            *profile.calls.entry(function.clone()).or_default() += 1;
+            stack.push(function.clone());
+        } else if sites.returns.contains(&pc) && stack.len() > 1 {
+            stack.pop();
+        }
+    }
+    profile.cycles = emulator.cycles - start;
+    profile.halted = emulator.halted();
+    profile
+}
+
+fn percent(cycles: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        cycles as f64 * 100.0 / total as f64
+    }
+}
+
+impl Profile {
+    /// The cycles spent in the code from each label up to the next one, hottest first.  Code
+    /// before the first label is counted as `<start>`.
+    pub fn regions(&self, labels: &[(u16, String)]) -> Vec<(String, u64)> {
+        let mut labels = labels.to_vec();
+        labels.sort();
+        let mut regions: Vec<(String, u64)> = vec![];
+        let mut next = 0;
+        let mut region = "<start>".to_string();
+        for (address, count) in self.counts.iter().enumerate() {
+            while next < labels.len() && labels[next].0 as usize <= address {
+                region = labels[next].1.clone();
+                next += 1;
+            }
+            match regions.iter_mut().find(|(x, _)| *x == region) {
+                Some((_, cycles)) => *cycles += count,
+                None => regions.push((region.clone(), *count)),
+            }
+        }
+        regions.retain(|(_, cycles)| *cycles > 0);
+        regions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        regions
+    }
+
+    /// Each function's calls along with its inclusive and exclusive cycles, hottest first.
+    /// Inclusive cycles count the functions it calls, and a recursive function only counts once.
+    pub fn functions(&self) -> Vec<(String, u64, u64, u64)> {
+        let mut functions: HashMap<&str, (u64, u64)> = HashMap::new();
+        for (stack, cycles) in &self.stacks {
+            let mut seen = HashSet::new();
+            for function in stack {
+                if seen.insert(function) {
+                    functions.entry(function).or_default().0 += cycles;
+                }
+            }
+            functions.entry(stack.last().unwrap()).or_default().1 += cycles;
+        }
+        let mut functions = functions
+            .into_iter()
+            .map(|(name, (inclusive, exclusive))| {
+                let calls = self.calls.get(name).copied().unwrap_or(0);
+                (name.to_string(), calls, inclusive, exclusive)
+            })
+            .collect::<Vec<(String, u64, u64, u64)>>();
+        functions.sort_by(|a, b| b.2.cmp(&a.2).then(b.3.cmp(&a.3)).then(a.0.cmp(&b.0)));
+        functions
+    }
+
+    /// The call stacks in the folded format flame graph tools read, one `outer;inner cycles` line
+    /// per stack.
+    pub fn folded(&self) -> String {
+        let mut lines = self
+            .stacks
+            .iter()
+            .map(|(stack, cycles)| format!("{} {}", stack.join(";"), cycles))
+            .collect::<Vec<String>>();
+        lines.sort();
+        lines.into_iter().map(|x| x + "\n").collect()
+    }
+
+    pub fn report(&self, labels: &[(u16, String)]) -> String {
+        let status = if self.halted {
+            "halted"
+        } else {
+            "still running"
+        };
+        let mut output = vec![format!("{} cycles, {}", self.cycles, status), String::new()];
+
+        let regions = self.regions(labels);
+        let width = regions.iter().map(|x| x.0.len()).max().unwrap_or(0).max(5);
+        output.push(format!(
+            "{:<w$}  {:>12}  {:>6}",
+            "Label",
+            "Cycles",
+            "%",
+            w = width
+        ));
+        for (name, cycles) in regions {
+            output.push(format!(
+                "{:<w$}  {:>12}  {:>5.1}%",
+                name,
+                cycles,
+                percent(cycles, self.cycles),
+                w = width
+            ));
+        }
+
+        output.push(String::new());
+        let functions = self.functions();
+        let width = functions
+            .iter()
+            .map(|x| x.0.len())
+            .max()
+            .unwrap_or(0)
+            .max(8);
+        output.push(format!(
+            "{:<w$}  {:>8}  {:>12}  {:>6}  {:>12}  {:>6}",
+            "Function",
+            "Calls",
+            "Inclusive",
+            "%",
+            "Exclusive",
+            "%",
+            w = width
+        ));
+        for (name, calls, inclusive, exclusive) in functions {
+            output.push(format!(
+                "{:<w$}  {:>8}  {:>12}  {:>5.1}%  {:>12}  {:>5.1}%",
+                name,
+                calls,
+                inclusive,
+                percent(inclusive, self.cycles),
+                exclusive,
+                percent(exclusive, self.cycles),
+                w = width
+            ));
+        }
+        output.join("\n") + "\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{Assemblable, SymbolKind};
+    use crate::emulator::Emulator;
+    use crate::parser::parse_lines;
+    use crate::preprocessor::Preprocessable;
+    use crate::profiler::{profile, CallSites, Profile};
+    use crate::symbols::symbols;
+    use crate::types::{Instruction, Line};
+
+    /// Multiplies 3 by 4 with a function which adds in a loop.
+    const PROGRAM: &str = "\
+@3\nD=A\n@R0\nM=D\n@4\nD=A\n@R1\nM=D\n#call MUL\n(END)\n@END\n0;JMP\n\
+(MUL)\n@R2\nM=0\n(LOOP)\n@R1\nD=M\n@DONE\nD;JEQ\n#call ADD\n@R1\nM=M-1\n@LOOP\n0;JMP\n(DONE)\n#ret\n\
+(ADD)\n@R0\nD=M\n@R2\nM=D+M\n#ret";
+
+    fn run() -> (Profile, Vec<(u16, String)>) {
+        let program = parse_lines(Line::read("main.asm", PROGRAM).preprocess());
+        let labels = symbols(&program)
+            .into_iter()
+            .filter(|x| x.kind == SymbolKind::Label)
+            .map(|x| (x.address, x.name))
+            .collect::<Vec<(u16, String)>>();
+        let sites = CallSites::new(&program);
+        let rom = program
+            .into_iter()
+            .map(|(x, _)| x)
+            .collect::<Vec<Instruction>>()
+            .assemble();
+        let mut emulator = Emulator::new(rom);
+        let profile = profile(&mut emulator, &sites, 10_000);
+        assert_eq!(emulator.ram[2], 12);
+        (profile, labels)
+    }
+
+    #[test]
+    fn counts_cycles() {
+        let (profile, labels) = run();
+        assert!(profile.halted);
+        assert_eq!(profile.counts.iter().sum::<u64>(), profile.cycles);
+        let regions = profile.regions(&labels);
+        assert_eq!(regions[0].0, "LOOP");
+        assert_eq!(regions.iter().map(|x| x.1).sum::<u64>(), profile.cycles);
+    }
+
+    #[test]
+    fn attributes_cycles_to_functions() {
+        let (profile, _) = run();
+        let functions = profile.functions();
+        let names = functions
+            .iter()
+            .map(|x| (x.0.as_str(), x.1))
+            .collect::<Vec<(&str, u64)>>();
+        assert_eq!(names, vec![("<main>", 0), ("MUL", 1), ("ADD", 4)]);
+        let (_, _, main, _) = &functions[0];
+        assert_eq!(*main, profile.cycles);
+        // ADD is 4 instructions and a 4 instruction #ret
+        assert_eq!((functions[2].2, functions[2].3), (32, 32));
+        assert_eq!(functions[1].2, functions[1].3 + 32);
+
+        let folded = profile.folded();
+        assert!(folded.contains("<main>;MUL;ADD 32\n"));
+        assert_eq!(folded.lines().count(), 3);
+    }
+}
@@ -0,0 +1,247 @@
+//! Property tests over randomly generated programs, checking the parser, assembler, disassembler,
+//! optimizer and emulator all agree with each other.
+
+use crate::assembler::{decode, encode, Assemblable, SymbolTable};
+use crate::emulator::Emulator;
+use crate::optimizer::Optimizable;
+use crate::parser::{parse, parse_line};
+use crate::types::{Computation, Instruction, Jump, Location, Macro, Operation, Register, Source};
+use proptest::prelude::*;
+use proptest::sample::{select, subsequence};
+
+/// Every computation the ALU can do, including the ones written the other way around.
+const COMPUTATIONS: [&str; 34] = [
+    "0", "1", "-1", "D", "A", "!D", "!A", "-D", "-A", "D+1", "A+1", "D-1", "A-1", "D+A", "A+D",
+    "D-A", "A-D", "D&A", "A&D", "D|A", "A|D", "M", "!M", "-M", "M+1", "M-1", "D+M", "M+D", "D-M",
+    "M-D", "D&M", "M&D", "D|M", "M|D",
+];
+
+const JUMPS: [Jump; 8] = [
+    Jump::None,
+    Jump::JGT,
+    Jump::JEQ,
+    Jump::JGE,
+    Jump::JLT,
+    Jump::JNE,
+    Jump::JLE,
+    Jump::JMP,
+];
+
+fn computation_of(text: &str) -> Computation {
+    match parse_line(text) {
+        Ok(Some(Instruction::C(_, computation, _))) => computation,
+        _ => unreachable!("{} is a computation", text),
+    }
+}
+
+fn computation() -> impl Strategy<Value = Computation> {
+    select(&COMPUTATIONS[..]).prop_map(computation_of)
+}
+
+/// Any of A, D and M in any order, each at most once.
+fn dest() -> impl Strategy<Value = Vec<Register>> {
+    subsequence(vec![Register::A, Register::D, Register::M], 0..=3).prop_shuffle()
+}
+
+fn symbol() -> impl Strategy<Value = String> {
+    "[a-zA-Z_.$:][a-zA-Z0-9_.$:]{0,8}"
+}
+
+fn instruction() -> impl Strategy<Value = Instruction> {
+    prop_oneof![
+        (0..=0x7FFFu16).prop_map(|x| Instruction::A(Location::Address(x))),
+        symbol().prop_map(|x| Instruction::A(Location::Label(x))),
+        (dest(), computation(), select(&JUMPS[..]))
+            .prop_map(|(dest, comp, jump)| Instruction::C(dest, comp, jump)),
+        symbol().prop_map(Instruction::Label),
+        symbol().prop_map(|x| Instruction::Macro(Macro::Call(x))),
+        Just(Instruction::Macro(Macro::Return)),
+    ]
+}
+
+/// A program without directives whose labels are all different and don't redefine a predefined
+/// symbol, so it can be assembled.
+fn assemblable() -> impl Strategy<Value = Vec<Instruction>> {
+    prop::collection::vec(instruction(), 0..64).prop_map(|program| {
+        let predefined = SymbolTable::new(&[]);
+        let mut labels = std::collections::HashSet::new();
+        program
+            .into_iter()
+            .filter(|x| match x {
+                Instruction::Label(label) => {
+                    predefined.get(label).is_none() && labels.insert(label.clone())
+                }
+                Instruction::Macro(_) => false,
+                _ => true,
+            })
+            .collect()
+    })
+}
+
+fn load() -> impl Strategy<Value = Instruction> {
+    (0..24u16).prop_map(|x| Instruction::A(Location::Address(x)))
+}
+
+/// Code which doesn't jump, loading small addresses so it mostly works on the same few words of
+/// RAM.  Variables are left out as they are allocated in order of first use, which changes when
+/// the optimizer removes a load.  It starts with a load, as A could otherwise hold the address of
+/// a label, which moves when the optimizer removes code.
+fn straight_line() -> impl Strategy<Value = Vec<Instruction>> {
+    let compute =
+        (dest(), computation()).prop_map(|(dest, comp)| Instruction::C(dest, comp, Jump::None));
+    (
+        load(),
+        prop::collection::vec(prop_oneof![load(), compute], 0..12),
+    )
+        .prop_map(|(first, rest)| std::iter::once(first).chain(rest).collect())
+}
+
+/// A program which only jumps forwards, so it always halts.  Jumps only test D, as A and M are
+/// the label being jumped to and the word of RAM at its address.
+fn halting() -> impl Strategy<Value = Vec<Instruction>> {
+    let block = (
+        straight_line(),
+        select(vec!["0", "D", "!D", "-D", "D+1", "D-1"]).prop_map(computation_of),
+        select(&JUMPS[1..]),
+        any::<prop::sample::Index>(),
+    );
+    prop::collection::vec(block, 1..8).prop_map(|blocks| {
+        let count = blocks.len();
+        let mut program = vec![];
+        for (i, (code, comp, jump, target)) in blocks.into_iter().enumerate() {
+            program.push(Instruction::Label(format!("B{}", i)));
+            program.extend(code);
+            // jump to any later block, or the end
+            let target = i + 1 + target.index(count - i);
+            let label = if target == count {
+                "END".to_string()
+            } else {
+                format!("B{}", target)
+            };
+            program.push(Instruction::A(Location::Label(label)));
+            program.push(Instruction::C(vec![], comp, jump));
+        }
+        program.extend(parse("(END)\n@END\n0;JMP".to_string()));
+        program
+    })
+}
+
+/// What the disassembler gives back for an instruction: labels resolved, destinations in the order
+/// A, M, D and commutative computations with D first.
+fn disassembled(instruction: &Instruction, symbols: &SymbolTable) -> Option<Instruction> {
+    Some(match instruction {
+        Instruction::Label(_) | Instruction::Macro(_) => return None,
+        Instruction::A(Location::Label(label)) => {
+            Instruction::A(Location::Address(symbols.get(label).unwrap()))
+        }
+        Instruction::A(x) => Instruction::A(x.clone()),
+        Instruction::C(dest, Computation::Computation(lhs, rhs, op), jump) => {
+            let dest = [Register::A, Register::M, Register::D]
+                .iter()
+                .filter(|x| dest.contains(x))
+                .copied()
+                .collect();
+            let commutes = matches!(op, Operation::Add | Operation::And | Operation::Or);
+            let (lhs, rhs) = match (lhs, rhs) {
+                (_, Source::Register(Register::D)) if commutes => (rhs, lhs),
+                _ => (lhs, rhs),
+            };
+            Instruction::C(
+                dest,
+                Computation::Computation(lhs.clone(), rhs.clone(), *op),
+                *jump,
+            )
+        }
+    })
+}
+
+/// Runs a program one instruction at a time, without the decoded ROM `Emulator::run` uses.
+fn step(emulator: &mut Emulator, max_cycles: u64) -> bool {
+    for _ in 0..max_cycles {
+        if emulator.halted() {
+            return true;
+        }
+        emulator.step();
+    }
+    emulator.halted()
+}
+
+/// Words which are mostly C-instructions and small A-instructions, so programs jump around the
+/// ROM and work on the same few words of RAM.
+fn word() -> impl Strategy<Value = u16> {
+    let symbols = SymbolTable::new(&[]);
+    let compute =
+        (dest(), computation(), select(&JUMPS[..])).prop_map(move |(dest, comp, jump)| {
+            encode(&Instruction::C(dest, comp, jump), &symbols).unwrap()
+        });
+    // the forms the decoded ROM has its own ops for
+    let common = [
+        "D=M", "D=A", "M=D", "A=M", "M=0", "M=1", "M=-1", "M=M+1", "M=M-1", "AM=M+1", "AM=M-1",
+        "MD=M+1", "MD=M-1", "D=D+M", "D=D-M", "D=D+A", "D=D-A", "M=D+M", "D;JGT", "D;JEQ", "D;JNE",
+        "D;JLT", "0;JMP",
+    ]
+    .iter()
+    .map(|x| encode(&parse_line(x).unwrap().unwrap(), &SymbolTable::new(&[])).unwrap())
+    .collect::<Vec<u16>>();
+    prop_oneof![3 => 0..32u16, 3 => select(common), 3 => compute, 1 => any::<u16>()]
+}
+
+fn run(rom: Vec<u16>) -> Emulator {
+    let mut emulator = Emulator::new(rom);
+    assert!(emulator.run(10_000), "program did not halt");
+    emulator
+}
+
+proptest! {
+    #[test]
+    fn parses_what_it_formats(program in prop::collection::vec(instruction(), 0..64)) {
+        let text = program.iter().map(|x| x.to_string()).collect::<Vec<String>>().join("\n");
+        prop_assert_eq!(parse(text), program);
+    }
+
+    #[test]
+    fn disassembles_what_it_assembles(program in assemblable()) {
+        let symbols = SymbolTable::new(&program);
+        let expected = program
+            .iter()
+            .filter_map(|x| disassembled(x, &symbols))
+            .collect::<Vec<Instruction>>();
+        let words = program.assemble();
+        prop_assert_eq!(
+            words.iter().map(|x| decode(*x)).collect::<Vec<Option<Instruction>>>(),
+            expected.iter().cloned().map(Some).collect::<Vec<Option<Instruction>>>()
+        );
+        // and the disassembly assembles back to the same words
+        let empty = SymbolTable::new(&[]);
+        prop_assert_eq!(
+            expected.iter().filter_map(|x| encode(x, &empty)).collect::<Vec<u16>>(),
+            words
+        );
+    }
+
+    #[test]
+    fn decodes_the_same_as_stepping(
+        rom in prop::collection::vec(word(), 0..32),
+        ram in prop::collection::vec(any::<u16>(), 32),
+        cycles in 0..2000u64,
+    ) {
+        let mut decoded = Emulator::new(rom.clone());
+        decoded.ram[..32].copy_from_slice(&ram);
+        let mut stepped = Emulator::new(rom);
+        stepped.ram[..32].copy_from_slice(&ram);
+        prop_assert_eq!(decoded.run(cycles), step(&mut stepped, cycles));
+        prop_assert_eq!(
+            (decoded.pc, decoded.a, decoded.d, decoded.cycles),
+            (stepped.pc, stepped.a, stepped.d, stepped.cycles)
+        );
+        prop_assert_eq!(decoded.ram, stepped.ram);
+    }
+
+    #[test]
+    fn optimizes_without_changing_ram(program in halting()) {
+        let original = run(program.clone().assemble());
+        let optimized = run(program.optimize().assemble());
+        prop_assert!(optimized.cycles <= original.cycles);
+        prop_assert_eq!(original.ram, optimized.ram);
+    }
+}
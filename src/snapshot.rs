@@ -0,0 +1,160 @@
+use crate::emulator::Emulator;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// The version of the snapshot format, which goes up whenever what a snapshot records changes.
+pub const VERSION: u32 = 2;
+
+/// Everything the emulator needs to carry on running a program from where it was.  The key being
+/// pressed is the word at `KBD` in `ram`.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    /// A hash of the ROM, so a snapshot is only restored into the program it was taken of.
+    pub rom: String,
+    pub pc: u16,
+    pub a: u16,
+    pub d: u16,
+    pub cycles: u64,
+    /// How far through its keyboard script the emulator is.
+    pub keys_pressed: usize,
+    pub ram: Vec<u16>,
+}
+
+/// Hashes a ROM with 64-bit FNV-1a, which unlike `std`'s hasher gives the same hash everywhere.
+pub fn rom_hash(rom: &[u16]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in rom.iter().flat_map(|x| x.to_le_bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+impl Snapshot {
+    /// Takes a snapshot of the emulator.  The Jack OS's heap and output aren't in RAM, so an
+    /// emulator running calls to it natively can't be snapshotted.
+    pub fn new(emulator: &Emulator) -> Result<Snapshot, String> {
+        if emulator.os.is_some() {
+            return Err("Snapshots can't include the Jack OS run with --os".to_string());
+        }
+        Ok(Snapshot {
+            version: VERSION,
+            rom: rom_hash(emulator.rom()),
+            pc: emulator.pc,
+            a: emulator.a,
+            d: emulator.d,
+            cycles: emulator.cycles,
+            keys_pressed: emulator.keys_pressed,
+            ram: emulator.ram.clone(),
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Could not serialize snapshot") + "\n"
+    }
+
+    /// Reads a snapshot written by `to_json`, checking the version first so an error about a
+    /// snapshot from another version says so.
+    pub fn from_json(text: &str) -> Result<Snapshot, String> {
+        let value: serde_json::Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        match value.get("version").and_then(|x| x.as_u64()) {
+            Some(version) if version == VERSION as u64 => {}
+            Some(version) => {
+                return Err(format!(
+                    "Can't read version {} snapshots, only version {}",
+                    version, VERSION
+                ))
+            }
+            None => return Err("Not a snapshot, as it has no version".to_string()),
+        }
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        fs::write(path, self.to_json())
+            .map_err(|e| format!("Could not write snapshot {:?}: {:?}", path, e))
+    }
+
+    pub fn load(path: &str) -> Result<Snapshot, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read snapshot {:?}: {:?}", path, e))?;
+        Snapshot::from_json(&text).map_err(|e| format!("Could not load snapshot {:?}: {}", path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assemblable;
+    use crate::emulator::Emulator;
+    use crate::parser::parse;
+    use crate::snapshot::{Snapshot, VERSION};
+
+    fn emulator(program: &str) -> Emulator {
+        Emulator::new(parse(program.to_string()).assemble())
+    }
+
+    const COUNT: &str = "@10\nD=A\n@i\nM=D\n(LOOP)\n@i\nMD=M-1\n@LOOP\nD;JGT\n(END)\n@END\n0;JMP";
+
+    #[test]
+    fn restores_snapshots() {
+        let mut original = emulator(COUNT);
+        original.run(20);
+        let snapshot = Snapshot::from_json(&Snapshot::new(&original).unwrap().to_json()).unwrap();
+        assert_eq!((snapshot.version, snapshot.cycles), (VERSION, 20));
+
+        // carrying on from the snapshot ends up the same as carrying on from where it was taken
+        let mut restored = emulator(COUNT);
+        restored.restore(&snapshot).unwrap();
+        assert!(original.run(1000) && restored.run(1000));
+        assert_eq!(
+            (restored.pc, restored.a, restored.d, restored.cycles),
+            (original.pc, original.a, original.d, original.cycles)
+        );
+        assert_eq!(restored.ram, original.ram);
+    }
+
+    #[test]
+    fn restores_keyboard_position() {
+        // adds the key being pressed to R0 every time round
+        const ADD_KEYS: &str = "(LOOP)\n@KBD\nD=M\n@R0\nM=D+M\n@LOOP\n0;JMP";
+        let keyboard = vec![(10, 1), (40, 2), (70, 0)];
+        let mut original = emulator(ADD_KEYS);
+        original.keyboard = keyboard.clone();
+        original.run(50);
+        let snapshot = Snapshot::new(&original).unwrap();
+        assert_eq!(snapshot.keys_pressed, 2);
+
+        let mut restored = emulator(ADD_KEYS);
+        assert_eq!(
+            restored.restore(&snapshot),
+            Err("The snapshot has pressed 2 keys, but the keyboard script only has 0".into())
+        );
+        restored.keyboard = keyboard;
+        restored.restore(&snapshot).unwrap();
+        original.run(100);
+        restored.run(100);
+        assert_eq!(restored.keys_pressed, 3);
+        assert_eq!(restored.ram, original.ram);
+    }
+
+    #[test]
+    fn refuses_other_snapshots() {
+        let snapshot = Snapshot::new(&emulator(COUNT)).unwrap();
+        assert_eq!(
+            emulator("@1\nD=A").restore(&snapshot),
+            Err("The snapshot is of a different ROM".into())
+        );
+        let text = snapshot
+            .to_json()
+            .replacen("\"version\":2", "\"version\":3", 1);
+        assert_eq!(
+            Snapshot::from_json(&text),
+            Err("Can't read version 3 snapshots, only version 2".into())
+        );
+        assert_eq!(
+            Snapshot::from_json("{}"),
+            Err("Not a snapshot, as it has no version".into())
+        );
+    }
+}
@@ -0,0 +1,52 @@
+// std/div.asm
+// #call std.div: D = R13 / R14 and R13 = R13 % R14, for R13 from 0 to 32767 and R14 from 1 to
+// 32767.  Changes R13 to R15.
+
+(std.div)
+@R15
+M=0
+@std.div.remainder
+M=0
+@16
+D=A
+@std.div.count
+M=D
+(std.div.loop)
+// shift the top bit of R13 into the remainder
+@std.div.remainder
+D=M
+M=D+M
+@R13
+D=M
+M=D+M
+@std.div.low
+D;JGE
+@std.div.remainder
+M=M+1
+(std.div.low)
+// and shift a 1 into the quotient if R14 fits into the remainder
+@R15
+D=M
+M=D+M
+@std.div.remainder
+D=M
+@R14
+D=D-M
+@std.div.next
+D;JLT
+@std.div.remainder
+M=D
+@R15
+M=M+1
+(std.div.next)
+@std.div.count
+MD=M-1
+@std.div.loop
+D;JGT
+@std.div.remainder
+D=M
+@R13
+M=D
+@R15
+D=M
+#ret
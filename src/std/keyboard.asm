@@ -0,0 +1,19 @@
+// std/keyboard.asm
+// #call std.wait_key: waits for a key to be pressed and let go again, then gives its code in D.
+// Changes R13.
+
+(std.wait_key)
+@KBD
+D=M
+@std.wait_key
+D;JEQ
+@R13
+M=D
+(std.wait_key.release)
+@KBD
+D=M
+@std.wait_key.release
+D;JNE
+@R13
+D=M
+#ret
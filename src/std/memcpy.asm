@@ -0,0 +1,23 @@
+// std/memcpy.asm
+// #call std.memcpy: copies R15 words from the address in R13 to the address in R14, starting at
+// the lowest address.  Changes R13 to R15.
+
+(std.memcpy)
+@R15
+D=M
+@std.memcpy.end
+D;JLE
+@R13
+AM=M+1
+A=A-1
+D=M
+@R14
+AM=M+1
+A=A-1
+M=D
+@R15
+M=M-1
+@std.memcpy
+0;JMP
+(std.memcpy.end)
+#ret
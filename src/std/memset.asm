@@ -0,0 +1,20 @@
+// std/memset.asm
+// #call std.memset: sets R15 words starting at the address in R14 to R13.  Changes R14 and R15.
+
+(std.memset)
+@R15
+D=M
+@std.memset.end
+D;JLE
+@R13
+D=M
+@R14
+AM=M+1
+A=A-1
+M=D
+@R15
+M=M-1
+@std.memset
+0;JMP
+(std.memset.end)
+#ret
@@ -0,0 +1,34 @@
+// std/mul.asm
+// #call std.mul: D = R13 * R14, wrapping around at 16 bits like the ALU does.
+// Changes R13 to R15.
+
+(std.mul)
+@R15
+M=0
+@std.mul.bit
+M=1
+(std.mul.loop)
+// add the shifted R13 if this bit of R14 is set
+@std.mul.bit
+D=M
+@R14
+D=D&M
+@std.mul.next
+D;JEQ
+@R13
+D=M
+@R15
+M=D+M
+(std.mul.next)
+@R13
+D=M
+M=D+M
+@std.mul.bit
+D=M
+MD=D+M
+// the bit shifts out of the top after 16 times round
+@std.mul.loop
+D;JNE
+@R15
+D=M
+#ret
@@ -0,0 +1,78 @@
+// std/pixel.asm
+// #call std.set_pixel: turns on the pixel at column R13 (0 to 511) and row R14 (0 to 255).
+// #call std.clear_pixel: turns it off.
+// Both change R13 to R15.
+
+(std.set_pixel)
+#call std.pixel.locate
+@R15
+A=M
+M=D|M
+#ret
+
+(std.clear_pixel)
+#call std.pixel.locate
+D=!D
+@R15
+A=M
+M=D&M
+#ret
+
+// Leaves the address of the pixel's word of screen memory in R15 and its bit in D.
+(std.pixel.locate)
+// R15 = SCREEN + 32 * row
+@R14
+D=M
+@R15
+M=D
+@5
+D=A
+@std.pixel.count
+M=D
+(std.pixel.row)
+@R15
+D=M
+M=D+M
+@std.pixel.count
+MD=M-1
+@std.pixel.row
+D;JGT
+@SCREEN
+D=A
+@R15
+M=D+M
+// the bit is 1 shifted left by column % 16
+@15
+D=A
+@R13
+D=D&M
+@std.pixel.count
+M=D
+@R14
+M=1
+(std.pixel.bit)
+@std.pixel.count
+MD=M-1
+@std.pixel.column
+D;JLT
+@R14
+D=M
+M=D+M
+@std.pixel.bit
+0;JMP
+// and the word is column / 16 along
+(std.pixel.column)
+@16
+D=A
+@R13
+MD=M-D
+@std.pixel.done
+D;JLT
+@R15
+M=M+1
+@std.pixel.column
+0;JMP
+(std.pixel.done)
+@R14
+D=M
+#ret
@@ -0,0 +1,122 @@
+//! The standard library, built into the binary and included with `#include <std/mul.asm>`.
+//!
+//! Every routine is called with `#call` and returns with `#ret`.  Arguments go in R13 to R15 and
+//! results come back in D, and a routine may change A, D, R13 to R15 and its own `std.` variables
+//! but nothing else.  Each file starts with a comment describing its routines.
+
+pub const FILES: [(&str, &str); 6] = [
+    ("std/mul.asm", include_str!("std/mul.asm")),
+    ("std/div.asm", include_str!("std/div.asm")),
+    ("std/memcpy.asm", include_str!("std/memcpy.asm")),
+    ("std/memset.asm", include_str!("std/memset.asm")),
+    ("std/pixel.asm", include_str!("std/pixel.asm")),
+    ("std/keyboard.asm", include_str!("std/keyboard.asm")),
+];
+
+/// The path inside the angle brackets of `#include <path>`, if it is one.
+pub fn library_path(include: &str) -> Option<&str> {
+    include.strip_prefix('<')?.strip_suffix('>')
+}
+
+/// Gives the text of a standard library file.
+pub fn get(path: &str) -> Option<&'static str> {
+    FILES
+        .iter()
+        .find(|(x, _)| *x == path)
+        .map(|(_, text)| *text)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::Emulator;
+
+    const KBD: usize = 24576;
+
+    /// Sets R13 to R15, calls a routine and keeps what it gives back in R0.
+    fn call(file: &str, routine: &str, arguments: &[u16]) -> Emulator {
+        let mut program = String::new();
+        for (register, value) in (13..).zip(arguments) {
+            program += &format!("#load D, {}\n@R{}\nM=D\n", *value as i16, register);
+        }
+        program += &format!("#call {}\n@R0\nM=D\n(END)\n@END\n0;JMP\n", routine);
+        program += &format!("#include <std/{}.asm>\n", file);
+        let mut emulator = Emulator::new(crate::assemble("test.asm", &program).unwrap());
+        assert!(emulator.run(100_000), "{} did not return", routine);
+        emulator
+    }
+
+    #[test]
+    fn multiplies() {
+        for (a, b) in [
+            (0, 5),
+            (6, 7),
+            (-3, 5),
+            (-4, -8),
+            (181, 181),
+            (300, 300),
+            (1, -1),
+        ] {
+            let expected = (a as i16).wrapping_mul(b as i16) as u16;
+            assert_eq!(
+                call("mul", "std.mul", &[a as u16, b as u16]).ram[0],
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn divides() {
+        for (a, b) in [
+            (0, 3),
+            (7, 2),
+            (100, 10),
+            (32767, 1),
+            (32767, 32767),
+            (5, 9),
+        ] {
+            let emulator = call("div", "std.div", &[a, b]);
+            assert_eq!((emulator.ram[0], emulator.ram[13]), (a / b, a % b));
+        }
+    }
+
+    #[test]
+    fn copies_and_sets_memory() {
+        let mut program = "#data FROM 1, 2, 3, 4\n#data TO 0, 0, 0, 0, 0\n".to_string();
+        program += "@FROM\nD=A\n@R13\nM=D\n@TO\nD=A\n@R14\nM=D+1\n@3\nD=A\n@R15\nM=D\n";
+        program += "#call std.memcpy\n@9\nD=A\n@R13\nM=D\n@TO\nD=A\n@R14\nM=D\n@R15\nM=1\n";
+        program += "#call std.memset\n(END)\n@END\n0;JMP\n";
+        program += "#include <std/memcpy.asm>\n#include <std/memset.asm>\n";
+        let mut emulator = Emulator::new(crate::assemble("test.asm", &program).unwrap());
+        assert!(emulator.run(10_000));
+        assert_eq!(emulator.ram[16..25], [1, 2, 3, 4, 9, 1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn sets_and_clears_pixels() {
+        let emulator = call("pixel", "std.set_pixel", &[37, 2]);
+        assert_eq!(emulator.ram[16384 + 2 * 32 + 2], 1 << 5);
+        let emulator = call("pixel", "std.set_pixel", &[511, 255]);
+        assert_eq!(emulator.ram[16384 + 8191], 0x8000);
+
+        let mut program = "#load D, -1\n@16385\nM=D\n@16386\nM=D\n".to_string();
+        program += "@16\nD=A\n@R13\nM=D\n@R14\nM=0\n#call std.clear_pixel\n";
+        program += "(END)\n@END\n0;JMP\n#include <std/pixel.asm>\n";
+        let mut emulator = Emulator::new(crate::assemble("test.asm", &program).unwrap());
+        assert!(emulator.run(10_000));
+        assert_eq!(emulator.ram[16385], 0xFFFE);
+        assert_eq!(emulator.ram[16386], 0xFFFF);
+    }
+
+    #[test]
+    fn waits_for_a_key() {
+        let program =
+            "#call std.wait_key\n@R0\nM=D\n(END)\n@END\n0;JMP\n#include <std/keyboard.asm>";
+        let mut emulator = Emulator::new(crate::assemble("test.asm", program).unwrap());
+        assert!(!emulator.run(100));
+        emulator.ram[KBD] = 65;
+        assert!(!emulator.run(100));
+        emulator.ram[KBD] = 0;
+        assert!(emulator.run(100));
+        assert_eq!(emulator.ram[0], 65);
+    }
+}
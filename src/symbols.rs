@@ -0,0 +1,244 @@
+use crate::assembler::{predefined_symbols, SymbolKind, SymbolTable};
+use crate::types::{Instruction, Line};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub address: u16,
+}
+
+/// Every symbol in an assembled program: the predefined symbols, then labels and then variables,
+/// each in order of address.
+pub fn symbols(program: &[(Instruction, Line)]) -> Vec<Symbol> {
+    try_symbols(program).unwrap_or_else(|e| panic!("{}", e))
+}
+
+pub fn try_symbols(program: &[(Instruction, Line)]) -> Result<Vec<Symbol>, String> {
+    let instructions = program
+        .iter()
+        .map(|(x, _)| x.clone())
+        .collect::<Vec<Instruction>>();
+    let table = SymbolTable::try_new(&instructions)?;
+    let generated = program
+        .iter()
+        .filter_map(|(instruction, line)| match instruction {
+            Instruction::Label(label) if line.directive.is_some() => Some(label.as_str()),
+            _ => None,
+        })
+        .collect::<HashSet<&str>>();
+
+    let mut symbols = vec![];
+    for kind in &[
+        SymbolKind::Predefined,
+        SymbolKind::Label,
+        SymbolKind::Variable,
+    ] {
+        for (name, address) in table.of_kind(*kind) {
+            let kind = match kind {
+                SymbolKind::Label if generated.contains(name) => SymbolKind::Generated,
+                kind => *kind,
+            };
+            symbols.push(Symbol {
+                name: name.to_string(),
+                kind,
+                address,
+            });
+        }
+    }
+    Ok(symbols)
+}
+
+pub fn to_json(symbols: &[Symbol]) -> String {
+    serde_json::to_string_pretty(symbols).expect("Could not serialize symbols") + "\n"
+}
+
+/// Writes symbols in the `bank:address name` format many emulators read, with ROM in bank 00 and
+/// RAM in bank 01.
+pub fn to_sym(symbols: &[Symbol]) -> String {
+    let mut output = vec!["; bank 00 is ROM, bank 01 is RAM".to_string()];
+    for symbol in symbols {
+        let bank = if symbol.kind.in_rom() { 0 } else { 1 };
+        output.push(format!(
+            "{:02X}:{:04X} {}",
+            bank, symbol.address, symbol.name
+        ));
+    }
+    output.join("\n") + "\n"
+}
+
+/// Reads symbols written by `to_sym`.  The format doesn't record how a symbol was defined, so ROM
+/// symbols come back as labels and RAM symbols as variables unless they are predefined.
+pub fn parse_sym(text: &str) -> Result<Vec<Symbol>, String> {
+    let predefined = predefined_symbols();
+    text.lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with(';'))
+        .map(|line| {
+            let mut parts = line.split_whitespace();
+            let (location, name) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(location), Some(name), None) => (location, name.to_string()),
+                _ => return Err(format!("Could not parse symbol {:?}", line)),
+            };
+            let (bank, address) = match location.split_once(':') {
+                Some((bank, address)) => (
+                    u8::from_str_radix(bank, 16),
+                    u16::from_str_radix(address, 16),
+                ),
+                None => return Err(format!("Could not parse symbol {:?}", line)),
+            };
+            let kind = match (bank, &address) {
+                (Ok(0), Ok(_)) => SymbolKind::Label,
+                (Ok(1), Ok(address)) if predefined.get(&name) == Some(address) => {
+                    SymbolKind::Predefined
+                }
+                (Ok(1), Ok(_)) => SymbolKind::Variable,
+                _ => return Err(format!("Could not parse symbol {:?}", line)),
+            };
+            Ok(Symbol {
+                name,
+                kind,
+                address: address.unwrap(),
+            })
+        })
+        .collect()
+}
+
+/// Writes symbols to a file, as a `.sym` file if it has that extension or JSON otherwise.
+pub fn save(path: &str, symbols: &[Symbol]) -> Result<(), String> {
+    let text = if path.ends_with(".sym") {
+        to_sym(symbols)
+    } else {
+        to_json(symbols)
+    };
+    fs::write(path, text).map_err(|e| format!("Could not write symbols {:?}: {:?}", path, e))
+}
+
+/// Reads symbols written by `save`.
+pub fn load(path: &str) -> Result<Vec<Symbol>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read symbols {:?}: {:?}", path, e))?;
+    let symbols = if path.ends_with(".sym") {
+        parse_sym(&text)
+    } else {
+        serde_json::from_str(&text).map_err(|e| e.to_string())
+    };
+    symbols.map_err(|e| format!("Could not load symbols {:?}: {}", path, e))
+}
+
+/// Looks up names for ROM and RAM addresses.  Where several symbols share an address, the first
+/// one which isn't predefined wins.
+#[derive(Debug, Default)]
+pub struct Names {
+    rom: HashMap<u16, String>,
+    ram: HashMap<u16, String>,
+    addresses: HashMap<String, Symbol>,
+}
+
+impl Names {
+    pub fn new(symbols: &[Symbol]) -> Names {
+        let mut names = Names::default();
+        for symbol in symbols {
+            let addresses = if symbol.kind.in_rom() {
+                &mut names.rom
+            } else {
+                &mut names.ram
+            };
+            match addresses.get(&symbol.address) {
+                Some(name) if names.addresses[name].kind != SymbolKind::Predefined => {}
+                Some(_) if symbol.kind == SymbolKind::Predefined => {}
+                _ => {
+                    addresses.insert(symbol.address, symbol.name.clone());
+                }
+            }
+            names.addresses.insert(symbol.name.clone(), symbol.clone());
+        }
+        names
+    }
+
+    pub fn rom(&self, address: u16) -> Option<&str> {
+        self.rom.get(&address).map(|x| x.as_str())
+    }
+
+    pub fn ram(&self, address: u16) -> Option<&str> {
+        self.ram.get(&address).map(|x| x.as_str())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.addresses.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::SymbolKind;
+    use crate::parser::parse_lines;
+    use crate::preprocessor::Preprocessable;
+    use crate::symbols::{parse_sym, symbols, to_json, to_sym, Names, Symbol};
+    use crate::types::Line;
+
+    fn program() -> Vec<Symbol> {
+        let lines = Line::read(
+            "main.asm",
+            "#call f\n(END)\n@END\n0;JMP\n(f)\n@x\nM=1\n#ret",
+        );
+        symbols(&parse_lines(lines.preprocess()))
+    }
+
+    fn symbol(name: &str, kind: SymbolKind, address: u16) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            kind,
+            address,
+        }
+    }
+
+    #[test]
+    fn finds_symbols() {
+        let symbols = program();
+        assert_eq!(symbols.len(), 23 + 4);
+        assert_eq!(symbols[0], symbol("R0", SymbolKind::Predefined, 0));
+        assert_eq!(
+            &symbols[23..],
+            &[
+                symbol("END", SymbolKind::Label, 12),
+                symbol("RETURN$0", SymbolKind::Generated, 12),
+                symbol("f", SymbolKind::Label, 14),
+                symbol("x", SymbolKind::Variable, 16),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let symbols = program();
+        assert_eq!(
+            serde_json::from_str::<Vec<Symbol>>(&to_json(&symbols)).unwrap(),
+            symbols
+        );
+        assert!(to_json(&symbols).contains("\"kind\": \"macro-generated\""));
+
+        let sym = to_sym(&symbols);
+        assert!(sym.contains("\n00:000C RETURN$0\n"));
+        assert!(sym.contains("\n01:0010 x\n"));
+        let loaded = parse_sym(&sym).unwrap();
+        assert_eq!(loaded[0], symbol("R0", SymbolKind::Predefined, 0));
+        assert_eq!(loaded[24], symbol("RETURN$0", SymbolKind::Label, 12));
+        assert_eq!(loaded[26], symbol("x", SymbolKind::Variable, 16));
+        assert!(parse_sym("00:zz x").is_err());
+    }
+
+    #[test]
+    fn names_addresses() {
+        let names = Names::new(&program());
+        assert_eq!(names.rom(12), Some("END"));
+        assert_eq!(names.rom(14), Some("f"));
+        assert_eq!(names.ram(16), Some("x"));
+        assert_eq!(names.ram(0), Some("R0"));
+        assert_eq!(names.ram(12), Some("R12"));
+        assert_eq!(names.get("x").map(|x| x.address), Some(16));
+    }
+}
@@ -0,0 +1,384 @@
+use crate::assembler::{decode, SymbolKind};
+use crate::emulator::Emulator;
+use crate::symbols::Symbol;
+use crate::types::{Instruction, Line};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Read, Write};
+use std::ops::RangeInclusive;
+
+/// The first line of every trace file.
+const MAGIC: &str = "HACKTRACE 1";
+
+/// The state after one cycle: the address of the instruction executed, the registers it left
+/// behind and the word of RAM it wrote, if any.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Record {
+    pub cycle: u64,
+    pub pc: u16,
+    pub a: u16,
+    pub d: u16,
+    pub write: Option<(u16, u16)>,
+}
+
+impl Record {
+    fn write_to(&self, output: &mut impl Write) -> io::Result<()> {
+        output.write_all(&self.cycle.to_le_bytes())?;
+        for word in &[self.pc, self.a, self.d] {
+            output.write_all(&word.to_le_bytes())?;
+        }
+        match self.write {
+            Some((address, value)) => {
+                output.write_all(&[1])?;
+                output.write_all(&address.to_le_bytes())?;
+                output.write_all(&value.to_le_bytes())
+            }
+            None => output.write_all(&[0]),
+        }
+    }
+
+    /// Reads the next record, or gives `None` at the end of the input.
+    fn read_from(input: &mut impl Read) -> io::Result<Option<Record>> {
+        let mut cycle = [0; 8];
+        match input.read_exact(&mut cycle) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let mut word = || -> io::Result<u16> {
+            let mut bytes = [0; 2];
+            input.read_exact(&mut bytes)?;
+            Ok(u16::from_le_bytes(bytes))
+        };
+        let (pc, a, d) = (word()?, word()?, word()?);
+        let mut flag = [0];
+        input.read_exact(&mut flag)?;
+        let write = match flag[0] {
+            0 => None,
+            _ => {
+                let mut bytes = [0; 4];
+                input.read_exact(&mut bytes)?;
+                Some((
+                    u16::from_le_bytes([bytes[0], bytes[1]]),
+                    u16::from_le_bytes([bytes[2], bytes[3]]),
+                ))
+            }
+        };
+        Ok(Some(Record {
+            cycle: u64::from_le_bytes(cycle),
+            pc,
+            a,
+            d,
+            write,
+        }))
+    }
+}
+
+/// Which cycles to keep.  An empty list of ROM ranges keeps every instruction.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Filter {
+    pub rom: Vec<RangeInclusive<u16>>,
+    /// Only keep cycles which write into this range of RAM.
+    pub ram: Option<RangeInclusive<u16>>,
+}
+
+impl Filter {
+    fn matches(&self, record: &Record) -> bool {
+        (self.rom.is_empty() || self.rom.iter().any(|x| x.contains(&record.pc)))
+            && self.ram.as_ref().is_none_or(|range| {
+                record
+                    .write
+                    .is_some_and(|(address, _)| range.contains(&address))
+            })
+    }
+}
+
+/// Parses a range of addresses written `LO-HI`, or a single address.
+pub fn parse_range(text: &str) -> Result<RangeInclusive<u16>, String> {
+    let address = |x: &str| {
+        x.trim()
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid address {:?} in range {:?}", x, text))
+    };
+    let (lo, hi) = match text.split_once('-') {
+        Some((lo, hi)) => (address(lo)?, address(hi)?),
+        None => (address(text)?, address(text)?),
+    };
+    if lo > hi {
+        return Err(format!("Range {:?} is backwards", text));
+    }
+    Ok(lo..=hi)
+}
+
+/// The ROM from a label up to the next label the user wrote, so a label filter covers the loops
+/// and `#call` expansions inside it.
+pub fn label_range(
+    symbols: &[Symbol],
+    name: &str,
+    rom_size: usize,
+) -> Result<RangeInclusive<u16>, String> {
+    let start = symbols
+        .iter()
+        .find(|x| x.name == name && x.kind.in_rom())
+        .ok_or_else(|| format!("{} is not a label", name))?
+        .address;
+    let end = symbols
+        .iter()
+        .filter(|x| x.kind == SymbolKind::Label && x.address > start)
+        .map(|x| x.address)
+        .min()
+        .unwrap_or(rom_size as u16);
+    Ok(start..=end.max(start + 1) - 1)
+}
+
+/// What is at a ROM address, so a trace can be read without the program it came from.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Source {
+    pub instruction: String,
+    /// Where the instruction came from, e.g. `main.asm:3`, or empty if unknown.
+    pub location: String,
+}
+
+/// Describes every instruction in a ROM, using the program it was assembled from if there is one.
+pub fn sources(rom: &[u16], program: &[(Instruction, Line)]) -> Vec<Source> {
+    let mut locations = program
+        .iter()
+        .filter(|(x, _)| x.takes_rom())
+        .map(|(_, line)| match (line.file.as_ref(), line.number) {
+            ("", 0) => "<prologue>".to_string(),
+            (file, number) => format!("{}:{}", file, number),
+        });
+    rom.iter()
+        .map(|word| Source {
+            instruction: decode(*word).map_or(format!("{:016b}", word), |x| x.to_string()),
+            location: locations.next().unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Runs the emulator until it halts or has executed `max_cycles` instructions, writing a trace
+/// of the cycles the filter keeps.  Gives the number of records written.
+pub fn record(
+    emulator: &mut Emulator,
+    sources: &[Source],
+    filter: &Filter,
+    max_cycles: u64,
+    output: &mut impl Write,
+) -> io::Result<u64> {
+    writeln!(output, "{}", MAGIC)?;
+    writeln!(output, "{}", serde_json::to_string(sources)?)?;
+    let mut count = 0;
+    let start = emulator.cycles;
+    while emulator.cycles - start < max_cycles && !emulator.halted() {
+        let pc = emulator.pc;
+        let write = emulator.step();
+        let record = Record {
+            cycle: emulator.cycles,
+            pc,
+            a: emulator.a,
+            d: emulator.d,
+            write: write.map(|x| (x.address, x.new)),
+        };
+        if filter.matches(&record) {
+            record.write_to(output)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Trace {
+    pub sources: Vec<Source>,
+    pub records: Vec<Record>,
+}
+
+impl Trace {
+    pub fn read(mut input: impl BufRead) -> Result<Trace, String> {
+        let mut line = String::new();
+        input.read_line(&mut line).map_err(|e| e.to_string())?;
+        if line.trim_end() != MAGIC {
+            return Err("Not a trace file".to_string());
+        }
+        line.clear();
+        input.read_line(&mut line).map_err(|e| e.to_string())?;
+        let sources = serde_json::from_str(&line).map_err(|e| e.to_string())?;
+        let mut records = vec![];
+        while let Some(record) = Record::read_from(&mut input).map_err(|e| e.to_string())? {
+            records.push(record);
+        }
+        Ok(Trace { sources, records })
+    }
+
+    /// A line describing a record, with the instruction and where it came from.
+    pub fn describe(&self, record: &Record) -> String {
+        let source = self
+            .sources
+            .get(record.pc as usize)
+            .cloned()
+            .unwrap_or_default();
+        let write = match record.write {
+            Some((address, value)) => format!("RAM[{}]={}", address, value as i16),
+            None => String::new(),
+        };
+        format!(
+            "{:>10}  {:>5}  {:<16}  A={:<5} D={:<6}  {:<18}  {}",
+            record.cycle,
+            record.pc,
+            source.instruction,
+            record.a,
+            record.d as i16,
+            write,
+            source.location
+        )
+        .trim_end()
+        .to_string()
+    }
+}
+
+/// Finds the first place two traces differ and describes it, or gives `None` if they are the same.
+/// With `writes_only` only the RAM writes are compared, which lets traces of different builds of
+/// a program be compared even though they take different paths to the same result.
+pub fn diff(a: &Trace, b: &Trace, writes_only: bool) -> Option<String> {
+    let records = |trace: &Trace| {
+        trace
+            .records
+            .iter()
+            .filter(|x| !writes_only || x.write.is_some())
+            .copied()
+            .collect::<Vec<Record>>()
+    };
+    let (first, second) = (records(a), records(b));
+    let same = |x: &Record, y: &Record| {
+        if writes_only {
+            x.write == y.write
+        } else {
+            (x.pc, x.a, x.d, x.write) == (y.pc, y.a, y.d, y.write)
+        }
+    };
+    let index =
+        (0..first.len().max(second.len())).find(|i| match (first.get(*i), second.get(*i)) {
+            (Some(x), Some(y)) => !same(x, y),
+            _ => true,
+        })?;
+    let describe = |trace: &Trace, record: Option<&Record>| match record {
+        Some(record) => trace.describe(record),
+        None => "<end of trace>".to_string(),
+    };
+    let what = if writes_only { "write" } else { "record" };
+    Some(format!(
+        "First difference at {} {}\na: {}\nb: {}",
+        what,
+        index,
+        describe(a, first.get(index)),
+        describe(b, second.get(index))
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assemblable;
+    use crate::emulator::Emulator;
+    use crate::optimizer::Optimizable;
+    use crate::parser::parse_lines;
+    use crate::preprocessor::Preprocessable;
+    use crate::symbols::symbols;
+    use crate::trace::{diff, label_range, parse_range, record, sources, Filter, Record, Trace};
+    use crate::types::{Instruction, Line};
+
+    const PROGRAM: &str =
+        "@3\nD=A\n@i\nM=D\n(LOOP)\n@i\nMD=M-1\n@LOOP\nD;JGT\n@j\nM=1\n@j\nM=M+1\n(END)\n@END\n0;JMP";
+
+    fn trace(filter: &Filter, optimize: bool) -> Trace {
+        let mut program = parse_lines(Line::read("main.asm", PROGRAM).preprocess());
+        if optimize {
+            program = program.optimize();
+        }
+        let rom = program
+            .iter()
+            .map(|(x, _)| x.clone())
+            .collect::<Vec<Instruction>>()
+            .assemble();
+        let mut output = vec![];
+        record(
+            &mut Emulator::new(rom.clone()),
+            &sources(&rom, &program),
+            filter,
+            1000,
+            &mut output,
+        )
+        .unwrap();
+        Trace::read(output.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn records_and_reads() {
+        let trace = trace(&Filter::default(), false);
+        assert_eq!(trace.records.len(), 3 + 4 + 3 * 4 + 4);
+        assert_eq!(
+            trace.records[6],
+            Record {
+                cycle: 7,
+                pc: 6,
+                a: 16,
+                d: 3,
+                write: Some((16, 3))
+            }
+        );
+        assert_eq!(
+            trace.describe(&trace.records[6]),
+            "         7      6  M=D               A=16    D=3       RAM[16]=3           main.asm:4"
+        );
+    }
+
+    #[test]
+    fn filters_records() {
+        let in_loop = Filter {
+            rom: vec![7..=10],
+            ram: None,
+        };
+        assert_eq!(trace(&in_loop, false).records.len(), 3 * 4);
+        let writes = Filter {
+            rom: vec![],
+            ram: Some(16..=16),
+        };
+        let records = trace(&writes, false).records;
+        assert_eq!(
+            records
+                .iter()
+                .map(|x| x.write.unwrap().1)
+                .collect::<Vec<u16>>(),
+            vec![3, 2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn finds_differences() {
+        let before = trace(&Filter::default(), false);
+        let after = trace(&Filter::default(), true);
+        assert_eq!(diff(&before, &before, false), None);
+        // the optimizer drops the second @j, which changes the path but not what is written
+        let difference = diff(&before, &after, false).unwrap();
+        assert!(difference.starts_with("First difference at record 21\n"));
+        assert!(difference.contains("M=M+1"));
+        assert!(difference.ends_with("main.asm:13"));
+        assert_eq!(diff(&before, &after, true), None);
+
+        let mut truncated = after.clone();
+        truncated.records.pop();
+        let difference = diff(&before, &truncated, true).unwrap();
+        assert!(difference.starts_with("First difference at write 6\n"));
+        assert!(difference.ends_with("\nb: <end of trace>"));
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("16-31"), Ok(16..=31));
+        assert_eq!(parse_range("5"), Ok(5..=5));
+        assert!(parse_range("9-2").is_err());
+        assert!(parse_range("a-2").is_err());
+        let program = parse_lines(Line::read("main.asm", PROGRAM).preprocess());
+        let symbols = symbols(&program);
+        assert_eq!(label_range(&symbols, "LOOP", 17), Ok(7..=14));
+        assert_eq!(label_range(&symbols, "END", 17), Ok(15..=16));
+        assert!(label_range(&symbols, "i", 17).is_err());
+    }
+}
@@ -1,3 +1,8 @@
+use crate::expression::{is_symbol_char, Expression};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Register {
     A,
@@ -7,12 +12,80 @@ pub enum Register {
 }
 impl From<&str> for Register {
     fn from(val: &str) -> Self {
-        return match val {
+        match val {
             "A" => Register::A,
             "D" => Register::D,
             "M" => Register::M,
             _ => Register::None,
-        };
+        }
+    }
+}
+
+impl Register {
+    /// The bit this register sets in the destination field of a C-instruction.
+    pub fn dest_bit(self) -> u16 {
+        match self {
+            Register::A => 0b100,
+            Register::D => 0b010,
+            Register::M => 0b001,
+            Register::None => 0,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::A => write!(f, "A"),
+            Register::D => write!(f, "D"),
+            Register::M => write!(f, "M"),
+            Register::None => Ok(()),
+        }
+    }
+}
+
+/// The largest value an A-instruction can hold, as the top bit selects a C-instruction.
+pub const MAX_ADDRESS: u16 = 0x7FFF;
+
+/// Parses a decimal (`-12`), hexadecimal (`0x4000`), binary (`0b1010`) or character (`'A'`)
+/// literal.  Returns `None` if the text is not a literal at all and should be treated as a symbol.
+pub fn parse_literal(val: &str) -> Option<Result<i32, String>> {
+    let (negative, digits) = match val.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, val),
+    };
+    let parsed = match digits {
+        d if d.len() > 2 && (d.starts_with("0x") || d.starts_with("0X")) => {
+            i32::from_str_radix(&d[2..], 16)
+        }
+        d if d.len() > 2 && (d.starts_with("0b") || d.starts_with("0B")) => {
+            i32::from_str_radix(&d[2..], 2)
+        }
+        d if d.starts_with('\'') => {
+            let mut chars = d.chars().skip(1);
+            return match (chars.next(), chars.next(), chars.next(), negative) {
+                (Some(ch), Some('\''), None, false) => Some(Ok(ch as i32)),
+                _ => Some(Err(format!("Could not parse character literal {}", val))),
+            };
+        }
+        d if d.starts_with(|ch: char| ch.is_ascii_digit()) || negative => d.parse::<i32>(),
+        _ => return None,
+    };
+    Some(
+        parsed
+            .map(|x| if negative { -x } else { x })
+            .map_err(|_| format!("Could not parse literal {}", val)),
+    )
+}
+
+/// Parses a literal as a 16-bit word.  Negative values are stored in two's complement so anything
+/// from -32768 to 65535 is accepted.
+pub fn parse_word(val: &str) -> Result<u16, String> {
+    match parse_literal(val) {
+        Some(Ok(x)) if (-32768..=65535).contains(&x) => Ok(x as u16),
+        Some(Ok(_)) => Err(format!("Literal {} does not fit in 16 bits", val)),
+        Some(Err(e)) => Err(e),
+        None => Err(format!("Expected a literal but found {}", val)),
     }
 }
 
@@ -20,15 +93,61 @@ impl From<&str> for Register {
 pub enum Location {
     Address(u16),
     Label(String),
+    /// An expression using symbols, which is worked out once they have addresses.  Expressions of
+    /// just literals are worked out straight away.
+    Expression(Expression),
 }
 
-impl From<&str> for Location {
-    fn from(val: &str) -> Self {
-        let parsed = val.parse::<u16>();
-        return match parsed {
-            Ok(x) => Location::Address(x),
-            Err(_) => Location::Label(val.into()),
+/// Checks the value of an A-instruction fits in 15 bits.
+pub fn check_address(text: &dyn fmt::Display, value: i64) -> Result<u16, String> {
+    if (0..=MAX_ADDRESS as i64).contains(&value) {
+        Ok(value as u16)
+    } else {
+        Err(format!(
+            "{} is {}, which does not fit in an A-instruction (0 to {})",
+            text, value, MAX_ADDRESS
+        ))
+    }
+}
+
+impl TryFrom<&str> for Location {
+    type Error = String;
+
+    fn try_from(val: &str) -> Result<Self, Self::Error> {
+        // a single literal or symbol, rather than an expression
+        let word = val.strip_prefix('-').unwrap_or(val);
+        let single = match word.strip_prefix('\'') {
+            Some(rest) => rest.chars().count() <= 2,
+            None => !word.is_empty() && word.chars().all(is_symbol_char),
         };
+        if !single {
+            let expression = Expression::parse(val)?;
+            return if expression.symbols().is_empty() {
+                let value = expression.evaluate(&|_| None)?;
+                Ok(Location::Address(check_address(&expression, value)?))
+            } else {
+                Ok(Location::Expression(expression))
+            };
+        }
+        match parse_literal(val) {
+            Some(Ok(x)) if (0..=MAX_ADDRESS as i32).contains(&x) => Ok(Location::Address(x as u16)),
+            Some(Ok(_)) => Err(format!(
+                "Literal {} does not fit in an A-instruction (0 to {})",
+                val, MAX_ADDRESS
+            )),
+            Some(Err(e)) => Err(e),
+            None => Ok(Location::Label(val.into())),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::Address(x) => write!(f, "{}", x),
+            Location::Label(x) => write!(f, "{}", x),
+            Location::Expression(x) => write!(f, "{}", x),
+        }
     }
 }
 
@@ -42,24 +161,34 @@ pub enum Source {
 
 impl From<&str> for Source {
     fn from(val: &str) -> Self {
-        return match val {
+        match val {
             "A" => Source::Register(Register::A),
             "D" => Source::Register(Register::D),
             "M" => Source::Register(Register::M),
             "0" => Source::Zero,
             "1" => Source::One,
             _ => Source::None,
-        };
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Register(x) => write!(f, "{}", x),
+            Source::One => write!(f, "1"),
+            Source::Zero => write!(f, "0"),
+            Source::None => Ok(()),
+        }
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Operation {
-    Nop,
     Not,
+    /// `-`, which negates with one source (`-D`) and subtracts with two (`D-A`).
     Negative,
     Add,
-    Subtract,
     And,
     Or,
     None,
@@ -67,17 +196,31 @@ pub enum Operation {
 
 impl From<&str> for Operation {
     fn from(val: &str) -> Self {
-        return match val {
+        match val {
             "-" => Operation::Negative,
             "!" => Operation::Not,
             "+" => Operation::Add,
             "&" => Operation::And,
             "|" => Operation::Or,
             _ => Operation::None,
-        };
+        }
     }
 }
 
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Not => write!(f, "!"),
+            Operation::Negative => write!(f, "-"),
+            Operation::Add => write!(f, "+"),
+            Operation::And => write!(f, "&"),
+            Operation::Or => write!(f, "|"),
+            Operation::None => Ok(()),
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Jump {
     JGT,
@@ -92,7 +235,7 @@ pub enum Jump {
 
 impl From<&str> for Jump {
     fn from(val: &str) -> Self {
-        return match val.to_ascii_uppercase().as_ref() {
+        match val.to_ascii_uppercase().as_ref() {
             "JGT" => Jump::JGT,
             "JEQ" => Jump::JEQ,
             "JGE" => Jump::JGE,
@@ -101,13 +244,153 @@ impl From<&str> for Jump {
             "JLE" => Jump::JLE,
             "JMP" => Jump::JMP,
             _ => Jump::None,
-        };
+        }
+    }
+}
+
+impl Jump {
+    /// The jump field of a C-instruction, one bit each for jumping when the result is less than,
+    /// equal to and greater than zero.
+    pub fn bits(self) -> u16 {
+        match self {
+            Jump::None => 0b000,
+            Jump::JGT => 0b001,
+            Jump::JEQ => 0b010,
+            Jump::JGE => 0b011,
+            Jump::JLT => 0b100,
+            Jump::JNE => 0b101,
+            Jump::JLE => 0b110,
+            Jump::JMP => 0b111,
+        }
     }
 }
+
+impl fmt::Display for Jump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Jump::None => Ok(()),
+            jump => write!(f, "{:?}", jump),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Computation {
     Computation(Source, Source, Operation),
-    None,
+}
+
+impl Computation {
+    /// The `a` bit followed by the six ALU control bits of a C-instruction, or `None` if the ALU
+    /// can't compute this.
+    pub fn bits(&self) -> Option<u16> {
+        Some(match self.to_string().as_ref() {
+            "0" => 0b0_101010,
+            "1" => 0b0_111111,
+            "-1" => 0b0_111010,
+            "D" => 0b0_001100,
+            "A" => 0b0_110000,
+            "!D" => 0b0_001101,
+            "!A" => 0b0_110001,
+            "-D" => 0b0_001111,
+            "-A" => 0b0_110011,
+            "D+1" => 0b0_011111,
+            "A+1" => 0b0_110111,
+            "D-1" => 0b0_001110,
+            "A-1" => 0b0_110010,
+            "D+A" | "A+D" => 0b0_000010,
+            "D-A" => 0b0_010011,
+            "A-D" => 0b0_000111,
+            "D&A" | "A&D" => 0b0_000000,
+            "D|A" | "A|D" => 0b0_010101,
+            "M" => 0b1_110000,
+            "!M" => 0b1_110001,
+            "-M" => 0b1_110011,
+            "M+1" => 0b1_110111,
+            "M-1" => 0b1_110010,
+            "D+M" | "M+D" => 0b1_000010,
+            "D-M" => 0b1_010011,
+            "M-D" => 0b1_000111,
+            "D&M" | "M&D" => 0b1_000000,
+            "D|M" | "M|D" => 0b1_010101,
+            _ => return None,
+        })
+    }
+
+    /// Whether the computation reads the given register.
+    pub fn uses(&self, register: Register) -> bool {
+        let Computation::Computation(lhs, rhs, _) = self;
+        *lhs == Source::Register(register) || *rhs == Source::Register(register)
+    }
+}
+
+impl fmt::Display for Computation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Computation::Computation(lhs, Source::None, op) => write!(f, "{}{}", op, lhs),
+            Computation::Computation(lhs, rhs, op) => write!(f, "{}{}{}", lhs, op, rhs),
+        }
+    }
+}
+
+/// A variable declared with `#var`, which is given `size` words of RAM, at `address` if it is
+/// pinned to one.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Variable {
+    pub name: String,
+    pub size: u16,
+    pub address: Option<u16>,
+}
+
+impl Variable {
+    /// Parses the arguments of `#var NAME`, `#var NAME[SIZE]` or either of them followed by
+    /// `@ ADDRESS`, where the size and address are constant expressions.
+    pub fn parse(text: &str) -> Result<Variable, String> {
+        let constant = |text: &str| -> Result<i64, String> {
+            let expression = Expression::parse(text)?;
+            expression.evaluate(&|_| None)
+        };
+        let (declaration, address) = match text.split_once('@') {
+            Some((declaration, address)) => (declaration, Some(constant(address)?)),
+            None => (text, None),
+        };
+        let declaration = declaration.trim();
+        let (name, size) = match declaration.split_once('[') {
+            Some((name, size)) => match size.strip_suffix(']') {
+                Some(size) => (name.trim(), constant(size)?),
+                None => return Err(format!("Expected ] after {}", declaration)),
+            },
+            None => (declaration, 1),
+        };
+        let valid = !name.is_empty() && !name.starts_with(|x: char| x.is_ascii_digit());
+        if !valid || !name.chars().all(is_symbol_char) {
+            return Err(format!("Bad variable name {:?}", name));
+        }
+        if !(1..=MAX_ADDRESS as i64 + 1).contains(&size) {
+            return Err(format!("{} can't be {} words long", name, size));
+        }
+        let address = match address {
+            Some(address) => Some(check_address(&format!("The address of {}", name), address)?),
+            None => None,
+        };
+        Ok(Variable {
+            name: name.to_string(),
+            size: size as u16,
+            address,
+        })
+    }
+}
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if self.size != 1 {
+            write!(f, "[{}]", self.size)?;
+        }
+        if let Some(address) = self.address {
+            write!(f, " @ {}", address)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -115,17 +398,30 @@ pub enum Macro {
     Call(String),
     Return,
     Include(String),
+    Var(Variable),
     None,
 }
 
 impl From<(&str, &str)> for Macro {
     fn from(val: (&str, &str)) -> Self {
-        return match (val.0.to_ascii_lowercase().as_ref(), val.1) {
+        match (val.0.to_ascii_lowercase().as_ref(), val.1) {
             ("call", arg) => Macro::Call(arg.into()),
             ("ret", _) => Macro::Return,
             ("include", arg) => Macro::Include(arg.into()),
             _ => Macro::None,
-        };
+        }
+    }
+}
+
+impl fmt::Display for Macro {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Macro::Call(x) => write!(f, "#call {}", x),
+            Macro::Return => write!(f, "#ret"),
+            Macro::Include(x) => write!(f, "#include {}", x),
+            Macro::Var(x) => write!(f, "#var {}", x),
+            Macro::None => Ok(()),
+        }
     }
 }
 
@@ -133,5 +429,70 @@ impl From<(&str, &str)> for Macro {
 pub enum Instruction {
     A(Location),
     C(Vec<Register>, Computation, Jump),
+    Label(String),
     Macro(Macro),
 }
+
+impl Instruction {
+    /// Whether the instruction is assembled into a word of ROM, rather than being a label or a
+    /// `#var` declaration.
+    pub fn takes_rom(&self) -> bool {
+        !matches!(
+            self,
+            Instruction::Label(_) | Instruction::Macro(Macro::Var(_))
+        )
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::A(x) => write!(f, "@{}", x),
+            Instruction::C(dest, comp, jump) => {
+                for register in dest {
+                    write!(f, "{}", register)?;
+                }
+                if !dest.is_empty() {
+                    write!(f, "=")?;
+                }
+                write!(f, "{}", comp)?;
+                if *jump != Jump::None {
+                    write!(f, ";{}", jump)?;
+                }
+                Ok(())
+            }
+            Instruction::Label(x) => write!(f, "({})", x),
+            Instruction::Macro(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// A line of ASM along with where it came from, so later stages can point back at the source.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Line {
+    pub text: String,
+    /// The file the line was read from, or empty for code generated by the preprocessor itself.
+    pub file: String,
+    /// The line number within the file, starting from 1.
+    pub number: usize,
+    /// The directive this line was expanded from, e.g. `#call f`.
+    pub directive: Option<String>,
+    /// Whether the file was pulled in by `#include`.
+    pub included: bool,
+}
+
+impl Line {
+    /// Splits the contents of a file into lines.
+    pub fn read(file: &str, text: &str) -> Vec<Line> {
+        text.split('\n')
+            .enumerate()
+            .map(|(i, x)| Line {
+                text: x.trim_end_matches('\r').to_string(),
+                file: file.to_string(),
+                number: i + 1,
+                directive: None,
+                included: false,
+            })
+            .collect()
+    }
+}
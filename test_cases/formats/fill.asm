@@ -0,0 +1,20 @@
+// Fills the first row of the screen with black, then halts.  Long enough to need more than one
+// line or record in each format.
+@SCREEN
+D=A
+@address
+M=D
+(LOOP)
+#load D, -1
+@address
+A=M
+M=D
+@address
+MD=M+1
+@16416
+D=D-A
+@LOOP
+D;JLT
+(END)
+@END
+0;JMP
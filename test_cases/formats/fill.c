@@ -0,0 +1,7 @@
+#include <stdint.h>
+
+const uint16_t rom[19] = {
+    0x3fff, 0xec90, 0xe308, 0x4000, 0xec10, 0x0010, 0xe308, 0xee90,
+    0x0010, 0xfc20, 0xe308, 0x0010, 0xfdd8, 0x4020, 0xe4d0, 0x0007,
+    0xe304, 0x0011, 0xea87,
+};
@@ -0,0 +1,11 @@
+// Runs fill.asm, checking it blacks out the 32 words of the screen's first row and no more.
+
+load fill.hack,
+output-file fill.out,
+compare-to fill.cmp,
+output-list RAM[16384]%X3.4.3 RAM[16415]%X3.4.3 RAM[16416]%X3.4.3 RAM[16]%D1.6.1;
+
+repeat 400 {
+  ticktock;
+}
+output;
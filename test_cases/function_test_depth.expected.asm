@@ -0,0 +1,82 @@
+@16383
+D=A-1
+M=D
+// JUMPING TO LABEL TEST1
+// STORE RETURN ADDRESS
+@RETURN$0
+D=A
+@16383
+A=M
+M=D
+@16383
+M=M-1
+// STORED
+@TEST1
+0;JMP
+(RETURN$0)
+
+@END
+(END)
+0;JMP
+
+(TEST1)
+@5
+D=A
+@0
+M=D
+// JUMPING TO LABEL TEST2
+// STORE RETURN ADDRESS
+@RETURN$1
+D=A
+@16383
+A=M
+M=D
+@16383
+M=M-1
+// STORED
+@TEST2
+0;JMP
+(RETURN$1)
+// RETURN FROM STORED ADDRESS
+@16383
+AM=M+1
+A=M
+0;JMP
+// RETURNED
+
+(TEST2)
+@5
+D=A
+@1
+M=D
+// JUMPING TO LABEL TEST3
+// STORE RETURN ADDRESS
+@RETURN$2
+D=A
+@16383
+A=M
+M=D
+@16383
+M=M-1
+// STORED
+@TEST3
+0;JMP
+(RETURN$2)
+// RETURN FROM STORED ADDRESS
+@16383
+AM=M+1
+A=M
+0;JMP
+// RETURNED
+
+(TEST3)
+@5
+D=A
+@2
+M=D
+// RETURN FROM STORED ADDRESS
+@16383
+AM=M+1
+A=M
+0;JMP
+// RETURNED
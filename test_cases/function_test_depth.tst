@@ -0,0 +1,13 @@
+// Runs function_test_depth.asm, which calls three functions each nested inside the last.  Each
+// sets one of RAM[0..2] to 5, and the stack pointer at RAM[16383] should be back where it started.
+
+load function_test_depth.hack,
+output-file function_test_depth.out,
+compare-to function_test_depth.cmp,
+output-list RAM[0]%D2.6.2 RAM[1]%D2.6.2 RAM[2]%D2.6.2 RAM[16383]%D2.6.2;
+
+output;
+repeat 100 {
+  ticktock;
+}
+output;
@@ -0,0 +1,32 @@
+@16383
+D=A-1
+M=D
+// JUMPING TO LABEL TEST1
+// STORE RETURN ADDRESS
+@RETURN$0
+D=A
+@16383
+A=M
+M=D
+@16383
+M=M-1
+// STORED
+@TEST1
+0;JMP
+(RETURN$0)
+
+@END
+(END)
+0;JMP
+
+(TEST1)
+@5
+D=A
+@0
+M=D
+// RETURN FROM STORED ADDRESS
+@16383
+AM=M+1
+A=M
+0;JMP
+// RETURNED
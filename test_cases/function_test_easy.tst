@@ -0,0 +1,13 @@
+// Runs function_test_easy.asm, which calls a function setting RAM[0] to 5.
+
+load function_test_easy.hack,
+output-file function_test_easy.out,
+compare-to function_test_easy.cmp,
+output-list RAM[0]%D2.6.2 RAM[16383]%D2.6.2 PC%D2.6.2;
+
+set RAM[0] -1;
+output;
+repeat 40 {
+  ticktock;
+}
+output;
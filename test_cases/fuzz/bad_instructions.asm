@@ -0,0 +1,5 @@
+@32768
+@-1
+D=D*A
+AMDM=1
+0;JMPX
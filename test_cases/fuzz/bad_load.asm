@@ -0,0 +1,3 @@
+#load D
+#load A, 0x10000
+#load M, 1
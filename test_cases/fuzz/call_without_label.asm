@@ -0,0 +1 @@
+#call
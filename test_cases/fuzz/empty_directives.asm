@@ -0,0 +1,3 @@
+#data
+#string
+#load
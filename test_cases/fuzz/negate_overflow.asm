@@ -0,0 +1 @@
+@-(-(32768*32768*32768*32768*4)*2)
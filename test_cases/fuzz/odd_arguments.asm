@@ -0,0 +1,4 @@
+#ret extra words
+#call f g
+(f)
+#frobnicate
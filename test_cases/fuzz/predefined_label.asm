@@ -0,0 +1,2 @@
+(SP)
+@SP
@@ -0,0 +1,4 @@
+#DATAé 1
+#lé
+é#call f
+#string 🦀 "🦀"
@@ -0,0 +1,2 @@
+#string S "
+#string S ""x
@@ -0,0 +1,467 @@
+//! Golden tests over every program in `test_cases`.  Each `x.asm` is preprocessed and assembled by
+//! the `hack-asm` binary and checked against `x.expected.asm` and `x.hack`, and if there is an
+//! `x.tst` test script it is run on the emulator and checked against the file it compares to.
+//! Programs in `test_cases/formats` are checked in every output format as well.
+//!
+//! `cargo test --test golden -- --bless` writes the expected files from the current output
+//! instead.
+
+use hack_asm::emulator::{read_rom, Emulator, RAM_SIZE};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::str;
+
+/// Programs in here are expected to fail, and are checked by the library's tests instead.
+const SKIPPED: &[&str] = &["fuzz"];
+
+/// Programs in `test_cases/formats` are also written in every other format `--format` takes, with
+/// these extensions.
+const FORMATS: [(&str, &str); 8] = [
+    ("raw-be", "be.bin"),
+    ("raw-le", "le.bin"),
+    ("ihex", "ihex"),
+    ("logisim", "logisim"),
+    ("memb", "memb"),
+    ("memh", "memh"),
+    ("c", "c"),
+    ("rust", "rs"),
+];
+
+fn main() {
+    let bless = std::env::args().any(|x| x == "--bless");
+    let mut programs = vec![];
+    find_programs(Path::new("test_cases"), &mut programs);
+    programs.sort();
+
+    let mut failures = vec![];
+    for program in &programs {
+        let result = check_program(program, bless);
+        println!(
+            "test {} ... {}",
+            program.display(),
+            if result.is_ok() { "ok" } else { "FAILED" }
+        );
+        if let Err(e) = result {
+            failures.push(format!("{}:\n{}", program.display(), e));
+        }
+    }
+    if let Err(e) = check_readme() {
+        failures.push(format!("README.md:\n{}", e));
+    }
+
+    if !failures.is_empty() {
+        println!("\nfailures:\n\n{}", failures.join("\n\n"));
+        println!("\nrun `cargo test --test golden -- --bless` if the new output is right");
+        process::exit(1);
+    }
+    println!("\n{} programs passed", programs.len());
+}
+
+fn find_programs(directory: &Path, programs: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(directory).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        if path.is_dir() {
+            if !SKIPPED.contains(&name.as_str()) {
+                find_programs(&path, programs);
+            }
+        } else if name.ends_with(".asm") && !name.ends_with(".expected.asm") {
+            programs.push(path);
+        }
+    }
+}
+
+fn hack_asm(args: &[&str]) -> Result<Vec<u8>, String> {
+    let output = Command::new(env!("CARGO_BIN_EXE_hack-asm"))
+        .args(args)
+        .output()
+        .map_err(|e| format!("Could not run hack-asm: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+    Ok(output.stdout)
+}
+
+/// Checks `actual` is what `path` holds, or writes it there when blessing.  Text is compared line
+/// by line, so line endings don't matter.
+fn compare(path: &Path, actual: &[u8], bless: bool) -> Result<(), String> {
+    if bless {
+        return fs::write(path, actual).map_err(|e| format!("Could not write {:?}: {}", path, e));
+    }
+    let expected = fs::read(path).map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+    let (expected, actual) = match (str::from_utf8(&expected), str::from_utf8(actual)) {
+        (Ok(expected), Ok(actual)) => (expected, actual),
+        _ if expected == actual => return Ok(()),
+        _ => {
+            let i = expected.iter().zip(actual).position(|(a, b)| a != b);
+            let i = i.unwrap_or_else(|| expected.len().min(actual.len()));
+            return Err(format!("{} differs at byte {}", path.display(), i));
+        }
+    };
+    match expected
+        .lines()
+        .zip(actual.lines())
+        .position(|(a, b)| a != b)
+    {
+        None if expected.lines().count() == actual.lines().count() => Ok(()),
+        i => {
+            let i = i.unwrap_or_else(|| expected.lines().count().min(actual.lines().count()));
+            Err(format!(
+                "{} differs at line {}\nexpected: {:?}\n  actual: {:?}",
+                path.display(),
+                i + 1,
+                expected.lines().nth(i).unwrap_or("<end of file>"),
+                actual.lines().nth(i).unwrap_or("<end of file>"),
+            ))
+        }
+    }
+}
+
+fn check_program(program: &Path, bless: bool) -> Result<(), String> {
+    let file = program.to_str().unwrap();
+    let preprocessed = hack_asm(&["-p", file])?;
+    compare(
+        &program.with_extension("expected.asm"),
+        &preprocessed,
+        bless,
+    )?;
+    let hack = hack_asm(&["-p", "-a", file])?;
+    compare(&program.with_extension("hack"), &hack, bless)?;
+    if program.parent().and_then(Path::file_name) == Some("formats".as_ref()) {
+        for (format, extension) in FORMATS {
+            let output = hack_asm(&["-p", "--format", format, file])?;
+            compare(&program.with_extension(extension), &output, bless)?;
+        }
+    }
+
+    let script = program.with_extension("tst");
+    if script.is_file() {
+        let text = fs::read_to_string(&script).unwrap();
+        let directory = script.parent().unwrap();
+        let test = Script::parse(&text).map_err(|e| format!("{}: {}", script.display(), e))?;
+        let output = test.run(directory)?;
+        let compare_to = test
+            .compare_to
+            .ok_or_else(|| format!("{} has no compare-to", script.display()))?;
+        compare(&directory.join(compare_to), output.as_bytes(), bless)?;
+    }
+    Ok(())
+}
+
+/// Checks the output shown after each `hack-asm -p <file>` in the README is what it gives.
+fn check_readme() -> Result<(), String> {
+    const COMMAND: &str = "`hack-asm -p ";
+    let readme = fs::read_to_string("README.md").unwrap();
+    let mut rest = readme.as_str();
+    while let Some(start) = rest.find(COMMAND) {
+        rest = &rest[start + COMMAND.len()..];
+        let file = &rest[..rest.find('`').unwrap()];
+        if file.starts_with('-') || file.contains(' ') {
+            // other options, which give something else
+            continue;
+        }
+        let block = rest.find("```\n").ok_or("Missing example output")? + 4;
+        rest = &rest[block..];
+        let end = rest.find("```").ok_or("Unterminated example output")?;
+        let expected = Path::new(file).with_extension("expected.asm");
+        compare(&expected, &rest.as_bytes()[..end], false)?;
+    }
+    Ok(())
+}
+
+/// A test script in the format of the Nand2Tetris CPU emulator, e.g.
+///
+/// ```text
+/// load Max.hack,
+/// compare-to Max.cmp,
+/// output-list RAM[0]%D2.6.2 RAM[1]%D2.6.2;
+/// set RAM[0] 3;
+/// repeat 14 { ticktock; }
+/// output;
+/// ```
+///
+/// Only the commands the CPU emulator uses for Hack programs are supported.  `output-file` is
+/// accepted but nothing is written to it, as the output is compared directly.
+struct Script {
+    load: Option<String>,
+    compare_to: Option<String>,
+    commands: Vec<Step>,
+}
+
+enum Step {
+    OutputList(Vec<Column>),
+    Set(Value, u16),
+    Ticktock,
+    Output,
+    Echo(String),
+    Repeat(usize, Vec<Step>),
+}
+
+#[derive(Clone, Copy)]
+enum Value {
+    Ram(u16),
+    A,
+    D,
+    Pc,
+}
+
+struct Column {
+    name: String,
+    value: Value,
+    format: char,
+    padding: (usize, usize),
+    width: usize,
+}
+
+fn value(name: &str) -> Result<Value, String> {
+    Ok(match name {
+        "A" => Value::A,
+        "D" => Value::D,
+        "PC" => Value::Pc,
+        _ => {
+            let address = name
+                .strip_prefix("RAM[")
+                .and_then(|x| x.strip_suffix(']'))
+                .and_then(|x| x.parse().ok())
+                .filter(|x| (*x as usize) < RAM_SIZE)
+                .ok_or_else(|| format!("Unknown value {}", name))?;
+            Value::Ram(address)
+        }
+    })
+}
+
+/// Parses a number as written in a script: decimal, or hex or binary after `%X` or `%B`.
+fn number(text: &str) -> Result<u16, String> {
+    let parsed = if let Some(hex) = text.strip_prefix("%X") {
+        i32::from_str_radix(hex, 16)
+    } else if let Some(binary) = text.strip_prefix("%B") {
+        i32::from_str_radix(binary, 2)
+    } else {
+        text.trim_start_matches("%D").parse()
+    };
+    parsed
+        .map(|x| x as u16)
+        .map_err(|_| format!("Could not parse number {}", text))
+}
+
+/// Parses `RAM[0]%D2.6.2`: a value, then how to format it and its padding, width and padding.
+fn column(text: &str) -> Result<Column, String> {
+    let error = || format!("Could not parse output column {}", text);
+    let (name, format) = text.split_at(text.find('%').ok_or_else(error)?);
+    let mut chars = format[1..].chars();
+    let kind = chars
+        .next()
+        .filter(|x| "DXBS".contains(*x))
+        .ok_or_else(error)?;
+    let sizes = chars
+        .as_str()
+        .split('.')
+        .map(|x| x.parse().map_err(|_| error()))
+        .collect::<Result<Vec<usize>, String>>()?;
+    match sizes[..] {
+        [left, width, right] => Ok(Column {
+            name: name.to_string(),
+            value: value(name)?,
+            format: kind,
+            padding: (left, right),
+            width,
+        }),
+        _ => Err(error()),
+    }
+}
+
+impl Script {
+    fn parse(text: &str) -> Result<Script, String> {
+        let mut script = Script {
+            load: None,
+            compare_to: None,
+            commands: vec![],
+        };
+        let mut words = tokens(text).into_iter();
+        script.commands = script.steps(&mut words, false)?;
+        Ok(script)
+    }
+
+    fn steps(
+        &mut self,
+        words: &mut impl Iterator<Item = String>,
+        nested: bool,
+    ) -> Result<Vec<Step>, String> {
+        let mut steps = vec![];
+        while let Some(word) = words.next() {
+            let mut argument = || words.next().ok_or(format!("Missing argument to {}", word));
+            match word.as_str() {
+                "," | ";" => {}
+                "}" if nested => return Ok(steps),
+                "load" => self.load = Some(argument()?),
+                "compare-to" => self.compare_to = Some(argument()?),
+                "output-file" => {
+                    argument()?;
+                }
+                "set" => {
+                    let target = value(&argument()?)?;
+                    steps.push(Step::Set(target, number(&argument()?)?));
+                }
+                "ticktock" => steps.push(Step::Ticktock),
+                "output" => steps.push(Step::Output),
+                "echo" => steps.push(Step::Echo(argument()?.trim_matches('"').to_string())),
+                "output-list" => {
+                    let mut columns = vec![];
+                    for word in words.by_ref() {
+                        if word == ";" || word == "," {
+                            break;
+                        }
+                        columns.push(column(&word)?);
+                    }
+                    steps.push(Step::OutputList(columns));
+                }
+                "repeat" => {
+                    let count = argument()?;
+                    let count = count
+                        .parse()
+                        .map_err(|_| format!("Could not parse repeat count {}", count))?;
+                    if words.next().as_deref() != Some("{") {
+                        return Err("Missing { after repeat".to_string());
+                    }
+                    steps.push(Step::Repeat(count, self.steps(words, true)?));
+                }
+                word => return Err(format!("Unknown command {}", word)),
+            }
+        }
+        if nested {
+            return Err("Missing } after repeat".to_string());
+        }
+        Ok(steps)
+    }
+
+    /// Runs the script, giving what it outputs.
+    fn run(&self, directory: &Path) -> Result<String, String> {
+        let load = self
+            .load
+            .as_ref()
+            .ok_or("The script doesn't load a program")?;
+        let path = directory.join(load);
+        let text =
+            fs::read_to_string(&path).map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+        let mut emulator = Emulator::new(read_rom(&text)?);
+        let mut columns = &[][..];
+        let mut output = String::new();
+        run(&self.commands, &mut emulator, &mut columns, &mut output);
+        Ok(output)
+    }
+}
+
+fn run<'a>(
+    steps: &'a [Step],
+    emulator: &mut Emulator,
+    columns: &mut &'a [Column],
+    output: &mut String,
+) {
+    for step in steps {
+        match step {
+            Step::OutputList(list) => {
+                *columns = list;
+                let header = columns.iter().map(|x| {
+                    let width = x.padding.0 + x.width + x.padding.1;
+                    format!("{:^width$}", x.name, width = width)
+                });
+                output.push_str(&row(header));
+            }
+            Step::Set(target, value) => match target {
+                Value::Ram(address) => emulator.ram[*address as usize] = *value,
+                Value::A => emulator.a = *value,
+                Value::D => emulator.d = *value,
+                Value::Pc => emulator.pc = *value,
+            },
+            Step::Ticktock => {
+                emulator.step();
+            }
+            Step::Output => {
+                let cells = columns.iter().map(|x| {
+                    let value = match x.value {
+                        Value::Ram(address) => emulator.ram[address as usize],
+                        Value::A => emulator.a,
+                        Value::D => emulator.d,
+                        Value::Pc => emulator.pc,
+                    };
+                    let text = match x.format {
+                        'X' => format!("{:0width$X}", value, width = x.width),
+                        'B' => format!("{:0width$b}", value, width = x.width),
+                        _ => (value as i16).to_string(),
+                    };
+                    format!(
+                        "{}{:>width$}{}",
+                        " ".repeat(x.padding.0),
+                        text,
+                        " ".repeat(x.padding.1),
+                        width = x.width
+                    )
+                });
+                output.push_str(&row(cells));
+            }
+            Step::Echo(text) => {
+                output.push_str(text);
+                output.push('\n');
+            }
+            Step::Repeat(count, steps) => {
+                for _ in 0..*count {
+                    run(steps, emulator, columns, output);
+                }
+            }
+        }
+    }
+}
+
+fn row(cells: impl Iterator<Item = String>) -> String {
+    format!("|{}|\n", cells.collect::<Vec<String>>().join("|"))
+}
+
+/// Splits a script into words and the punctuation between them, dropping comments.
+fn tokens(text: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut word = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '/' if chars.peek() == Some(&'/') => {
+                chars.by_ref().take_while(|x| *x != '\n').for_each(drop);
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                for ch in chars.by_ref() {
+                    if last == '*' && ch == '/' {
+                        break;
+                    }
+                    last = ch;
+                }
+            }
+            '"' => {
+                word.push(ch);
+                for ch in chars.by_ref() {
+                    word.push(ch);
+                    if ch == '"' {
+                        break;
+                    }
+                }
+            }
+            ',' | ';' | '{' | '}' => {
+                if !word.is_empty() {
+                    tokens.push(std::mem::take(&mut word));
+                }
+                tokens.push(ch.to_string());
+            }
+            ch if ch.is_whitespace() => {
+                if !word.is_empty() {
+                    tokens.push(std::mem::take(&mut word));
+                }
+            }
+            ch => word.push(ch),
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
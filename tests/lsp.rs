@@ -0,0 +1,111 @@
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{ChildStdout, Command, Stdio};
+
+fn send(input: &mut impl Write, message: Value) {
+    let body = message.to_string();
+    write!(input, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    input.flush().unwrap();
+}
+
+fn receive(output: &mut BufReader<ChildStdout>) -> Value {
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        output.read_line(&mut header).unwrap();
+        match header.trim() {
+            "" => break,
+            header => {
+                if let Some(value) = header.strip_prefix("Content-Length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    output.read_exact(&mut body).unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[test]
+fn serves_a_scripted_session() {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_hack-asm"))
+        .arg("lsp")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut input = server.stdin.take().unwrap();
+    let mut output = BufReader::new(server.stdout.take().unwrap());
+    let uri = "file:///tmp/scripted.asm";
+
+    send(
+        &mut input,
+        json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+    );
+    let response = receive(&mut output);
+    assert_eq!(response["id"], 1);
+    assert_eq!(
+        response["result"]["capabilities"]["definitionProvider"],
+        true
+    );
+    send(
+        &mut input,
+        json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+    );
+
+    send(
+        &mut input,
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": {
+                "uri": uri, "languageId": "hack-asm", "version": 1, "text": "(LOOP)\n@LOOP\n0;JMQ",
+            } },
+        }),
+    );
+    let diagnostics = receive(&mut output);
+    assert_eq!(diagnostics["method"], "textDocument/publishDiagnostics");
+    assert_eq!(
+        diagnostics["params"]["diagnostics"][0]["range"]["start"]["line"],
+        2
+    );
+
+    send(
+        &mut input,
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": { "uri": uri, "version": 2 },
+                "contentChanges": [{ "text": "(LOOP)\n@LOOP\n0;JMP" }],
+            },
+        }),
+    );
+    assert_eq!(receive(&mut output)["params"]["diagnostics"], json!([]));
+
+    send(
+        &mut input,
+        json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "textDocument/definition",
+            "params": {
+                "textDocument": { "uri": uri },
+                "position": { "line": 1, "character": 3 },
+            },
+        }),
+    );
+    let response = receive(&mut output);
+    assert_eq!(response["id"], 2);
+    assert_eq!(response["result"]["uri"], uri);
+    assert_eq!(response["result"]["range"]["start"]["line"], 0);
+
+    send(
+        &mut input,
+        json!({ "jsonrpc": "2.0", "id": 3, "method": "shutdown" }),
+    );
+    assert_eq!(receive(&mut output)["id"], 3);
+    send(&mut input, json!({ "jsonrpc": "2.0", "method": "exit" }));
+    assert!(server.wait().unwrap().success());
+}